@@ -28,6 +28,13 @@ macro_rules! to_ref {
     }};
 }
 
+macro_rules! to_ref_mut {
+    ($ptr:ident) => {{
+        assert_not_null!($ptr);
+        unsafe { &mut *$ptr }
+    }};
+}
+
 macro_rules! void_to_box {
     ($ptr:ident) => {{
         assert_not_null!($ptr);
@@ -79,12 +86,6 @@ macro_rules! unwrap_or_ret {
     };
 }
 
-macro_rules! unwrap_or_ret_err_code {
-    ($expr:expr) => {
-        unwrap_or_ret!($expr, -1)
-    };
-}
-
 macro_rules! unwrap_or_ret_null {
     ($expr:expr) => {
         unwrap_or_ret!($expr, ptr::null_mut())
@@ -12,21 +12,115 @@
 
 namespace speedreader {
 
+namespace {
+
+C_CRewriter* NewRewriter(C_SpeedReader* speedreader,
+                         const std::string& url,
+                         C_CompiledRewriteConfig* config,
+                         RewriterType rewriter_type,
+                         OutputFormat output_format,
+                         const ResponseInfo* response_info,
+                         void (*output_sink)(const char*, size_t, void*),
+                         void* output_sink_user_data) {
+  if (!response_info) {
+    return rewriter_new(speedreader, url.c_str(), url.length(), output_sink,
+                        output_sink_user_data, config, rewriter_type,
+                        output_format, nullptr);
+  }
+  auto header = [](const std::string& value) -> const char* {
+    return value.empty() ? nullptr : value.c_str();
+  };
+  // Only read during the call, the strings don't need to outlive it
+  C_CResponseInfo c_response_info = {
+      response_info->status,
+      header(response_info->content_type),
+      response_info->content_type.length(),
+      header(response_info->content_language),
+      response_info->content_language.length(),
+      header(response_info->link),
+      response_info->link.length(),
+  };
+  return rewriter_new(speedreader, url.c_str(), url.length(), output_sink,
+                      output_sink_user_data, config, rewriter_type,
+                      output_format, &c_response_info);
+}
+
+}  // namespace
+
 SpeedReader::SpeedReader() : raw_(speedreader_new()) {}
 SpeedReader::SpeedReader(const char* whitelist_serialized,
                          size_t whitelist_size)
     : raw_(with_whitelist(whitelist_serialized, whitelist_size)) {}
 
 bool SpeedReader::deserialize(const char* data, size_t data_size) {
-  auto* new_raw = with_whitelist(data, data_size);
-  if (new_raw != nullptr) {
-    speedreader_free(raw_);
-    raw_ = new_raw;
-    return true;
-  } else {
+  return SetLayer(WhitelistLayer::WhitelistLayerComponent, data, data_size);
+}
+
+bool SpeedReader::DeserializeVerified(const char* data, size_t data_size) {
+  if (!speedreader_set_verified_layer(
+          raw_, WhitelistLayer::WhitelistLayerComponent, data, data_size)) {
+    VLOG(2) << __func__ << " verification failed";
+    return false;
+  }
+  return true;
+}
+
+bool SpeedReader::SetLayer(WhitelistLayer layer,
+                           const char* data,
+                           size_t data_size) {
+  if (!speedreader_set_layer(raw_, layer, data, data_size)) {
     VLOG(2) << __func__ << " deserialization failed";
     return false;
   }
+  return true;
+}
+
+bool SpeedReader::DisableDomain(WhitelistLayer layer,
+                                const std::string& domain) {
+  return speedreader_disable_domain(raw_, layer, domain.c_str(),
+                                    domain.length());
+}
+
+bool SpeedReader::EnableDomain(WhitelistLayer layer,
+                               const std::string& domain) {
+  return speedreader_enable_domain(raw_, layer, domain.c_str(),
+                                   domain.length());
+}
+
+int SpeedReader::LayerForURL(const std::string& url) {
+  return speedreader_layer_for_url(raw_, url.c_str(), url.length());
+}
+
+bool SpeedReader::ApplyPatch(const char* data, size_t data_size) {
+  if (!speedreader_apply_patch(raw_, data, data_size)) {
+    VLOG(2) << __func__ << " patch deserialization failed";
+    return false;
+  }
+  return true;
+}
+
+void SpeedReader::SetHeuristicsFallback(bool enabled) {
+  speedreader_set_heuristics_fallback(raw_, enabled);
+}
+
+void SpeedReader::SetReadabilityThreshold(float threshold) {
+  speedreader_set_readability_threshold(raw_, threshold);
+}
+
+void SpeedReader::SetExtractionDiagnostics(bool enabled) {
+  speedreader_set_extraction_diagnostics(raw_, enabled);
+}
+
+void SpeedReader::SetImageTarget(unsigned int width, float pixel_ratio) {
+  speedreader_set_image_target(raw_, width, pixel_ratio);
+}
+
+bool SpeedReader::SetStylesheetURL(const std::string& url) {
+  return speedreader_set_stylesheet(raw_, url.c_str(), url.length(), false);
+}
+
+bool SpeedReader::SetInlineStylesheet(const std::string& css) {
+  return speedreader_set_stylesheet(raw_, css.c_str(), css.length(), true);
 }
 
 SpeedReader::~SpeedReader() {
@@ -42,13 +136,32 @@ RewriterType SpeedReader::RewriterTypeForURL(const std::string& url) {
 }
 
 std::unique_ptr<Rewriter> SpeedReader::MakeRewriter(const std::string& url) {
-  return std::make_unique<Rewriter>(raw_, url, RewriterType::RewriterUnknown);
+  return std::make_unique<Rewriter>(raw_, url, RewriterType::RewriterUnknown,
+                                    OutputFormat::OutputFormatHtml, nullptr);
 }
 
 std::unique_ptr<Rewriter> SpeedReader::MakeRewriter(
     const std::string& url,
     RewriterType rewriter_type) {
-  return std::make_unique<Rewriter>(raw_, url, rewriter_type);
+  return std::make_unique<Rewriter>(raw_, url, rewriter_type,
+                                    OutputFormat::OutputFormatHtml, nullptr);
+}
+
+std::unique_ptr<Rewriter> SpeedReader::MakeRewriter(
+    const std::string& url,
+    RewriterType rewriter_type,
+    OutputFormat output_format) {
+  return std::make_unique<Rewriter>(raw_, url, rewriter_type, output_format,
+                                    nullptr);
+}
+
+std::unique_ptr<Rewriter> SpeedReader::MakeRewriter(
+    const std::string& url,
+    RewriterType rewriter_type,
+    OutputFormat output_format,
+    const ResponseInfo& response_info) {
+  return std::make_unique<Rewriter>(raw_, url, rewriter_type, output_format,
+                                    &response_info);
 }
 
 std::unique_ptr<Rewriter> SpeedReader::MakeRewriter(
@@ -56,17 +169,22 @@ std::unique_ptr<Rewriter> SpeedReader::MakeRewriter(
     RewriterType rewriter_type,
     void (*output_sink)(const char*, size_t, void*),
     void* output_sink_user_data) {
-  return std::make_unique<Rewriter>(raw_, url, rewriter_type, output_sink,
-                                    output_sink_user_data);
+  return std::make_unique<Rewriter>(raw_, url, rewriter_type,
+                                    OutputFormat::OutputFormatHtml, nullptr,
+                                    output_sink, output_sink_user_data);
 }
 
 Rewriter::Rewriter(C_SpeedReader* speedreader,
                    const std::string& url,
-                   RewriterType rewriter_type)
+                   RewriterType rewriter_type,
+                   OutputFormat output_format,
+                   const ResponseInfo* response_info)
     : Rewriter(
           speedreader,
           url,
           rewriter_type,
+          output_format,
+          response_info,
           [](const char* chunk, size_t chunk_len, void* user_data) {
             std::string* out = static_cast<std::string*>(user_data);
             out->append(chunk, chunk_len);
@@ -76,6 +194,8 @@ Rewriter::Rewriter(C_SpeedReader* speedreader,
 Rewriter::Rewriter(C_SpeedReader* speedreader,
                    const std::string& url,
                    RewriterType rewriter_type,
+                   OutputFormat output_format,
+                   const ResponseInfo* response_info,
                    void (*output_sink)(const char*, size_t, void*),
                    void* output_sink_user_data)
     : output_(""),
@@ -83,16 +203,20 @@ Rewriter::Rewriter(C_SpeedReader* speedreader,
       poisoned_(false),
       config_raw_(
           get_rewriter_opaque_config(speedreader, url.c_str(), url.length())),
-      raw_(rewriter_new(speedreader,
-                        url.c_str(),
-                        url.length(),
-                        output_sink,
-                        output_sink_user_data,
-                        config_raw_,
-                        rewriter_type)) {}
+      raw_(NewRewriter(speedreader,
+                       url,
+                       config_raw_,
+                       rewriter_type,
+                       output_format,
+                       response_info,
+                       output_sink,
+                       output_sink_user_data)) {
+  // Creation failed, e.g. for an invalid URL
+  poisoned_ = !raw_;
+}
 
 Rewriter::~Rewriter() {
-  if (!ended_) {
+  if (raw_) {
     rewriter_free(raw_);
   }
   free_rewriter_opaque_config(config_raw_);
@@ -120,6 +244,23 @@ int Rewriter::End() {
   }
 }
 
+float Rewriter::GetClassifierConfidence() {
+  if (!raw_) {
+    return -1;
+  }
+  return rewriter_classifier_confidence(raw_);
+}
+
+std::string Rewriter::GetExtractionDiagnostics() {
+  if (!raw_) {
+    return std::string();
+  }
+  C_CharBuf diagnostics = rewriter_extraction_diagnostics(raw_);
+  std::string json(diagnostics.data, diagnostics.len);
+  speedreader_str_free(diagnostics);
+  return json;
+}
+
 const std::string& Rewriter::GetOutput() {
   return output_;
 }
@@ -14,15 +14,33 @@
 namespace speedreader {
 
 using RewriterType = C_CRewriterType;
+using DistillOutcome = C_CDistillOutcome;
+using OutputFormat = C_COutputFormat;
+using WhitelistLayer = C_CWhitelistLayer;
+
+/// HTTP response a document came with. Empty headers and a 0 `status` are
+/// unknown.
+struct ResponseInfo {
+  uint16_t status = 0;
+  std::string content_type;
+  std::string content_language;
+  std::string link;
+};
 
+/// Owns a `C_CRewriter`. Note the FFI ownership change: `rewriter_end` no
+/// longer frees the rewriter, so every `rewriter_new` result must be released
+/// with `rewriter_free`, ended or not. Raw FFI callers that relied on
+/// `rewriter_end` freeing it would leak it.
 class Rewriter {
  public:
   /// Create a buffering `Rewriter`. Output will be accumulated internally,
-  /// retrievable via `GetOutput`. Expected to only be instantiated by
-  /// `SpeedReader`.
+  /// retrievable via `GetOutput`. `response_info` may be null if unknown.
+  /// Expected to only be instantiated by `SpeedReader`.
   Rewriter(C_SpeedReader* speedreader,
            const std::string& url,
-           RewriterType rewriter_type);
+           RewriterType rewriter_type,
+           OutputFormat output_format,
+           const ResponseInfo* response_info);
 
   /// Create a streaming `Rewriter`. Provided callback will be called with every
   /// new chunk of output available. Output availability is not strictly related
@@ -31,6 +49,8 @@ class Rewriter {
   Rewriter(C_SpeedReader* speedreader,
            const std::string& url,
            RewriterType rewriter_type,
+           OutputFormat output_format,
+           const ResponseInfo* response_info,
            void (*output_sink)(const char*, size_t, void*),
            void* output_sink_user_data);
   ~Rewriter();
@@ -40,13 +60,26 @@ class Rewriter {
 
   /// Write a new chunk of data (byte array) to the rewriter instance. Does
   /// _not_ need to be a full document and can be called many times with ever
-  /// new chunk of data available.
+  /// new chunk of data available. Returns a `DistillOutcome` code, or -1 if
+  /// the `Rewriter` has already ended or failed, or could not be created.
   int Write(const char* chunk, size_t chunk_len);
 
   /// Finish processing input and "close" the `Rewriter`. Flushes any input not
-  /// yet processed and deallocates some of the internal resources.
+  /// yet processed and deallocates some of the internal resources. Returns a
+  /// `DistillOutcome` code telling whether the document was distilled and, if
+  /// not, why; -1 if the `Rewriter` has already ended or failed.
   int End();
 
+  /// Share of classifier votes, between 0 and 1, for the document being
+  /// readable. Only known after `End` if the classifier ran, -1 otherwise.
+  float GetClassifierConfidence();
+
+  /// Extraction diagnostics as JSON, if enabled with
+  /// `SpeedReader::SetExtractionDiagnostics`: how heuristics scored the
+  /// candidates and which nodes they removed, and why. Only known after `End`
+  /// if heuristics extraction ran, empty otherwise.
+  std::string GetExtractionDiagnostics();
+
   /// Returns accumulated output. Output is only accumulated if no explicit
   /// callback was provided, otherwise will return an empty string.
   const std::string& GetOutput();
@@ -55,7 +88,7 @@ class Rewriter {
   std::string output_;
   bool ended_;
   bool poisoned_;
-  C_CRewriterConfig* config_raw_;
+  C_CompiledRewriteConfig* config_raw_;
   C_CRewriter* raw_;
 };
 
@@ -68,8 +101,54 @@ class SpeedReader {
   SpeedReader(const SpeedReader&) = delete;
   void operator=(const SpeedReader&) = delete;
 
+  /// Replaces the component updated whitelist, keeping the other layers.
   bool deserialize(const char* data, size_t data_size);
 
+  /// Like `deserialize`, for whitelists in the signed format. Fails on
+  /// corrupted, forged or newer format version data.
+  bool DeserializeVerified(const char* data, size_t data_size);
+
+  /// Replaces one layer of the whitelist, keeping the others. Higher layers
+  /// override the configurations of lower ones.
+  bool SetLayer(WhitelistLayer layer, const char* data, size_t data_size);
+
+  /// Turns SpeedReader off for the domain and its subdomains, whatever lower
+  /// layers say. Returns false if already disabled in the layer.
+  bool DisableDomain(WhitelistLayer layer, const std::string& domain);
+
+  /// Lifts `DisableDomain`. Returns false if the domain was not disabled in
+  /// the layer.
+  bool EnableDomain(WhitelistLayer layer, const std::string& domain);
+
+  /// Returns the `WhitelistLayer` that decided how the URL is handled, -1 if
+  /// none has anything for it.
+  int LayerForURL(const std::string& url);
+
+  /// Applies a serialized whitelist patch on top of the current whitelist.
+  /// Returns false, leaving the whitelist unchanged, if it can't be parsed.
+  bool ApplyPatch(const char* data, size_t data_size);
+
+  /// Re-run documents through heuristics when the streaming rules configured
+  /// for a site keep little or no text. Off by default.
+  void SetHeuristicsFallback(bool enabled);
+
+  /// Share of classifier votes, between 0 and 1, a document needs to exceed
+  /// to be considered readable by heuristics. Defaults to 0.5.
+  void SetReadabilityThreshold(float threshold);
+
+  /// Record extraction diagnostics of heuristics rewriters, retrievable with
+  /// `Rewriter::GetExtractionDiagnostics`. Off by default.
+  void SetExtractionDiagnostics(bool enabled);
+
+  /// Layout width, in CSS pixels, and device pixel ratio images are picked
+  /// for from `srcset` and `<picture>` candidates. Defaults to 600 and 1.
+  void SetImageTarget(unsigned int width, float pixel_ratio);
+
+  /// Stylesheet output documents link to, or include with `SetInlineStylesheet`.
+  /// Return false if the value isn't valid UTF-8.
+  bool SetStylesheetURL(const std::string& url);
+  bool SetInlineStylesheet(const std::string& css);
+
   /// Checks if the provided URL matches whitelisted readable URLs.
   bool IsReadableURL(const std::string& url);
 
@@ -87,6 +166,23 @@ class SpeedReader {
   std::unique_ptr<Rewriter> MakeRewriter(const std::string& url,
                                          RewriterType rewriter_type);
 
+  /// Create a buffering `Rewriter` writing the article as Markdown or plain
+  /// text instead of HTML. The whole article is converted once `End` is
+  /// called.
+  std::unique_ptr<Rewriter> MakeRewriter(const std::string& url,
+                                         RewriterType rewriter_type,
+                                         OutputFormat output_format);
+
+  /// Create a buffering `Rewriter` for a document that came with the given
+  /// HTTP response. Its charset decodes the document, and its language and
+  /// canonical link fill in article metadata. Responses other than successful
+  /// HTML ones are not distilled: `Write` and `End` return
+  /// `DistillOutcomeUnsupportedResponse`.
+  std::unique_ptr<Rewriter> MakeRewriter(const std::string& url,
+                                         RewriterType rewriter_type,
+                                         OutputFormat output_format,
+                                         const ResponseInfo& response_info);
+
   /// Create a `Rewriter` that calls provided callback with every new chunk of
   /// output available.
   std::unique_ptr<Rewriter> MakeRewriter(const std::string& url,
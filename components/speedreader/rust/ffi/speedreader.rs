@@ -1,7 +1,8 @@
 use super::*;
-use libc::c_void;
+use ::speedreader::whitelist::WhitelistLayer;
+use ::speedreader::{ImageTarget, Stylesheet};
+use libc::{c_float, c_uint, c_void};
 use std::panic::{self, AssertUnwindSafe};
-use std::any::Any;
 
 // NOTE: we use `ExternOutputSink` proxy type, for extern handler function
 struct ExternOutputSink {
@@ -29,6 +30,44 @@ impl OutputSink for ExternOutputSink {
     }
 }
 
+/// Stands in for the rewriter of a response that can't be distilled, so the
+/// reason reaches the caller from `rewriter_write` and `rewriter_end`.
+struct UnsupportedResponseRewriter {
+    reason: String,
+}
+
+impl UnsupportedResponseRewriter {
+    fn error(&self) -> Result<(), SpeedReaderError> {
+        Err(SpeedReaderError::UnsupportedResponse(self.reason.clone()))
+    }
+}
+
+impl SpeedReaderProcessor for UnsupportedResponseRewriter {
+    fn write(&mut self, _input: &[u8]) -> Result<(), SpeedReaderError> {
+        self.error()
+    }
+
+    fn end(&mut self) -> Result<(), SpeedReaderError> {
+        self.error()
+    }
+
+    fn rewriter_type(&self) -> RewriterType {
+        RewriterType::Unknown
+    }
+
+    fn metadata(&self) -> Option<&ArticleMetadata> {
+        None
+    }
+
+    fn confidence(&self) -> Option<f32> {
+        None
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        None
+    }
+}
+
 /// Indicate type of rewriter that would be used based on existing
 /// configuration. `RewrtierUnknown` indicates that no configuration was found
 /// for the provided parameters.
@@ -62,17 +101,141 @@ impl From<RewriterType> for CRewriterType {
     }
 }
 
-/// Opaque structure to have the minimum amount of type safety across the FFI.
-/// Only replaces c_void
+/// Format of the rewriter output: the distilled HTML document, Markdown or
+/// plain text.
 #[repr(C)]
-pub struct CRewriter {
-    _private: [u8; 0],
+#[allow(clippy::enum_variant_names)]
+pub enum COutputFormat {
+    OutputFormatHtml,
+    OutputFormatMarkdown,
+    OutputFormatPlainText,
+}
+
+impl From<COutputFormat> for OutputFormat {
+    fn from(format: COutputFormat) -> Self {
+        match format {
+            COutputFormat::OutputFormatHtml => OutputFormat::Html,
+            COutputFormat::OutputFormatMarkdown => OutputFormat::Markdown,
+            COutputFormat::OutputFormatPlainText => OutputFormat::PlainText,
+        }
+    }
+}
+
+/// Result codes returned by `rewriter_write` and `rewriter_end`. Anything but
+/// `DistillOutcomeDistilled` means the document could not be distilled; -1 is
+/// returned instead if the rewriter was misused or panicked.
+#[repr(C)]
+#[allow(clippy::enum_variant_names)]
+pub enum CDistillOutcome {
+    DistillOutcomeDistilled = 0,
+    DistillOutcomeClassifierRejected = 1,
+    DistillOutcomeNothingExtracted = 2,
+    DistillOutcomeInvalidScheme = 3,
+    DistillOutcomeInvalidUrl = 4,
+    DistillOutcomeParseFailure = 5,
+    DistillOutcomeSelectorFailure = 6,
+    DistillOutcomeRewritingFailure = 7,
+    DistillOutcomeConfigurationFailure = 8,
+    DistillOutcomeUnsupportedResponse = 9,
+}
+
+impl From<DistillOutcome> for CDistillOutcome {
+    fn from(outcome: DistillOutcome) -> Self {
+        match outcome {
+            DistillOutcome::Distilled => CDistillOutcome::DistillOutcomeDistilled,
+            DistillOutcome::ClassifierRejected => CDistillOutcome::DistillOutcomeClassifierRejected,
+            DistillOutcome::NothingExtracted => CDistillOutcome::DistillOutcomeNothingExtracted,
+            DistillOutcome::InvalidScheme => CDistillOutcome::DistillOutcomeInvalidScheme,
+            DistillOutcome::InvalidUrl => CDistillOutcome::DistillOutcomeInvalidUrl,
+            DistillOutcome::ParseFailure => CDistillOutcome::DistillOutcomeParseFailure,
+            DistillOutcome::SelectorFailure => CDistillOutcome::DistillOutcomeSelectorFailure,
+            DistillOutcome::RewritingFailure => CDistillOutcome::DistillOutcomeRewritingFailure,
+            DistillOutcome::ConfigurationFailure => {
+                CDistillOutcome::DistillOutcomeConfigurationFailure
+            }
+            DistillOutcome::UnsupportedResponse => {
+                CDistillOutcome::DistillOutcomeUnsupportedResponse
+            }
+        }
+    }
+}
+
+impl<T> From<&Result<T, SpeedReaderError>> for CDistillOutcome {
+    fn from(result: &Result<T, SpeedReaderError>) -> Self {
+        CDistillOutcome::from(DistillOutcome::from(result))
+    }
+}
+
+/// HTTP response a document came with. Headers are UTF-8 strings of the
+/// given length, NULL if unknown; a `status` of 0 is unknown too.
+#[repr(C)]
+pub struct CResponseInfo {
+    pub status: u16,
+    pub content_type: *const c_char,
+    pub content_type_len: size_t,
+    pub content_language: *const c_char,
+    pub content_language_len: size_t,
+    pub link: *const c_char,
+    pub link_len: size_t,
+}
+
+impl CResponseInfo {
+    fn to_response_info(&self) -> ResponseInfo {
+        ResponseInfo {
+            status: Some(self.status).filter(|s| *s != 0),
+            content_type: header_value(self.content_type, self.content_type_len),
+            content_language: header_value(self.content_language, self.content_language_len),
+            link: header_value(self.link, self.link_len),
+        }
+    }
+}
+
+// Headers that aren't UTF-8 are treated as unknown
+fn header_value(data: *const c_char, len: size_t) -> Option<String> {
+    if data.is_null() {
+        return None;
+    }
+    let value: Result<&str, _> = to_str!(data, len);
+    value.ok().map(str::to_owned)
+}
+
+/// Whitelist sources, in increasing order of precedence. The whitelist
+/// SpeedReader is created with and patches apply to `WhitelistLayerComponent`.
+#[repr(C)]
+#[allow(clippy::enum_variant_names)]
+pub enum CWhitelistLayer {
+    WhitelistLayerBuiltIn = 0,
+    WhitelistLayerComponent = 1,
+    WhitelistLayerEnterprise = 2,
+    WhitelistLayerUser = 3,
+}
+
+impl From<CWhitelistLayer> for WhitelistLayer {
+    fn from(layer: CWhitelistLayer) -> Self {
+        match layer {
+            CWhitelistLayer::WhitelistLayerBuiltIn => WhitelistLayer::BuiltIn,
+            CWhitelistLayer::WhitelistLayerComponent => WhitelistLayer::Component,
+            CWhitelistLayer::WhitelistLayerEnterprise => WhitelistLayer::Enterprise,
+            CWhitelistLayer::WhitelistLayerUser => WhitelistLayer::User,
+        }
+    }
+}
+
+impl From<WhitelistLayer> for CWhitelistLayer {
+    fn from(layer: WhitelistLayer) -> Self {
+        match layer {
+            WhitelistLayer::BuiltIn => CWhitelistLayer::WhitelistLayerBuiltIn,
+            WhitelistLayer::Component => CWhitelistLayer::WhitelistLayerComponent,
+            WhitelistLayer::Enterprise => CWhitelistLayer::WhitelistLayerEnterprise,
+            WhitelistLayer::User => CWhitelistLayer::WhitelistLayerUser,
+        }
+    }
 }
 
 /// Opaque structure to have the minimum amount of type safety across the FFI.
 /// Only replaces c_void
 #[repr(C)]
-pub struct CRewriterConfig {
+pub struct CRewriter {
     _private: [u8; 0],
 }
 
@@ -95,6 +258,180 @@ pub extern "C" fn with_whitelist(
     to_ptr_mut(SpeedReader::with_whitelist(whitelist))
 }
 
+/// Applies a serialized `WhitelistPatch` to the live instance. Returns false,
+/// leaving the instance unchanged, if the patch can't be deserialized.
+#[no_mangle]
+pub extern "C" fn speedreader_apply_patch(
+    speedreader: *mut SpeedReader,
+    patch_data: *const c_char,
+    patch_data_size: size_t,
+) -> bool {
+    let patch_data: &[u8] =
+        unsafe { std::slice::from_raw_parts(patch_data as *const u8, patch_data_size) };
+    let patch = unwrap_or_ret! { whitelist::WhitelistPatch::deserialize(patch_data), false };
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.apply_patch(patch);
+    true
+}
+
+/// Enables re-running documents through heuristics when the streaming
+/// rules configured for a site keep little or no text. Off by default.
+#[no_mangle]
+pub extern "C" fn speedreader_set_heuristics_fallback(
+    speedreader: *mut SpeedReader,
+    enabled: bool,
+) {
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.set_heuristics_fallback(enabled);
+}
+
+/// Enables recording how heuristics score candidates and which nodes they
+/// remove, see `rewriter_extraction_diagnostics`. Off by default.
+#[no_mangle]
+pub extern "C" fn speedreader_set_extraction_diagnostics(
+    speedreader: *mut SpeedReader,
+    enabled: bool,
+) {
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.set_extraction_diagnostics(enabled);
+}
+
+/// Sets the share of classifier votes, between 0 and 1, a document needs to
+/// exceed to be considered readable by heuristics. Defaults to 0.5.
+#[no_mangle]
+pub extern "C" fn speedreader_set_readability_threshold(
+    speedreader: *mut SpeedReader,
+    threshold: c_float,
+) {
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.set_readability_threshold(threshold);
+}
+
+/// Sets the stylesheet of output documents: a URL to link, or CSS to
+/// include if `inline` is true. The rest of the output template is kept.
+/// Returns false if it isn't valid UTF-8.
+#[no_mangle]
+pub extern "C" fn speedreader_set_stylesheet(
+    speedreader: *mut SpeedReader,
+    stylesheet: *const c_char,
+    stylesheet_len: size_t,
+    inline: bool,
+) -> bool {
+    let stylesheet = unwrap_or_ret! { to_str!(stylesheet, stylesheet_len), false };
+    let speedreader = to_ref_mut!(speedreader);
+    let stylesheet = if inline {
+        Stylesheet::Inline(stylesheet.to_owned())
+    } else {
+        Stylesheet::Link(stylesheet.to_owned())
+    };
+    speedreader.set_stylesheet(stylesheet);
+    true
+}
+
+/// Sets the layout width, in CSS pixels, and device pixel ratio images are
+/// picked for from `srcset` and `<picture>` candidates. Defaults to 600 and 1.
+#[no_mangle]
+pub extern "C" fn speedreader_set_image_target(
+    speedreader: *mut SpeedReader,
+    width: c_uint,
+    pixel_ratio: c_float,
+) {
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.set_image_target(ImageTarget { width, pixel_ratio });
+}
+
+/// New instance of SpeedReader using a signed whitelist, see
+/// `Whitelist::deserialize_verified`. Returns NULL if the whitelist is
+/// malformed, from a newer format version or fails verification.
+#[no_mangle]
+pub extern "C" fn with_verified_whitelist(
+    whitelist_data: *const c_char,
+    whitelist_data_size: size_t,
+) -> *mut SpeedReader {
+    let whitelist_data: &[u8] =
+        unsafe { std::slice::from_raw_parts(whitelist_data as *const u8, whitelist_data_size) };
+    let whitelist = unwrap_or_ret_null! { whitelist::Whitelist::deserialize_verified(whitelist_data) };
+    to_ptr_mut(SpeedReader::with_whitelist(whitelist))
+}
+
+/// Replaces one layer of the whitelist with a deserialized one, keeping the
+/// other layers. Returns false, leaving the instance unchanged, if the
+/// whitelist can't be deserialized.
+#[no_mangle]
+pub extern "C" fn speedreader_set_layer(
+    speedreader: *mut SpeedReader,
+    layer: CWhitelistLayer,
+    whitelist_data: *const c_char,
+    whitelist_data_size: size_t,
+) -> bool {
+    let whitelist_data: &[u8] =
+        unsafe { std::slice::from_raw_parts(whitelist_data as *const u8, whitelist_data_size) };
+    let whitelist = unwrap_or_ret! { whitelist::Whitelist::deserialize(whitelist_data), false };
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.set_layer(layer.into(), whitelist);
+    true
+}
+
+/// Same as `speedreader_set_layer`, for whitelists in the signed format.
+#[no_mangle]
+pub extern "C" fn speedreader_set_verified_layer(
+    speedreader: *mut SpeedReader,
+    layer: CWhitelistLayer,
+    whitelist_data: *const c_char,
+    whitelist_data_size: size_t,
+) -> bool {
+    let whitelist_data: &[u8] =
+        unsafe { std::slice::from_raw_parts(whitelist_data as *const u8, whitelist_data_size) };
+    let whitelist =
+        unwrap_or_ret! { whitelist::Whitelist::deserialize_verified(whitelist_data), false };
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.set_layer(layer.into(), whitelist);
+    true
+}
+
+/// Turns SpeedReader off for the domain and its subdomains in the layer,
+/// overriding lower layers. Returns false if it was already disabled there.
+#[no_mangle]
+pub extern "C" fn speedreader_disable_domain(
+    speedreader: *mut SpeedReader,
+    layer: CWhitelistLayer,
+    domain: *const c_char,
+    domain_len: size_t,
+) -> bool {
+    let domain = unwrap_or_ret! { to_str!(domain, domain_len), false };
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.disable_domain(layer.into(), domain)
+}
+
+/// Lifts `speedreader_disable_domain`. Returns false if the domain was not
+/// disabled in the layer.
+#[no_mangle]
+pub extern "C" fn speedreader_enable_domain(
+    speedreader: *mut SpeedReader,
+    layer: CWhitelistLayer,
+    domain: *const c_char,
+    domain_len: size_t,
+) -> bool {
+    let domain = unwrap_or_ret! { to_str!(domain, domain_len), false };
+    let speedreader = to_ref_mut!(speedreader);
+    speedreader.enable_domain(layer.into(), domain)
+}
+
+/// Returns the `CWhitelistLayer` that decided how the URL is handled, -1 if
+/// no layer has anything for it.
+#[no_mangle]
+pub extern "C" fn speedreader_layer_for_url(
+    speedreader: *const SpeedReader,
+    url: *const c_char,
+    url_len: size_t,
+) -> c_int {
+    let url = unwrap_or_ret! { to_str!(url, url_len), -1 };
+    let speedreader = to_ref!(speedreader);
+    speedreader
+        .get_layer(url)
+        .map_or(-1, |layer| CWhitelistLayer::from(layer) as c_int)
+}
+
 /// Checks if the provided URL matches whitelisted readable URLs.
 #[no_mangle]
 pub extern "C" fn url_readable(
@@ -127,26 +464,34 @@ pub extern "C" fn speedreader_free(speedreader: *mut SpeedReader) {
     drop(to_box!(speedreader));
 }
 
+/// Returns rewriter configuration compiled for the given URL, to be passed
+/// to `rewriter_new`. Must be freed by calling `free_rewriter_opaque_config`
+/// once no rewriter created with it is in use.
 #[no_mangle]
 pub extern "C" fn get_rewriter_opaque_config(
     speedreader: *const SpeedReader,
     url: *const c_char,
     url_len: size_t
-) -> *mut CRewriterConfig {
+) -> *mut CompiledRewriteConfig {
     let url = unwrap_or_ret_null! { to_str!(url, url_len) };
     let speedreader = to_ref!(speedreader);
 
-    let opaque_config = speedreader.get_opaque_config(url);
-    box_to_opaque!(opaque_config, CRewriterConfig)
+    to_ptr_mut(speedreader.get_rewriter_config(url))
 }
 
 /// Returns SpeedReader rewriter instance for the given URL. If provided
 /// `rewriter_type` is `RewriterUnknown`, will look it up in the whitelist
 /// and default to heuristics-based rewriter if none found in the whitelist.
-/// Returns NULL if no URL provided or initialization fails.
+/// Output is written in `output_format`. `response_info` describes the HTTP
+/// response of the document, or is NULL if unknown; it is only read during
+/// the call.
+/// Returns NULL if no URL provided or initialization fails. If the response
+/// is not a successful HTML one, the rewriter returned distills nothing:
+/// `rewriter_write` and `rewriter_end` report `DistillOutcomeUnsupportedResponse`.
 /// Results of rewriting sent to `output_sink` callback function.
-/// MUST be finished with `rewriter_end` which will free
-/// associated memory.
+/// MUST be finished with `rewriter_end`, then freed with `rewriter_free`.
+/// `rewriter_end` does not free the rewriter, so the classifier confidence
+/// and diagnostics can still be read after it.
 #[no_mangle]
 pub extern "C" fn rewriter_new(
     speedreader: *const SpeedReader,
@@ -154,28 +499,41 @@ pub extern "C" fn rewriter_new(
     url_len: size_t,
     output_sink: unsafe extern "C" fn(*const c_char, size_t, *mut c_void),
     output_sink_user_data: *mut c_void,
-    rewriter_opaque_config: *mut CRewriterConfig,
+    rewriter_opaque_config: *const CompiledRewriteConfig,
     rewriter_type: CRewriterType,
+    output_format: COutputFormat,
+    response_info: *const CResponseInfo,
 ) -> *mut CRewriter {
     let url = unwrap_or_ret_null! { to_str!(url, url_len) };
+    let response = if response_info.is_null() {
+        None
+    } else {
+        Some(to_ref!(response_info).to_response_info())
+    };
     let speedreader = to_ref!(speedreader);
-
-    let opaque_config: &Box<dyn Any> = leak_void_to_box!(rewriter_opaque_config);
+    let compiled_config = to_ref!(rewriter_opaque_config);
 
     let output_sink = ExternOutputSink::new(output_sink, output_sink_user_data);
 
-    let rewriter = unwrap_or_ret_null! { speedreader
-        .get_rewriter(
-            url,
-            opaque_config,
-            output_sink,
-            rewriter_type.to_rewriter_type(),
-        )
+    let rewriter: Box<dyn SpeedReaderProcessor + '_> = match speedreader.get_rewriter(
+        url,
+        compiled_config,
+        output_sink,
+        rewriter_type.to_rewriter_type(),
+        OutputFormat::from(output_format),
+        response.as_ref(),
+    ) {
+        Ok(rewriter) => rewriter,
+        Err(SpeedReaderError::UnsupportedResponse(reason)) => {
+            Box::new(UnsupportedResponseRewriter { reason })
+        }
+        Err(_) => return ptr::null_mut(),
     };
     box_to_opaque!(rewriter, CRewriter)
 }
 
 /// Write a new chunk of data (byte array) to the rewriter instance.
+/// Returns a `CDistillOutcome` code, or -1 if the rewriter panicked.
 #[no_mangle]
 pub extern "C" fn rewriter_write(
     rewriter: *mut CRewriter,
@@ -198,32 +556,49 @@ pub extern "C" fn rewriter_write(
           return -1;
       }
     };
-    unwrap_or_ret_err_code! { res };
-    0
+    CDistillOutcome::from(&res) as c_int
 }
 
 /// Complete rewriting for this instance.
-/// Will free memory used by the rewriter.
-/// Calling twice will cause panic.
+/// Unlike in earlier versions, does not free the rewriter: `rewriter_free`
+/// must still be called, also after an error. Calling twice is an error.
+/// Returns a `CDistillOutcome` code telling whether the document was
+/// distilled and, if not, why.
 #[no_mangle]
 pub extern "C" fn rewriter_end(rewriter: *mut CRewriter) -> c_int {
-    // Clean up the memory by converting the pointer back into a Box and letting
-    // the Box be dropped at the end of the function
-    let mut rewriter: Box<Box<dyn SpeedReaderProcessor>> = void_to_box!(rewriter);
-    unwrap_or_ret_err_code! { rewriter.end() };
-    0
+    let rewriter: &mut Box<dyn SpeedReaderProcessor> = leak_void_to_box!(rewriter);
+    CDistillOutcome::from(&rewriter.end()) as c_int
+}
+
+/// Share of classifier votes, between 0 and 1, for the document being
+/// readable. Only known after `rewriter_end` for rewriters that ran the
+/// classifier, -1 otherwise.
+#[no_mangle]
+pub extern "C" fn rewriter_classifier_confidence(rewriter: *mut CRewriter) -> c_float {
+    let rewriter: &mut Box<dyn SpeedReaderProcessor> = leak_void_to_box!(rewriter);
+    rewriter.confidence().unwrap_or(-1.0)
+}
+
+/// Extraction diagnostics as JSON: the candidates heuristics scored and the
+/// nodes they removed, with reasons. Only known after `rewriter_end` if
+/// diagnostics were enabled and heuristics extraction ran, empty otherwise.
+/// Must be freed by calling `speedreader_str_free`.
+#[no_mangle]
+pub extern "C" fn rewriter_extraction_diagnostics(rewriter: *mut CRewriter) -> CharBuf {
+    let rewriter: &mut Box<dyn SpeedReaderProcessor> = leak_void_to_box!(rewriter);
+    CharBuf::new(rewriter.diagnostics().map(Diagnostics::to_json).unwrap_or_default())
 }
 
+/// Frees a rewriter from `rewriter_new`, whether it was ended or not.
 #[no_mangle]
 pub extern "C" fn rewriter_free(rewriter: *mut CRewriter) {
     // Clean up the memory by converting the pointer back
     // into a Box and letting the Box be dropped.
-    void_to_box!(rewriter);
+    let _: Box<Box<dyn SpeedReaderProcessor>> = void_to_box!(rewriter);
 }
 
 #[no_mangle]
-pub extern "C" fn free_rewriter_opaque_config(config: *mut CRewriterConfig) {
-    // Clean up the memory by converting the pointer back
-    // into a Box and letting the Box be dropped.
-    void_to_box!(config);
+pub extern "C" fn free_rewriter_opaque_config(config: *mut CompiledRewriteConfig) {
+    assert_not_null!(config);
+    drop(to_box!(config));
 }
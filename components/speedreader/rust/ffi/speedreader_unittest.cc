@@ -63,6 +63,49 @@ TEST(SpeedreaderFFITest, URLNotReadable) {
   EXPECT_FALSE(sr.IsReadableURL(url_str));
 }
 
+TEST(SpeedreaderFFITest, ApplyPatch) {
+  constexpr char patch[] = R"(
+    {
+      "remove_domains": ["example.com"],
+      "add": [{"domain": "unknown.com", "url_rules": ["||unknown.com/news/"]}]
+    }
+  )";
+  SpeedReader sr;
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  ASSERT_TRUE(sr.ApplyPatch(patch, strlen(patch)));
+  EXPECT_FALSE(
+      sr.IsReadableURL("https://example.com/news/article/topic/index.html"));
+  EXPECT_TRUE(
+      sr.IsReadableURL("https://unknown.com/news/article/topic/index.html"));
+  EXPECT_FALSE(sr.ApplyPatch("garbage", strlen("garbage")));
+}
+
+TEST(SpeedreaderFFITest, Layers) {
+  constexpr char user[] = R"(
+    [{"domain": "unknown.com", "url_rules": ["||unknown.com/news/"]}]
+  )";
+  const std::string article = "https://example.com/news/article/index.html";
+  SpeedReader sr;
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  ASSERT_TRUE(
+      sr.SetLayer(WhitelistLayer::WhitelistLayerUser, user, strlen(user)));
+  EXPECT_TRUE(sr.IsReadableURL("https://unknown.com/news/index.html"));
+  EXPECT_EQ(sr.LayerForURL(article), WhitelistLayer::WhitelistLayerComponent);
+
+  EXPECT_TRUE(sr.DisableDomain(WhitelistLayer::WhitelistLayerUser,
+                               "example.com"));
+  EXPECT_FALSE(sr.IsReadableURL(article));
+  EXPECT_EQ(sr.LayerForURL(article), WhitelistLayer::WhitelistLayerUser);
+
+  // Component updates don't touch the user layer
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  EXPECT_FALSE(sr.IsReadableURL(article));
+  EXPECT_TRUE(
+      sr.EnableDomain(WhitelistLayer::WhitelistLayerUser, "example.com"));
+  EXPECT_TRUE(sr.IsReadableURL(article));
+  EXPECT_EQ(sr.LayerForURL("https://brave.com/"), -1);
+}
+
 TEST(SpeedreaderFFITest, URLInvalid) {
   SpeedReader sr;
   std::string url_str = "brave://about";
@@ -113,8 +156,10 @@ TEST(SpeedreaderFFITest, RewriterCallback) {
   const char* content2 = "hello world</div></html>";
   ASSERT_EQ(rewriter->Write(content2, strlen(content2)), 0);
   ASSERT_EQ(rewriter->End(), 0);
-  EXPECT_STREQ(output.c_str(),
-               "<html><div class=\"article-body\">hello world</div></html>");
+  EXPECT_EQ(output.find("<!DOCTYPE html>"), 0u);
+  EXPECT_NE(output.find("<div id=\"article-content\"><div "
+                        "class=\"article-body\">hello world</div></div>"),
+            std::string::npos);
   EXPECT_STREQ(rewriter->GetOutput().c_str(), "");
 }
 
@@ -128,8 +173,109 @@ TEST(SpeedreaderFFITest, RewriterBufering) {
   const char* content2 = "hello world</div></html>";
   ASSERT_EQ(rewriter->Write(content2, strlen(content2)), 0);
   ASSERT_EQ(rewriter->End(), 0);
-  EXPECT_STREQ(rewriter->GetOutput().c_str(),
-               "<html><div class=\"article-body\">hello world</div></html>");
+  const std::string output = rewriter->GetOutput();
+  EXPECT_EQ(output.find("<!DOCTYPE html>"), 0u);
+  EXPECT_NE(output.find("<div id=\"article-content\"><div "
+                        "class=\"article-body\">hello world</div></div>"),
+            std::string::npos);
+}
+
+TEST(SpeedreaderFFITest, RewriterStylesheet) {
+  SpeedReader sr;
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  ASSERT_TRUE(sr.SetStylesheetURL("chrome://speedreader/theme.css"));
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  auto rewriter = sr.MakeRewriter(url_str);
+  const char* content = "<html><div class=\"article-body\">hello</div></html>";
+  ASSERT_EQ(rewriter->Write(content, strlen(content)), 0);
+  ASSERT_EQ(rewriter->End(), 0);
+  EXPECT_NE(rewriter->GetOutput().find(
+                "<link rel=\"stylesheet\" href=\"chrome://speedreader/theme.css\">"),
+            std::string::npos);
+}
+
+TEST(SpeedreaderFFITest, RewriterStylesheetKeepsTemplate) {
+  SpeedReader sr;
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  ASSERT_TRUE(sr.SetStylesheetURL("chrome://speedreader/theme.css"));
+  ASSERT_TRUE(sr.SetInlineStylesheet("body { margin: 0 }"));
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  auto rewriter = sr.MakeRewriter(url_str);
+  const char* content = "<html><div class=\"article-body\">hello</div></html>";
+  ASSERT_EQ(rewriter->Write(content, strlen(content)), 0);
+  ASSERT_EQ(rewriter->End(), 0);
+  const std::string output = rewriter->GetOutput();
+  EXPECT_NE(output.find("<meta http-equiv=\"Content-Security-Policy\""),
+            std::string::npos);
+  EXPECT_NE(output.find("<style>body { margin: 0 }</style>"),
+            std::string::npos);
+  EXPECT_EQ(output.find("chrome://speedreader/theme.css"), std::string::npos);
+}
+
+TEST(SpeedreaderFFITest, RewriterMarkdown) {
+  SpeedReader sr;
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  auto rewriter = sr.MakeRewriter(url_str, RewriterType::RewriterUnknown,
+                                  OutputFormat::OutputFormatMarkdown);
+  const char* content =
+      "<html><head><title>Title</title></head><div class=\"article-body\">"
+      "<h2>Part</h2><p>Some <em>text</em></p></div></html>";
+  ASSERT_EQ(rewriter->Write(content, strlen(content)), 0);
+  ASSERT_EQ(rewriter->End(), 0);
+  EXPECT_EQ(rewriter->GetOutput(), "# Title\n\n## Part\n\nSome _text_\n");
+}
+
+TEST(SpeedreaderFFITest, RewriterResponseInfo) {
+  SpeedReader sr;
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  ResponseInfo response_info;
+  response_info.status = 200;
+  response_info.content_type = "text/html; charset=windows-1252";
+  response_info.content_language = "fr";
+  response_info.link = "</news/article/topic/>; rel=canonical";
+  auto rewriter = sr.MakeRewriter(url_str, RewriterType::RewriterUnknown,
+                                  OutputFormat::OutputFormatHtml,
+                                  response_info);
+  const char* content =
+      "<html><head><title>Caf\xe9</title></head><div class=\"article-body\">"
+      "<p>Some text</p></div></html>";
+  ASSERT_EQ(rewriter->Write(content, strlen(content)), 0);
+  ASSERT_EQ(rewriter->End(), 0);
+  const std::string& output = rewriter->GetOutput();
+  EXPECT_NE(output.find("<html lang=\"fr\""), std::string::npos);
+  EXPECT_NE(output.find("<title>Caf\xc3\xa9</title>"), std::string::npos);
+  EXPECT_NE(output.find("<link rel=\"canonical\" "
+                        "href=\"https://example.com/news/article/topic/\">"),
+            std::string::npos);
+}
+
+TEST(SpeedreaderFFITest, RewriterUnsupportedResponse) {
+  SpeedReader sr;
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  ResponseInfo response_info;
+  response_info.status = 200;
+  response_info.content_type = "application/pdf";
+  auto pdf_rewriter = sr.MakeRewriter(url_str, RewriterType::RewriterUnknown,
+                                      OutputFormat::OutputFormatHtml,
+                                      response_info);
+  const char* content = "<html><div class=\"article-body\">text</div></html>";
+  EXPECT_EQ(
+      pdf_rewriter->Write(content, strlen(content)),
+      static_cast<int>(DistillOutcome::DistillOutcomeUnsupportedResponse));
+  EXPECT_EQ(pdf_rewriter->GetOutput(), "");
+
+  response_info.status = 404;
+  response_info.content_type = "text/html";
+  auto not_found_rewriter = sr.MakeRewriter(
+      url_str, RewriterType::RewriterUnknown, OutputFormat::OutputFormatHtml,
+      response_info);
+  EXPECT_EQ(
+      not_found_rewriter->End(),
+      static_cast<int>(DistillOutcome::DistillOutcomeUnsupportedResponse));
+  EXPECT_EQ(not_found_rewriter->GetOutput(), "");
 }
 
 TEST(SpeedreaderFFITest, RewriterBadSequence) {
@@ -151,6 +297,67 @@ TEST(SpeedreaderFFITest, RewriterDoubleEnd) {
   ASSERT_NE(rewriter->End(), 0);
 }
 
+TEST(SpeedreaderFFITest, RewriterHeuristicsNotReadable) {
+  SpeedReader sr;
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  auto rewriter = sr.MakeRewriter(url_str, RewriterType::RewriterHeuristics);
+  const char* content = "<html><p>hello world</p></html>";
+  ASSERT_EQ(rewriter->Write(content, strlen(content)), 0);
+  EXPECT_EQ(rewriter->End(),
+            static_cast<int>(DistillOutcome::DistillOutcomeClassifierRejected));
+  EXPECT_GE(rewriter->GetClassifierConfidence(), 0);
+  EXPECT_LE(rewriter->GetClassifierConfidence(), 0.5);
+}
+
+TEST(SpeedreaderFFITest, RewriterReadabilityThreshold) {
+  SpeedReader sr;
+  sr.SetReadabilityThreshold(1);
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  auto rewriter = sr.MakeRewriter(url_str, RewriterType::RewriterHeuristics);
+  EXPECT_EQ(rewriter->GetClassifierConfidence(), -1);
+  const char* content = "<html><article><p>hello world</p></article></html>";
+  ASSERT_EQ(rewriter->Write(content, strlen(content)), 0);
+  EXPECT_EQ(rewriter->End(),
+            static_cast<int>(DistillOutcome::DistillOutcomeClassifierRejected));
+}
+
+TEST(SpeedreaderFFITest, RewriterExtractionDiagnostics) {
+  SpeedReader sr;
+  sr.SetReadabilityThreshold(0);
+  sr.SetExtractionDiagnostics(true);
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  auto rewriter = sr.MakeRewriter(url_str, RewriterType::RewriterHeuristics);
+  EXPECT_EQ(rewriter->GetExtractionDiagnostics(), "");
+  const char* content =
+      "<html><div class=\"comments\"><p>A comment</p></div><article>"
+      "<p>A long enough paragraph of text to be extracted as content.</p>"
+      "<p>And another paragraph, so that there is plenty of text.</p>"
+      "</article></html>";
+  ASSERT_EQ(rewriter->Write(content, strlen(content)), 0);
+  ASSERT_EQ(rewriter->End(), 0);
+  const std::string diagnostics = rewriter->GetExtractionDiagnostics();
+  EXPECT_NE(diagnostics.find("\"tag\":\"article\""), std::string::npos);
+  EXPECT_NE(diagnostics.find("\"reason\":\"unlikely_candidate\""),
+            std::string::npos);
+}
+
+TEST(SpeedreaderFFITest, RewriterImageTarget) {
+  SpeedReader sr;
+  ASSERT_TRUE(sr.deserialize(test_config, strlen(test_config)));
+  sr.SetImageTarget(300, 2);
+  std::string url_str = "https://example.com/news/article/topic/index.html";
+  auto rewriter = sr.MakeRewriter(url_str, RewriterType::RewriterUnknown);
+  const char* content =
+      "<html><div class=\"article-body\">"
+      "<img srcset=\"s.jpg 300w, l.jpg 600w\"></div></html>";
+  ASSERT_EQ(rewriter->Write(content, strlen(content)), 0);
+  ASSERT_EQ(rewriter->End(), 0);
+  EXPECT_NE(rewriter->GetOutput().find(
+                "<div class=\"article-body\"><img load=\"lazy\" "
+                "src=\"https://example.com/news/article/topic/l.jpg\"></div>"),
+            std::string::npos);
+}
+
 TEST(SpeedreaderFFITest, RewriterParsingAmbiguity) {
   const char* ambiguity =
       "<select><div><style><div></div></style></div></select>";
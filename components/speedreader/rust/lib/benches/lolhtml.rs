@@ -12,7 +12,7 @@ fn bench_lolhtml(c: &mut Criterion) {
     let sr = speedreader::SpeedReader::default();
     let config = sr.get_rewriter_type(article_url);
     assert!(config == speedreader::RewriterType::Streaming);
-    let user_data = sr.get_opaque_config(article_url);
+    let compiled = sr.get_rewriter_config(article_url);
 
     c.bench_function("lolhtml-cnet", |b| {
         b.iter(|| {
@@ -20,9 +20,11 @@ fn bench_lolhtml(c: &mut Criterion) {
             let mut rewriter = sr
                 .get_rewriter(
                     article_url,
-                    &user_data,
+                    &compiled,
                     black_box(|c: &[u8]| output.extend_from_slice(c)),
                     Some(config),
+                    speedreader::OutputFormat::Html,
+                    None,
                 )
                 .unwrap();
             rewriter.write(data.as_bytes()).ok();
@@ -37,7 +39,7 @@ fn bench_html5ever(c: &mut Criterion) {
     let client = reqwest::blocking::Client::new();
     let data = client.get(article_url).send().unwrap().text().unwrap();
     let sr = speedreader::SpeedReader::with_whitelist(speedreader::whitelist::Whitelist::default());
-    let user_data = sr.get_opaque_config(article_url);
+    let compiled = sr.get_rewriter_config(article_url);
 
     c.bench_function("html5ever-cnet", |b| {
         b.iter(|| {
@@ -45,9 +47,11 @@ fn bench_html5ever(c: &mut Criterion) {
             let mut rewriter = sr
                 .get_rewriter(
                     article_url,
-                    &user_data,
+                    &compiled,
                     black_box(|c: &[u8]| output.extend_from_slice(c)),
                     Some(speedreader::RewriterType::Heuristics),
+                    speedreader::OutputFormat::Html,
+                    None,
                 )
                 .unwrap();
             rewriter.write(data.as_bytes()).ok();
@@ -109,8 +109,7 @@ pub const N_FEATURES: usize = {features};
 pub const N_CLASSES: usize = {classes};
 
 pub fn predict(features: &[f32; N_FEATURES])-> usize {{
-    let mut classes: [i32; N_CLASSES] = Default::default();
-    {f_predict_body}
+    let classes = predict_votes(features);
 
     let mut class_idx: usize = 0;
     let mut class_val = classes[0];
@@ -123,6 +122,12 @@ pub fn predict(features: &[f32; N_FEATURES])-> usize {{
     class_idx
 }}
 
+pub fn predict_votes(features: &[f32; N_FEATURES])-> [u32; N_CLASSES] {{
+    let mut classes: [u32; N_CLASSES] = Default::default();
+    {f_predict_body}
+    classes
+}}
+
 {predictors}
     "###,
         features = 21,
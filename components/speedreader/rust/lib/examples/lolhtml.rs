@@ -50,15 +50,17 @@ async fn stream_content(article_url: &str) -> Result<(), Box<dyn Error>> {
 
     let sr = SpeedReader::default();
     let config = sr.get_rewriter_type(article_url);
-    let opaque = sr.get_opaque_config(article_url);
+    let compiled = sr.get_rewriter_config(article_url);
     let mut rewriter = sr.get_rewriter(
         article_url,
-        &opaque,
+        &compiled,
         |c: &[u8]| {
             mapped_file.write_all(c).ok();
             mapped_test_file.write_all(c).ok();
         },
         Some(config),
+        OutputFormat::Html,
+        None,
     )?;
 
     let mut init_file = fs::File::create(format!("{}/init.html", &dir))?;
@@ -0,0 +1,145 @@
+use serde_json::json;
+use speedreader::classifier::feature_extractor::FeatureExtractorStreamer;
+use speedreader::classifier::Classifier;
+use speedreader::whitelist::Whitelist;
+use speedreader::{
+    OutputFormat, ResponseInfo, RewriterType, SpeedReader, DEFAULT_READABILITY_THRESHOLD,
+};
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fs;
+use std::io::{self, Write};
+use url::Url;
+
+use super::{read_input, CliResult, Options};
+
+/// Writes the distilled document, the classifier features or the
+/// classifier's verdict for the input. Exits with 1 if the document could
+/// not be distilled or was classified as not readable. Extraction
+/// diagnostics of heuristics are written as JSON to `--diagnostics`.
+pub fn run(options: &Options) -> CliResult {
+    let url = options.require("url")?;
+    let input = read_input(options.positional.first().map(String::as_str))?;
+    let threshold = match options.get("threshold") {
+        Some(threshold) => threshold.parse()?,
+        None => DEFAULT_READABILITY_THRESHOLD,
+    };
+
+    match options.get("output").unwrap_or("html") {
+        "html" => distill(options, url, &input, threshold, OutputFormat::Html),
+        "markdown" => distill(options, url, &input, threshold, OutputFormat::Markdown),
+        "text" => distill(options, url, &input, threshold, OutputFormat::PlainText),
+        "features" => {
+            let features = features(options, url, &input)?;
+            let sorted: BTreeMap<_, _> = features.iter().collect();
+            writeln!(io::stdout(), "{}", serde_json::to_string_pretty(&sorted)?)?;
+            Ok(0)
+        }
+        "verdict" => {
+            let classifier = Classifier::from_feature_map(&features(options, url, &input)?);
+            let confidence = classifier.readable_confidence();
+            let readable = confidence > threshold;
+            let verdict = json!({
+                "readable": readable,
+                "confidence": confidence,
+                "threshold": threshold,
+                "votes": classifier.votes().to_vec(),
+            });
+            writeln!(io::stdout(), "{}", serde_json::to_string_pretty(&verdict)?)?;
+            Ok(if readable { 0 } else { 1 })
+        }
+        other => Err(format!("unknown output `{}`", other).into()),
+    }
+}
+
+fn distill(
+    options: &Options,
+    url: &str,
+    input: &[u8],
+    threshold: f32,
+    output_format: OutputFormat,
+) -> CliResult {
+    let rewriter_type = match options.get("type").unwrap_or("auto") {
+        "auto" => None,
+        "streaming" => Some(RewriterType::Streaming),
+        "heuristics" => Some(RewriterType::Heuristics),
+        other => return Err(format!("unknown rewriter type `{}`", other).into()),
+    };
+    let mut sr = match options.get("whitelist") {
+        Some(path) => SpeedReader::with_whitelist(Whitelist::deserialize(&fs::read(path)?)?),
+        None => SpeedReader::default(),
+    };
+    sr.set_readability_threshold(threshold);
+    sr.set_heuristics_fallback(options.flag("fallback"));
+    let diagnostics_path = options.get("diagnostics");
+    sr.set_extraction_diagnostics(diagnostics_path.is_some());
+
+    let response = response_info(options)?;
+    let config = sr.get_rewriter_config(url);
+    let mut output = vec![];
+    // Not distilling the document is an outcome, not a usage error
+    if let Some(Err(e)) = response.as_ref().map(ResponseInfo::check_distillable) {
+        eprintln!("{:?}: {}", e.outcome(), e);
+        return Ok(1);
+    }
+    let mut rewriter = sr.get_rewriter(
+        url,
+        &config,
+        |c: &[u8]| output.extend_from_slice(c),
+        rewriter_type,
+        output_format,
+        response.as_ref(),
+    )?;
+    let result = rewriter.write(input).and_then(|_| rewriter.end());
+    let used = rewriter.rewriter_type();
+    let confidence = rewriter.confidence();
+    // Written even if nothing was extracted, that's when they help most
+    if let Some(path) = diagnostics_path {
+        match rewriter.diagnostics() {
+            Some(diagnostics) => fs::write(path, serde_json::to_string_pretty(diagnostics)?)?,
+            None => eprintln!("diagnostics: heuristics extraction did not run"),
+        }
+    }
+    drop(rewriter);
+
+    eprintln!("rewriter: {:?}", used);
+    if let Some(confidence) = confidence {
+        eprintln!("confidence: {:.3}", confidence);
+    }
+    match result {
+        Ok(()) => {
+            io::stdout().write_all(&output)?;
+            Ok(0)
+        }
+        Err(e) => {
+            eprintln!("{:?}: {}", e.outcome(), e);
+            Ok(1)
+        }
+    }
+}
+
+fn features(
+    options: &Options,
+    url: &str,
+    input: &[u8],
+) -> Result<HashMap<String, u32>, Box<dyn Error>> {
+    let url = Url::parse(url)?;
+    let mut extractor = FeatureExtractorStreamer::try_new(&url)?;
+    extractor.set_encoding(response_info(options)?.and_then(|r| r.encoding()));
+    extractor
+        .write(&mut &input[..])
+        .map_err(|_| "failed to parse the document")?;
+    Ok(extractor.end().features.clone())
+}
+
+/// Response the document came with, if any of its headers are given.
+fn response_info(options: &Options) -> Result<Option<ResponseInfo>, Box<dyn Error>> {
+    let header = |name| options.get(name).map(str::to_owned);
+    let response = ResponseInfo {
+        status: options.get("status").map(str::parse).transpose()?,
+        content_type: header("content-type"),
+        content_language: header("content-language"),
+        link: header("link"),
+    };
+    Ok(Some(response).filter(|r| *r != ResponseInfo::default()))
+}
@@ -0,0 +1,136 @@
+//! Command-line front end to SpeedReader, for reproducing distillations and
+//! checking whitelists offline.
+
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io::{self, Read};
+use std::process;
+
+mod distill;
+mod whitelist;
+
+const USAGE: &str = "\
+Usage: speedreader <command> [options]
+
+Commands:
+  distill --url <url> [options] [file]
+      Distills an HTML document read from `file`, or stdin if omitted or `-`.
+      --type <auto|streaming|heuristics>  rewriter to use (default: auto)
+      --whitelist <file>                  serialized whitelist to look the URL up in
+      --output <html|markdown|text|features|verdict>
+                                          what to write to stdout (default: html)
+      --status <code>                     response status, must be 2xx
+      --content-type <value>              response Content-Type, must be HTML
+      --content-language <value>          response Content-Language
+      --link <value>                      response Link header, for the canonical URL
+      --threshold <0..1>                  classifier readability threshold
+      --fallback                          fall back to heuristics if site rules fail
+      --diagnostics <file>                write how heuristics scored candidates and
+                                          which nodes they removed, as JSON
+
+  whitelist validate [file]
+      Reports broken selectors and URL rules, and duplicate entries.
+  whitelist match --url <url> [file]
+      Shows the configuration and every rule, including exceptions, matching
+      the URL.
+  whitelist convert [--to <json|gzip>] [--out <file>] [file]
+      Converts between plain and compressed JSON (default: json).
+  whitelist pack --key <file> [--out <file>] [--force] [file]
+      Writes the signed release format; `--key` holds a raw Ed25519 key.
+  whitelist test --whitelist <file> <dir>
+      Checks the URLs in every `readable.txt` and `unreadable.txt` under
+      `dir` against the whitelist.
+
+Whitelist files are read from stdin if omitted or `-`.
+";
+
+pub type CliResult = Result<i32, Box<dyn Error>>;
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("distill") => Options::parse(&args[1..]).and_then(|o| distill::run(&o)),
+        Some("whitelist") => whitelist::run(&args[1..]),
+        Some("help") | Some("--help") | Some("-h") => {
+            print!("{}", USAGE);
+            Ok(0)
+        }
+        _ => {
+            eprint!("{}", USAGE);
+            Ok(2)
+        }
+    };
+    match result {
+        Ok(code) => process::exit(code),
+        Err(e) => {
+            eprintln!("speedreader: {}", e);
+            process::exit(2);
+        }
+    }
+}
+
+/// Command options: `--name value` pairs, `--flag`s and positional arguments.
+pub struct Options {
+    values: HashMap<String, String>,
+    flags: Vec<String>,
+    pub positional: Vec<String>,
+}
+
+/// Options that are switches rather than taking a value.
+const FLAGS: &[&str] = &["fallback", "force"];
+
+impl Options {
+    fn parse(args: &[String]) -> Result<Self, Box<dyn Error>> {
+        let mut options = Options {
+            values: HashMap::new(),
+            flags: vec![],
+            positional: vec![],
+        };
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let name = if arg.starts_with("--") {
+                Some(&arg[2..])
+            } else {
+                None
+            };
+            match name {
+                Some(name) if FLAGS.contains(&name) => options.flags.push(name.to_owned()),
+                Some(name) => {
+                    let value = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for `--{}`", name))?;
+                    options.values.insert(name.to_owned(), value.clone());
+                }
+                None => options.positional.push(arg.clone()),
+            }
+        }
+        Ok(options)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    pub fn require(&self, name: &str) -> Result<&str, Box<dyn Error>> {
+        self.get(name)
+            .ok_or_else(|| format!("`--{}` is required", name).into())
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.iter().any(|f| f == name)
+    }
+}
+
+/// Reads a file, or stdin for `-` or no path.
+pub fn read_input(path: Option<&str>) -> io::Result<Vec<u8>> {
+    match path {
+        None | Some("-") => {
+            let mut input = vec![];
+            io::stdin().read_to_end(&mut input)?;
+            Ok(input)
+        }
+        Some(path) => fs::read(path),
+    }
+}
@@ -0,0 +1,185 @@
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use speedreader::whitelist::Whitelist;
+use speedreader::{SpeedReader, SpeedReaderConfig};
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use url::Url;
+
+use super::{read_input, CliResult, Options};
+
+/// Expected readable URLs in a `test` suite directory, one per line.
+const READABLE_FILE: &str = "readable.txt";
+/// Expected unreadable URLs in a `test` suite directory, one per line.
+const UNREADABLE_FILE: &str = "unreadable.txt";
+
+pub fn run(args: &[String]) -> CliResult {
+    let command = args.first().map(String::as_str).unwrap_or_default();
+    let options = Options::parse(args.get(1..).unwrap_or_default())?;
+    match command {
+        "validate" => validate(&options),
+        "match" => match_url(&options),
+        "convert" => convert(&options),
+        "pack" => pack(&options),
+        "test" => test(&options),
+        _ => Err(format!("unknown whitelist command `{}`", command).into()),
+    }
+}
+
+fn load(options: &Options) -> Result<Whitelist, Box<dyn std::error::Error>> {
+    let data = read_input(options.positional.first().map(String::as_str))?;
+    Ok(Whitelist::deserialize(&data)?)
+}
+
+/// Prints every problem found in the whitelist, exits with 1 if any.
+fn validate(options: &Options) -> CliResult {
+    let data = read_input(options.positional.first().map(String::as_str))?;
+    let diagnostics = Whitelist::validate_serialized(&data)?;
+    for diagnostic in diagnostics.iter() {
+        println!("{}", diagnostic);
+    }
+    eprintln!("{} problem(s) found", diagnostics.len());
+    Ok(if diagnostics.is_empty() { 0 } else { 1 })
+}
+
+/// Shows the configuration picked for the URL, every URL rule matching it
+/// and the resulting verdict. Exits with 1 if the URL is not readable.
+fn match_url(options: &Options) -> CliResult {
+    let url = options.require("url")?;
+    let parsed = Url::parse(url)?;
+    let whitelist = load(options)?;
+
+    match whitelist.get_configuration_for_url(&parsed) {
+        Some(config) => println!(
+            "config: {}{} ({})",
+            config.domain,
+            if config.url_scope.is_empty() {
+                String::new()
+            } else {
+                format!(" [{}]", config.url_scope.join(", "))
+            },
+            if config.declarative_rewrite.is_some() {
+                "streaming"
+            } else {
+                "heuristics"
+            }
+        ),
+        None => println!("config: none"),
+    }
+    for m in whitelist.matching_rules(&parsed) {
+        let kind = if m.exception { "exception" } else { "rule" };
+        println!("{}: {} (from {})", kind, m.rule, m.config.domain);
+    }
+
+    let readable = SpeedReader::with_whitelist(whitelist).url_readable(url);
+    println!(
+        "readable: {}",
+        match readable {
+            Some(true) => "yes",
+            Some(false) => "no, excepted",
+            None => "no, no rule matches",
+        }
+    );
+    Ok(if readable == Some(true) { 0 } else { 1 })
+}
+
+/// Rewrites a whitelist as pretty JSON or gzip-compressed JSON, the two
+/// forms `Whitelist::deserialize` accepts.
+fn convert(options: &Options) -> CliResult {
+    let whitelist = load(options)?;
+    let json = whitelist.serialize()?;
+    let out = match options.get("to").unwrap_or("json") {
+        "json" => {
+            let configs: Vec<SpeedReaderConfig> = serde_json::from_slice(&json)?;
+            let mut pretty = serde_json::to_vec_pretty(&configs)?;
+            pretty.push(b'\n');
+            pretty
+        }
+        "gzip" => {
+            let mut gz = GzEncoder::new(Vec::new(), Compression::best());
+            gz.write_all(&json)?;
+            gz.finish()?
+        }
+        other => return Err(format!("unknown format `{}`", other).into()),
+    };
+    write_output(options.get("out"), &out)?;
+    Ok(0)
+}
+
+/// Produces the signed release format, see `Whitelist::serialize_signed`.
+fn pack(options: &Options) -> CliResult {
+    let key = fs::read(options.require("key")?)?;
+    let whitelist = load(options)?;
+    let diagnostics = whitelist.validate();
+    if !diagnostics.is_empty() && !options.flag("force") {
+        for diagnostic in diagnostics.iter() {
+            eprintln!("{}", diagnostic);
+        }
+        return Err("refusing to pack a whitelist with problems, use --force".into());
+    }
+    write_output(options.get("out"), &whitelist.serialize_signed(&key)?)?;
+    Ok(0)
+}
+
+/// Checks every `readable.txt` and `unreadable.txt` under the directory
+/// against the whitelist. Exits with 1 if any expectation fails.
+fn test(options: &Options) -> CliResult {
+    let sr = SpeedReader::with_whitelist(Whitelist::deserialize(&fs::read(
+        options.require("whitelist")?,
+    )?)?);
+    let dir = options
+        .positional
+        .first()
+        .ok_or("missing test suite directory")?;
+
+    let mut expectations = vec![];
+    collect_expectations(Path::new(dir), &mut expectations)?;
+    let mut failed = 0;
+    for (url, expected) in expectations.iter() {
+        let readable = sr.url_readable(url) == Some(true);
+        if readable != *expected {
+            failed += 1;
+            println!(
+                "FAIL {}: expected {}",
+                url,
+                if *expected { "readable" } else { "unreadable" }
+            );
+        }
+    }
+    println!(
+        "{} passed, {} failed",
+        expectations.len() - failed,
+        failed
+    );
+    Ok(if failed == 0 { 0 } else { 1 })
+}
+
+fn collect_expectations(dir: &Path, out: &mut Vec<(String, bool)>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.path());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_expectations(&path, out)?;
+            continue;
+        }
+        let expected = match path.file_name().and_then(|name| name.to_str()) {
+            Some(READABLE_FILE) => true,
+            Some(UNREADABLE_FILE) => false,
+            _ => continue,
+        };
+        for url in fs::read_to_string(&path)?.split_ascii_whitespace() {
+            out.push((url.to_owned(), expected));
+        }
+    }
+    Ok(())
+}
+
+/// Writes to the file, or stdout without one.
+fn write_output(path: Option<&str>, data: &[u8]) -> io::Result<()> {
+    match path {
+        Some(path) => fs::write(path, data),
+        None => io::stdout().write_all(data),
+    }
+}
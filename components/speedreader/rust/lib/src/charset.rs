@@ -0,0 +1,267 @@
+use encoding_rs::{CoderResult, Decoder, Encoding, UTF_16BE, UTF_16LE, UTF_8, WINDOWS_1252, X_USER_DEFINED};
+
+/// Bytes searched for a `<meta>` charset declaration, same as browsers.
+pub const PRESCAN_LENGTH: usize = 1024;
+
+/// Encoding named by the `charset` parameter of a `Content-Type` header value.
+pub fn encoding_from_content_type(content_type: &str) -> Option<&'static Encoding> {
+    content_type
+        .split(';')
+        .skip(1)
+        .find_map(|param| {
+            let separator = param.find('=')?;
+            let (name, value) = (&param[..separator], &param[separator + 1..]);
+            if name.trim().eq_ignore_ascii_case("charset") {
+                Some(value.trim().trim_matches(|c| c == '"' || c == '\''))
+            } else {
+                None
+            }
+        })
+        .and_then(|label| Encoding::for_label(label.as_bytes()))
+}
+
+/// Encoding declared by a `<meta charset>` or `<meta http-equiv=content-type>`
+/// tag in the start of a document. Declarations of UTF-16 mean UTF-8, as the
+/// document could not have been parsed that far otherwise.
+pub fn prescan_meta(document: &[u8]) -> Option<&'static Encoding> {
+    let document = document.to_ascii_lowercase();
+    let mut rest = &document[..];
+    while let Some(start) = rest.iter().position(|b| *b == b'<') {
+        rest = &rest[start..];
+        if rest.starts_with(b"<!--") {
+            let end = find(rest, b"-->")?;
+            rest = &rest[end + 3..];
+            continue;
+        }
+        let end = rest.iter().position(|b| *b == b'>')?;
+        let tag = &rest[1..end];
+        rest = &rest[end + 1..];
+        if tag.starts_with(b"meta") {
+            if let Some(encoding) = meta_encoding(&tag[4..]) {
+                return Some(match encoding {
+                    e if e == UTF_16BE || e == UTF_16LE => UTF_8,
+                    e if e == X_USER_DEFINED => WINDOWS_1252,
+                    e => e,
+                });
+            }
+        }
+    }
+    None
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+fn meta_encoding(attributes: &[u8]) -> Option<&'static Encoding> {
+    if !attributes.first().map_or(false, |b| b.is_ascii_whitespace() || *b == b'/') {
+        return None;
+    }
+    let attributes = String::from_utf8_lossy(attributes);
+    let attributes = parse_attributes(&attributes);
+    let value = |name: &str| attributes.iter().find(|(n, _)| n == name).map(|(_, v)| v);
+    if let Some(charset) = value("charset") {
+        return Encoding::for_label(charset.trim().as_bytes());
+    }
+    match (value("http-equiv"), value("content")) {
+        // The content may leave out the MIME type
+        (Some(http_equiv), Some(content)) if http_equiv.trim() == "content-type" => {
+            encoding_from_content_type(&format!(";{}", content))
+        }
+        _ => None,
+    }
+}
+
+/// Attribute names and values of a lowercased tag, quotes removed.
+fn parse_attributes(tag: &str) -> Vec<(String, String)> {
+    let mut attributes = vec![];
+    let mut rest = tag.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+    while !rest.is_empty() {
+        let name_end = rest
+            .find(|c: char| c.is_ascii_whitespace() || c == '=' || c == '/')
+            .unwrap_or(rest.len());
+        let name = &rest[..name_end];
+        rest = rest[name_end..].trim_start();
+        let mut value = "";
+        if rest.starts_with('=') {
+            let after = rest[1..].trim_start();
+            let (found, remaining) = match after.chars().next() {
+                Some(quote @ '"') | Some(quote @ '\'') => {
+                    let inner = &after[1..];
+                    let end = inner.find(quote).unwrap_or(inner.len());
+                    (&inner[..end], inner.get(end + 1..).unwrap_or_default())
+                }
+                _ => {
+                    let end = after.find(|c: char| c.is_ascii_whitespace()).unwrap_or(after.len());
+                    (&after[..end], &after[end..])
+                }
+            };
+            value = found;
+            rest = remaining;
+        }
+        if !name.is_empty() {
+            attributes.push((name.to_owned(), value.to_owned()));
+        }
+        rest = rest.trim_start_matches(|c: char| c.is_ascii_whitespace() || c == '/');
+    }
+    attributes
+}
+
+/// Decodes a document arriving in chunks to UTF-8, carrying sequences split
+/// between chunks over to the next one. The encoding is taken from a byte
+/// order mark, then the declared one, e.g. from `Content-Type`, then a
+/// `<meta>` tag in the first `PRESCAN_LENGTH` bytes, defaulting to UTF-8.
+/// Input is held back until the encoding is known. Malformed sequences are
+/// replaced with U+FFFD.
+pub struct StreamDecoder {
+    declared: Option<&'static Encoding>,
+    decoder: Option<Decoder>,
+    pending: Vec<u8>,
+    finished: bool,
+}
+
+impl StreamDecoder {
+    pub fn new(declared: Option<&'static Encoding>) -> Self {
+        StreamDecoder {
+            declared,
+            decoder: None,
+            pending: vec![],
+            finished: false,
+        }
+    }
+
+    /// Encoding the input is decoded from, once known.
+    pub fn encoding(&self) -> Option<&'static Encoding> {
+        self.decoder.as_ref().map(Decoder::encoding)
+    }
+
+    /// Decodes the next chunk of input, returning what can be decoded so far.
+    pub fn decode(&mut self, chunk: &[u8]) -> String {
+        self.decode_chunk(chunk, false)
+    }
+
+    /// Decodes whatever input is left. Later calls return nothing.
+    pub fn finish(&mut self) -> String {
+        if self.finished {
+            return String::new();
+        }
+        self.finished = true;
+        self.decode_chunk(&[], true)
+    }
+
+    fn decode_chunk(&mut self, chunk: &[u8], last: bool) -> String {
+        if self.decoder.is_none() {
+            self.pending.extend_from_slice(chunk);
+            match self.detect(last) {
+                // Decoders remove a BOM, and switch to its encoding
+                Some(encoding) => self.decoder = Some(encoding.new_decoder()),
+                None => return String::new(),
+            }
+            let pending = std::mem::replace(&mut self.pending, Vec::new());
+            return self.decode_chunk(&pending, last);
+        }
+        let decoder = match self.decoder.as_mut() {
+            Some(decoder) => decoder,
+            None => return String::new(),
+        };
+        let capacity = decoder
+            .max_utf8_buffer_length(chunk.len())
+            .unwrap_or(chunk.len() * 3 + 16);
+        let mut output = String::with_capacity(capacity);
+        // The output has room for all of the input
+        let (result, _, _) = decoder.decode_to_string(chunk, &mut output, last);
+        debug_assert_eq!(result, CoderResult::InputEmpty);
+        output
+    }
+
+    fn detect(&self, last: bool) -> Option<&'static Encoding> {
+        if self.declared.is_some() {
+            return self.declared;
+        }
+        if let Some((encoding, _)) = Encoding::for_bom(&self.pending) {
+            return Some(encoding);
+        }
+        let prescanned = &self.pending[..self.pending.len().min(PRESCAN_LENGTH)];
+        match prescan_meta(prescanned) {
+            Some(encoding) => Some(encoding),
+            None if last || self.pending.len() >= PRESCAN_LENGTH => Some(UTF_8),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use encoding_rs::{SHIFT_JIS, WINDOWS_1251};
+
+    fn decode_in_chunks(decoder: &mut StreamDecoder, input: &[u8], size: usize) -> String {
+        let mut output = String::new();
+        for chunk in input.chunks(size) {
+            output.push_str(&decoder.decode(chunk));
+        }
+        output.push_str(&decoder.finish());
+        output
+    }
+
+    #[test]
+    fn content_type_charset() {
+        assert_eq!(
+            encoding_from_content_type("text/html; charset=\"Shift_JIS\""),
+            Some(SHIFT_JIS)
+        );
+        assert_eq!(
+            encoding_from_content_type("text/html;foo=bar; CHARSET=cp1251"),
+            Some(WINDOWS_1251)
+        );
+        assert_eq!(encoding_from_content_type("text/html"), None);
+        assert_eq!(encoding_from_content_type("text/html; charset=bogus"), None);
+    }
+
+    #[test]
+    fn meta_declarations() {
+        assert_eq!(prescan_meta(b"<html><META charset='gbk'>"), Some(encoding_rs::GBK));
+        assert_eq!(
+            prescan_meta(b"<meta http-equiv=\"Content-Type\" content=\"text/html; charset=shift_jis\">"),
+            Some(SHIFT_JIS)
+        );
+        assert_eq!(prescan_meta(b"<meta charset=\"utf-16\">"), Some(UTF_8));
+        assert_eq!(prescan_meta(b"<!-- <meta charset=gbk> --><p>"), None);
+        assert_eq!(prescan_meta(b"<metadata charset=gbk>"), None);
+        assert_eq!(prescan_meta(b"<meta charset=gbk"), None);
+    }
+
+    #[test]
+    fn carries_split_sequences() {
+        let input = "<p>Grüße, 世界</p>".as_bytes();
+        for size in 1..input.len() {
+            let mut decoder = StreamDecoder::new(None);
+            assert_eq!(decode_in_chunks(&mut decoder, input, size), "<p>Grüße, 世界</p>");
+            assert_eq!(decoder.encoding(), Some(UTF_8));
+        }
+    }
+
+    #[test]
+    fn detects_encoding() {
+        let (body, _, _) = SHIFT_JIS.encode("<p>日本語のテキスト</p>");
+        let document = [&b"<head><meta charset=\"Shift_JIS\"></head>"[..], &body].concat();
+        let mut decoder = StreamDecoder::new(None);
+        assert_eq!(
+            decode_in_chunks(&mut decoder, &document, 3),
+            "<head><meta charset=\"Shift_JIS\"></head><p>日本語のテキスト</p>"
+        );
+        assert_eq!(decoder.encoding(), Some(SHIFT_JIS));
+
+        let (body, _, _) = WINDOWS_1251.encode("<p>Привет</p>");
+        let mut decoder = StreamDecoder::new(Some(WINDOWS_1251));
+        assert_eq!(decode_in_chunks(&mut decoder, &body, 2), "<p>Привет</p>");
+
+        // A byte order mark wins over the declared encoding
+        let mut decoder = StreamDecoder::new(Some(WINDOWS_1251));
+        let document = [&b"\xEF\xBB\xBF"[..], "<p>é</p>".as_bytes()].concat();
+        assert_eq!(decode_in_chunks(&mut decoder, &document, 1), "<p>é</p>");
+        assert_eq!(decoder.encoding(), Some(UTF_8));
+    }
+}
@@ -1,3 +1,4 @@
+use encoding_rs::Encoding;
 use html5ever::driver::{ParseOpts, Parser};
 use markup5ever_rcdom::{Handle, NodeData, RcDom};
 use html5ever::tendril::*;
@@ -11,11 +12,13 @@ use std::string::String;
 use std::vec::Vec;
 use url::Url;
 
+use crate::charset::StreamDecoder;
 use crate::speedreader::SpeedReaderError;
 
 // Feature extractor which accepts chunks of data to parse
 pub struct FeatureExtractorStreamer {
     inner: Parser<FeaturisingTreeSink>,
+    decoder: StreamDecoder,
 }
 
 impl FeatureExtractorStreamer {
@@ -28,16 +31,34 @@ impl FeatureExtractorStreamer {
 
         let parser = html5ever::parse_document(sink, ParseOpts::default());
 
-        Ok(FeatureExtractorStreamer { inner: parser })
+        Ok(FeatureExtractorStreamer {
+            inner: parser,
+            decoder: StreamDecoder::new(None),
+        })
     }
 
+    /// Decode the input from `encoding`, e.g. the charset of the response,
+    /// unless the document starts with a byte order mark. Only has an effect
+    /// before the first `write`.
+    pub fn set_encoding(&mut self, encoding: Option<&'static Encoding>) {
+        self.decoder = StreamDecoder::new(encoding);
+    }
+
+    /// Chunks may split characters. Input is decoded as described in
+    /// `StreamDecoder`, malformed sequences don't fail the write.
     pub fn write(&mut self, fragment: &mut &[u8]) -> Result<(), ()> {
-        let tend = StrTendril::try_from_byte_slice(fragment)?;
-        self.inner.process(tend);
+        let decoded = self.decoder.decode(fragment);
+        if !decoded.is_empty() {
+            self.inner.process(StrTendril::from(decoded));
+        }
         Ok(())
     }
 
     pub fn end(&mut self) -> &mut FeaturisingTreeSink {
+        let decoded = self.decoder.finish();
+        if !decoded.is_empty() {
+            self.inner.process(StrTendril::from(decoded));
+        }
         &mut self.inner.tokenizer.sink.sink
     }
 
@@ -308,4 +329,20 @@ mod tests {
             Err(SpeedReaderError::InvalidUrl(_))
         ));
     }
+
+    #[test]
+    fn test_split_and_legacy_encodings() {
+        let url = Url::parse("https://example.com/article").unwrap();
+        let (body, _, _) = encoding_rs::WINDOWS_1251.encode("<p>Привет</p><p>мир</p>");
+        let mut streamer = FeatureExtractorStreamer::try_new(&url).unwrap();
+        streamer.set_encoding(Some(encoding_rs::WINDOWS_1251));
+        for chunk in body.chunks(3) {
+            streamer.write(&mut &chunk[..]).unwrap();
+        }
+        let sink = streamer.end();
+        assert_eq!(sink.features["p"], 2);
+        let mut text = String::new();
+        readability::dom::extract_text(&sink.rcdom.document, &mut text, true);
+        assert_eq!(text, "Приветмир");
+    }
 }
@@ -2,9 +2,14 @@ pub mod feature_extractor;
 mod model;
 use std::collections::HashMap;
 
-use model::predict;
+use model::{predict, predict_votes};
 use model::N_FEATURES;
 
+pub use model::N_CLASSES;
+
+/// Class index of readable documents.
+pub const READABLE: usize = 1;
+
 pub struct Classifier {
     features_list: [f32; N_FEATURES],
 }
@@ -20,6 +25,25 @@ impl Classifier {
     pub fn classify(&self) -> usize {
         predict(&self.features_list)
     }
+
+    /// Number of trees in the model voting for each class.
+    pub fn votes(&self) -> [u32; N_CLASSES] {
+        predict_votes(&self.features_list)
+    }
+
+    /// Share of trees voting for the document being readable, in `[0, 1]`.
+    pub fn readable_confidence(&self) -> f32 {
+        confidence(&self.votes())
+    }
+}
+
+pub fn confidence(votes: &[u32; N_CLASSES]) -> f32 {
+    let total: u32 = votes.iter().sum();
+    if total == 0 {
+        0.0
+    } else {
+        votes[READABLE] as f32 / total as f32
+    }
 }
 
 // helpers
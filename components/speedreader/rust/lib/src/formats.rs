@@ -0,0 +1,604 @@
+use html5ever::tendril::TendrilSink;
+use markup5ever_rcdom::{Handle, NodeData, RcDom};
+
+use crate::metadata::ArticleMetadata;
+use crate::speedreader::OutputFormat;
+use crate::template::OutputTemplate;
+
+/// Places sanitized content in the requested format: the `OutputTemplate`,
+/// if any, for HTML, or a conversion starting with the article title and
+/// byline for the text formats.
+pub fn render(
+    format: OutputFormat,
+    template: Option<&OutputTemplate>,
+    metadata: &ArticleMetadata,
+    content: &[u8],
+) -> Vec<u8> {
+    match (format, template) {
+        (OutputFormat::Html, Some(template)) => template.render(metadata, content),
+        (OutputFormat::Html, None) => content.to_vec(),
+        (format, _) => convert(format, metadata, content).into_bytes(),
+    }
+}
+
+/// Converts sanitized HTML content to Markdown or plain text. Headings,
+/// lists, blockquotes, code blocks, tables, links and images keep their
+/// Markdown syntax; plain text only keeps list markers. HTML is returned as
+/// it is.
+pub fn convert(format: OutputFormat, metadata: &ArticleMetadata, html: &[u8]) -> String {
+    if format == OutputFormat::Html {
+        return String::from_utf8_lossy(html).into_owned();
+    }
+    let dom = html5ever::parse_document(RcDom::default(), Default::default())
+        .from_utf8()
+        .one(html);
+
+    let mut writer = Writer::new(format);
+    if let Some(title) = &metadata.title {
+        let markup = if format == OutputFormat::Markdown {
+            "# "
+        } else {
+            ""
+        };
+        writer.open(markup);
+        writer.text(title);
+        writer.close(markup, "");
+    }
+    let details: Vec<&str> = [&metadata.byline, &metadata.site_name, &metadata.published]
+        .iter()
+        .filter_map(|value| value.as_ref().map(String::as_str))
+        .collect();
+    if !details.is_empty() {
+        writer.block(2);
+        writer.text(&details.join(" · "));
+    }
+    writer.children(&dom.document);
+    writer.finish()
+}
+
+/// What starts the lines of a block: a blockquote `> `, or a list item
+/// marker on its first line and indentation on the next ones.
+struct Prefix {
+    first: Option<String>,
+    rest: String,
+}
+
+struct Writer {
+    format: OutputFormat,
+    output: String,
+    prefixes: Vec<Prefix>,
+    list_depth: usize,
+    /// Line breaks to write before more content, 2 for a blank line.
+    pending_breaks: usize,
+    /// Prefixes open when the pending breaks were asked for, which blank
+    /// lines keep.
+    break_depth: usize,
+    /// Whether a single pending line break comes from a `<br>`.
+    hard_break: bool,
+    pending_space: bool,
+    /// Opening markup of inline elements, written with their first content.
+    pending_markup: String,
+    line_start: bool,
+    /// Whether the current line only has digits so far, which a `.` would
+    /// turn into an ordered list item.
+    line_digits: bool,
+}
+
+impl Writer {
+    fn new(format: OutputFormat) -> Self {
+        Writer {
+            format,
+            output: String::new(),
+            prefixes: vec![],
+            list_depth: 0,
+            pending_breaks: 0,
+            break_depth: 0,
+            hard_break: false,
+            pending_space: false,
+            pending_markup: String::new(),
+            line_start: true,
+            line_digits: true,
+        }
+    }
+
+    fn markdown(&self) -> bool {
+        self.format == OutputFormat::Markdown
+    }
+
+    fn finish(self) -> String {
+        let mut output = self.output.trim_end().to_owned();
+        output.push('\n');
+        output
+    }
+
+    /// Ends the current block, separating it from what follows with `breaks`
+    /// line breaks.
+    fn block(&mut self, breaks: usize) {
+        if !self.output.is_empty() {
+            self.break_depth = if self.pending_breaks == 0 {
+                self.prefixes.len()
+            } else {
+                self.break_depth.min(self.prefixes.len())
+            };
+            self.pending_breaks = self.pending_breaks.max(breaks);
+        }
+        self.hard_break = false;
+        self.pending_space = false;
+    }
+
+    /// Writes pending line breaks and the prefixes of a new line.
+    fn begin_line(&mut self) {
+        let depth = self.break_depth.min(self.prefixes.len());
+        for _ in 1..self.pending_breaks {
+            let blank: String = self.prefixes[..depth]
+                .iter()
+                .map(|p| p.rest.as_str())
+                .collect();
+            self.output.push('\n');
+            self.output.push_str(blank.trim_end());
+        }
+        if self.pending_breaks == 1 && self.hard_break {
+            self.output.push('\\');
+        }
+        if self.pending_breaks > 0 {
+            self.output.push('\n');
+            self.pending_breaks = 0;
+            self.hard_break = false;
+            self.line_start = true;
+        }
+        if self.line_start {
+            for prefix in &mut self.prefixes {
+                match prefix.first.take() {
+                    Some(first) => self.output.push_str(&first),
+                    None => self.output.push_str(&prefix.rest),
+                }
+            }
+            self.pending_space = false;
+            self.line_digits = true;
+        }
+    }
+
+    /// Writes what goes ahead of inline content: line breaks and prefixes,
+    /// a collapsed space and opening markup.
+    fn begin_content(&mut self) {
+        self.begin_line();
+        if self.pending_space && !self.line_start {
+            self.output.push(' ');
+            self.line_digits = false;
+        }
+        self.pending_space = false;
+        if !self.pending_markup.is_empty() {
+            let markup = std::mem::replace(&mut self.pending_markup, String::new());
+            self.output.push_str(&markup);
+            self.line_digits = false;
+        }
+    }
+
+    /// Opens an inline element, or a heading.
+    fn open(&mut self, markup: &str) {
+        self.pending_markup.push_str(markup);
+    }
+
+    /// Closes an element opened with `opening`. Elements without content
+    /// are left out.
+    fn close(&mut self, opening: &str, closing: &str) {
+        if self.pending_markup.ends_with(opening) {
+            let len = self.pending_markup.len() - opening.len();
+            self.pending_markup.truncate(len);
+        } else {
+            self.output.push_str(closing);
+        }
+    }
+
+    /// Writes text, collapsing whitespace and escaping Markdown syntax.
+    fn text(&mut self, text: &str) {
+        for c in text.chars() {
+            if c.is_whitespace() {
+                self.pending_space = true;
+                continue;
+            }
+            self.begin_content();
+            if self.markdown() && self.escapes(c) {
+                self.output.push('\\');
+            }
+            self.output.push(c);
+            self.line_digits = self.line_digits && c.is_ascii_digit();
+            self.line_start = false;
+        }
+    }
+
+    fn escapes(&self, c: char) -> bool {
+        match c {
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '|' => true,
+            '#' | '+' | '-' => self.line_start,
+            '.' | ')' => self.line_digits && !self.line_start,
+            _ => false,
+        }
+    }
+
+    /// Writes inline content that is already in the output format.
+    fn atom(&mut self, content: &str) {
+        self.begin_content();
+        self.output.push_str(content);
+        self.line_digits = false;
+        self.line_start = false;
+    }
+
+    /// Writes a line as it is, for preformatted text and table rows.
+    fn raw_line(&mut self, line: &str) {
+        self.block(1);
+        self.begin_line();
+        self.output.push_str(line);
+        self.pending_space = false;
+        self.line_start = false;
+    }
+
+    fn children(&mut self, handle: &Handle) {
+        for child in handle.children.borrow().iter() {
+            self.node(child);
+        }
+    }
+
+    fn node(&mut self, handle: &Handle) {
+        let tag = match &handle.data {
+            NodeData::Document => return self.children(handle),
+            NodeData::Text { contents } => return self.text(&contents.borrow()),
+            NodeData::Element { name, .. } => name.local.to_string(),
+            _ => return,
+        };
+        match tag.as_str() {
+            "head" | "script" | "style" | "template" => (),
+            "h1" | "h2" | "h3" | "h4" | "h5" | "h6" => {
+                self.block(2);
+                let level = usize::from(tag.as_bytes()[1] - b'0');
+                let markup = if self.markdown() {
+                    format!("{} ", "#".repeat(level))
+                } else {
+                    String::new()
+                };
+                self.open(&markup);
+                self.children(handle);
+                self.close(&markup, "");
+                self.block(2);
+            }
+            "br" => {
+                self.block(1);
+                self.hard_break = self.markdown();
+            }
+            "hr" => {
+                self.block(2);
+                if self.markdown() {
+                    self.raw_line("---");
+                }
+                self.block(2);
+            }
+            "ul" | "ol" => self.list(handle, tag == "ol"),
+            "blockquote" => {
+                self.block(2);
+                let quote = if self.markdown() { "> " } else { "" };
+                self.prefixes.push(Prefix {
+                    first: None,
+                    rest: quote.to_owned(),
+                });
+                self.children(handle);
+                self.prefixes.pop();
+                self.block(2);
+            }
+            "pre" => self.preformatted(handle),
+            "table" => self.table(handle),
+            "code" | "kbd" | "samp" => self.code(handle),
+            "strong" | "b" => self.inline(handle, "**"),
+            "em" | "i" | "cite" => self.inline(handle, "_"),
+            "del" | "s" => self.inline(handle, "~~"),
+            "a" => self.link(handle),
+            "img" => self.image(handle),
+            "p" | "div" | "section" | "article" | "header" | "footer" | "main" | "aside"
+            | "nav" | "figure" | "figcaption" | "dl" | "dt" | "dd" | "li" | "video" | "audio"
+            | "picture" | "caption" => {
+                self.block(2);
+                self.children(handle);
+                self.block(2);
+            }
+            _ => self.children(handle),
+        }
+    }
+
+    fn inline(&mut self, handle: &Handle, markup: &str) {
+        if !self.markdown() {
+            return self.children(handle);
+        }
+        self.open(markup);
+        self.children(handle);
+        self.close(markup, markup);
+    }
+
+    fn list(&mut self, handle: &Handle, ordered: bool) {
+        // Nested lists follow their parent item's text directly
+        let breaks = if self.list_depth > 0 { 1 } else { 2 };
+        self.block(breaks);
+        self.list_depth += 1;
+        let mut number = attr("start", handle)
+            .and_then(|start| start.trim().parse::<i64>().ok())
+            .unwrap_or(1);
+        for item in handle.children.borrow().iter() {
+            if element_name(item) != Some("li") {
+                self.node(item);
+                continue;
+            }
+            if let Some(value) = attr("value", item).and_then(|v| v.trim().parse().ok()) {
+                number = value;
+            }
+            let marker = if ordered {
+                format!("{}. ", number)
+            } else {
+                "- ".to_owned()
+            };
+            self.block(1);
+            self.prefixes.push(Prefix {
+                rest: " ".repeat(marker.len()),
+                first: Some(marker),
+            });
+            self.children(item);
+            self.prefixes.pop();
+            self.block(1);
+            number += 1;
+        }
+        self.list_depth -= 1;
+        self.block(breaks);
+    }
+
+    fn preformatted(&mut self, handle: &Handle) {
+        let mut text = String::new();
+        raw_text(handle, &mut text);
+        let text = text.trim_end_matches('\n');
+        self.block(2);
+        if self.markdown() {
+            let fence = "`".repeat(longest_run(text, '`').max(2) + 1);
+            let language = code_language(handle).unwrap_or_default();
+            self.raw_line(&format!("{}{}", fence, language));
+            for line in text.lines() {
+                self.raw_line(line);
+            }
+            self.raw_line(&fence);
+        } else {
+            for line in text.lines() {
+                self.raw_line(line);
+            }
+        }
+        self.block(2);
+    }
+
+    fn code(&mut self, handle: &Handle) {
+        let mut text = String::new();
+        raw_text(handle, &mut text);
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if text.is_empty() {
+            return;
+        }
+        if !self.markdown() {
+            return self.text(&text);
+        }
+        let fence = "`".repeat(longest_run(&text, '`') + 1);
+        let padding = if text.starts_with('`') || text.ends_with('`') {
+            " "
+        } else {
+            ""
+        };
+        self.atom(&format!("{}{}{}{}{}", fence, padding, text, padding, fence));
+    }
+
+    fn link(&mut self, handle: &Handle) {
+        let href = match attr("href", handle) {
+            Some(href) if self.markdown() => href,
+            _ => return self.children(handle),
+        };
+        self.open("[");
+        self.children(handle);
+        self.close("[", &format!("]({})", link_destination(&href)));
+    }
+
+    fn image(&mut self, handle: &Handle) {
+        if !self.markdown() {
+            return;
+        }
+        let src = match attr("src", handle) {
+            Some(src) => src,
+            None => return,
+        };
+        let alt = attr("alt", handle).unwrap_or_default();
+        let mut alt_writer = Writer::new(self.format);
+        alt_writer.text(&alt);
+        self.atom(&format!(
+            "![{}]({})",
+            alt_writer.output,
+            link_destination(&src)
+        ));
+    }
+
+    fn table(&mut self, handle: &Handle) {
+        let mut rows = vec![];
+        table_rows(handle, &mut rows);
+        let rows: Vec<Vec<String>> = rows
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| {
+                        let mut writer = Writer::new(self.format);
+                        writer.children(cell);
+                        writer
+                            .output
+                            .split_whitespace()
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    })
+                    .collect()
+            })
+            .collect();
+        let columns = rows.iter().map(Vec::len).max().unwrap_or(0);
+        if columns == 0 {
+            return;
+        }
+        self.block(2);
+        for (i, row) in rows.iter().enumerate() {
+            let mut cells = row.clone();
+            cells.resize(columns, String::new());
+            if self.markdown() {
+                self.raw_line(&format!("| {} |", cells.join(" | ")));
+                if i == 0 {
+                    self.raw_line(&format!("|{}", " --- |".repeat(columns)));
+                }
+            } else {
+                self.raw_line(cells.join("\t").trim_end());
+            }
+        }
+        self.block(2);
+    }
+}
+
+fn element_name(handle: &Handle) -> Option<&str> {
+    match &handle.data {
+        NodeData::Element { name, .. } => Some(&*name.local),
+        _ => None,
+    }
+}
+
+fn attr(name: &str, handle: &Handle) -> Option<String> {
+    match &handle.data {
+        NodeData::Element { attrs, .. } => attrs
+            .borrow()
+            .iter()
+            .find(|attr| &*attr.name.local == name)
+            .map(|attr| attr.value.to_string()),
+        _ => None,
+    }
+}
+
+/// Text of an element and its descendants, whitespace kept.
+fn raw_text(handle: &Handle, text: &mut String) {
+    for child in handle.children.borrow().iter() {
+        match &child.data {
+            NodeData::Text { contents } => text.push_str(&contents.borrow()),
+            NodeData::Element { name, .. } if &*name.local == "br" => text.push('\n'),
+            NodeData::Element { .. } => raw_text(child, text),
+            _ => (),
+        }
+    }
+}
+
+fn longest_run(text: &str, c: char) -> usize {
+    text.split(|t| t != c).map(str::len).max().unwrap_or(0)
+}
+
+/// Language of a code block, from a `language-*` or `lang-*` class on the
+/// `<pre>` or a `<code>` in it.
+fn code_language(handle: &Handle) -> Option<String> {
+    let from_class = |handle: &Handle| {
+        attr("class", handle)?.split_whitespace().find_map(|class| {
+            ["language-", "lang-"]
+                .iter()
+                .find(|prefix| class.starts_with(**prefix))
+                .map(|prefix| &class[prefix.len()..])
+                .filter(|language| !language.contains('`'))
+                .map(str::to_owned)
+        })
+    };
+    from_class(handle).or_else(|| {
+        handle
+            .children
+            .borrow()
+            .iter()
+            .filter(|child| element_name(child) == Some("code"))
+            .find_map(from_class)
+    })
+}
+
+fn table_rows(handle: &Handle, rows: &mut Vec<Vec<Handle>>) {
+    for child in handle.children.borrow().iter() {
+        match element_name(child) {
+            Some("thead") | Some("tbody") | Some("tfoot") => table_rows(child, rows),
+            Some("tr") => rows.push(
+                child
+                    .children
+                    .borrow()
+                    .iter()
+                    .filter(|cell| match element_name(cell) {
+                        Some("td") | Some("th") => true,
+                        _ => false,
+                    })
+                    .cloned()
+                    .collect(),
+            ),
+            _ => (),
+        }
+    }
+}
+
+/// Keeps a URL from ending a Markdown link destination early.
+fn link_destination(url: &str) -> String {
+    url.replace(' ', "%20")
+        .replace('(', "%28")
+        .replace(')', "%29")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ARTICLE: &str = "<h2>Fish &amp; chips</h2>\
+        <p>Some <strong>bold</strong>, <em>emphasis</em><em></em> and a \
+        <a href=\"https://e.com/a (b)\">link</a> with <code>x `y`</code>.<br>Next *line*</p>\
+        <ul><li>One</li><li>Two<ol start=\"3\"><li>Three</li><li>Four</li></ol></li></ul>\
+        <blockquote><p>Quoted</p><p>text</p></blockquote>\
+        <pre class=\"language-rust\">fn main() {\n    let a = 1;\n}\n</pre>\
+        <p><img src=\"https://e.com/i.jpg\" alt=\"A [fish]\"></p>\
+        <p>1999. A year</p><p>- dash</p><hr>\
+        <table><tr><th>A</th><th>B|C</th></tr><tr><td>1</td></tr></table>";
+
+    #[test]
+    fn converts_to_markdown() {
+        let metadata = ArticleMetadata {
+            title: Some("Title".to_owned()),
+            byline: Some("Jane Doe".to_owned()),
+            site_name: Some("Example".to_owned()),
+            ..ArticleMetadata::default()
+        };
+        assert_eq!(
+            convert(OutputFormat::Markdown, &metadata, ARTICLE.as_bytes()),
+            "# Title\n\nJane Doe · Example\n\n## Fish & chips\n\n\
+             Some **bold**, _emphasis_ and a [link](https://e.com/a%20%28b%29) with `` x `y` ``.\\\n\
+             Next \\*line\\*\n\n\
+             - One\n- Two\n  3. Three\n  4. Four\n\n\
+             > Quoted\n>\n> text\n\n\
+             ```rust\nfn main() {\n    let a = 1;\n}\n```\n\n\
+             ![A \\[fish\\]](https://e.com/i.jpg)\n\n\
+             1999\\. A year\n\n\\- dash\n\n---\n\n\
+             | A | B\\|C |\n| --- | --- |\n| 1 |  |\n"
+        );
+    }
+
+    #[test]
+    fn converts_to_plain_text() {
+        assert_eq!(
+            convert(
+                OutputFormat::PlainText,
+                &ArticleMetadata::default(),
+                ARTICLE.as_bytes()
+            ),
+            "Fish & chips\n\nSome bold, emphasis and a link with x `y`.\nNext *line*\n\n\
+             - One\n- Two\n  3. Three\n  4. Four\n\nQuoted\n\ntext\n\n\
+             fn main() {\n    let a = 1;\n}\n\n1999. A year\n\n- dash\n\nA\tB|C\n1\n"
+        );
+    }
+
+    #[test]
+    fn html_keeps_template() {
+        let metadata = ArticleMetadata::default();
+        let template = OutputTemplate::default();
+        let html = render(OutputFormat::Html, Some(&template), &metadata, b"<p>a</p>");
+        assert_eq!(html, template.render(&metadata, b"<p>a</p>"));
+        assert_eq!(
+            render(OutputFormat::Html, None, &metadata, b"<p>a</p>"),
+            b"<p>a</p>"
+        );
+    }
+}
@@ -9,15 +9,31 @@ extern crate url;
 #[macro_use]
 extern crate matches;
 
+pub mod charset;
 pub mod classifier;
+pub mod formats;
+pub mod metadata;
+pub mod response;
 mod rewriter_config_builder;
+pub mod sanitizer;
 pub mod speedreader;
 mod speedreader_heuristics;
+mod speedreader_hybrid;
 mod speedreader_streaming;
+pub mod template;
 
 pub mod whitelist;
 
+pub use self::metadata::ArticleMetadata;
+pub use self::response::ResponseInfo;
+pub use self::sanitizer::SanitizerPolicy;
+pub use self::template::{OutputTemplate, Stylesheet};
+pub use readability::images::ImageTarget;
+pub use readability::diagnostics::Diagnostics;
+pub use readability::scorer::ReadabilityOptions;
+pub use self::speedreader_heuristics::DEFAULT_READABILITY_THRESHOLD;
 pub use self::speedreader::{
-    AttributeRewrite, OutputSink, RewriteRules, RewriterType, SpeedReader, SpeedReaderConfig,
+    AttributeRewrite, CompiledRewriteConfig, DistillOutcome, DomainMatch, OutputFormat,
+    OutputSink, RewriteOp, RewriteRules, RewriterType, SelectorOps, SpeedReader, SpeedReaderConfig,
     SpeedReaderError, SpeedReaderProcessor,
 };
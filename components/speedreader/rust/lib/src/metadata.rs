@@ -0,0 +1,705 @@
+use lazy_static::lazy_static;
+use lol_html::html_content::{Element, TextChunk};
+use lol_html::{ElementContentHandlers, Selector};
+use markup5ever_rcdom::{Handle, NodeData};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use markup5ever::data::NAMED_ENTITIES;
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::rc::Rc;
+use url::Url;
+
+use crate::response::ResponseInfo;
+
+/// Article metadata, gathered from the document `<title>`, OpenGraph and
+/// Twitter `<meta>` tags, JSON-LD article objects and byline / `<time>`
+/// markup. Every field is optional, as pages rarely provide all of them.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ArticleMetadata {
+    pub title: Option<String>,
+    pub byline: Option<String>,
+    pub site_name: Option<String>,
+    pub published: Option<String>,
+    pub excerpt: Option<String>,
+    pub lead_image: Option<String>,
+    /// Language and text direction declared on `<html>`.
+    #[serde(default)]
+    pub lang: Option<String>,
+    #[serde(default)]
+    pub dir: Option<String>,
+    /// Canonical URL from the response `Link` header.
+    #[serde(default)]
+    pub canonical_url: Option<String>,
+}
+
+const JSON_LD_ARTICLE_TYPES: [&str; 13] = [
+    "Article",
+    "AnalysisNewsArticle",
+    "BackgroundNewsArticle",
+    "BlogPosting",
+    "LiveBlogPosting",
+    "NewsArticle",
+    "OpinionNewsArticle",
+    "Report",
+    "ReportageNewsArticle",
+    "ReviewNewsArticle",
+    "ScholarlyArticle",
+    "SocialMediaPosting",
+    "TechArticle",
+];
+
+/// Accumulates metadata candidates while a document is being processed. The
+/// same collector is fed by the streaming rewriter (through lol_html content
+/// handlers) and by the heuristics rewriter (walking the parsed DOM), and
+/// resolves the candidates into `ArticleMetadata` on `finish`.
+#[derive(Clone, Default)]
+pub struct MetadataCollector {
+    document_title: String,
+    meta: HashMap<String, String>,
+    json_ld: ArticleMetadata,
+    json_ld_buffer: String,
+    text_buffer: String,
+    byline: String,
+    byline_done: bool,
+    time: Option<String>,
+    lang: Option<String>,
+    dir: Option<String>,
+    response_lang: Option<String>,
+    canonical_url: Option<String>,
+}
+
+impl MetadataCollector {
+    pub fn title_text(&mut self, text: &str) {
+        self.document_title.push_str(text);
+    }
+
+    /// Records `<meta>` content keyed by its `property` or `name` attribute.
+    /// Only the first occurrence of each key is kept.
+    pub fn meta(&mut self, key: &str, content: &str) {
+        let content = normalize_whitespace(content);
+        if !content.is_empty() {
+            self.meta
+                .entry(key.trim().to_lowercase())
+                .or_insert(content);
+        }
+    }
+
+    /// Parses the contents of a `<script type="application/ld+json">` block,
+    /// filling in any fields not yet found in a previous block.
+    pub fn json_ld(&mut self, text: &str) {
+        if let Ok(value) = serde_json::from_str::<Value>(text) {
+            if let Some(article) = find_json_ld_article(&value) {
+                merge_json_ld_article(&mut self.json_ld, article);
+            }
+        }
+    }
+
+    fn json_ld_chunk(&mut self, text: &str, last: bool) {
+        self.json_ld_buffer.push_str(text);
+        if last {
+            let buffer = std::mem::replace(&mut self.json_ld_buffer, String::new());
+            self.json_ld(&buffer);
+        }
+    }
+
+    /// lol_html passes text with character references undecoded and may
+    /// split it anywhere, so streamed text is decoded a text node at a time.
+    fn text_chunk(&mut self, text: &str, last: bool) -> Option<String> {
+        self.text_buffer.push_str(text);
+        if last {
+            let buffer = std::mem::replace(&mut self.text_buffer, String::new());
+            Some(decode_entities(&buffer).into_owned())
+        } else {
+            None
+        }
+    }
+
+    fn byline_element(&mut self) {
+        if !self.byline.trim().is_empty() {
+            self.byline_done = true;
+        }
+    }
+
+    fn byline_text(&mut self, text: &str) {
+        if !self.byline_done {
+            self.byline.push_str(text);
+        }
+    }
+
+    pub fn time(&mut self, datetime: &str) {
+        if self.time.is_none() && !datetime.trim().is_empty() {
+            self.time = Some(datetime.trim().to_owned());
+        }
+    }
+
+    /// Records the `lang` and `dir` attributes of `<html>`. Only `ltr`,
+    /// `rtl` and `auto` directions are kept.
+    pub fn html(&mut self, lang: Option<&str>, dir: Option<&str>) {
+        self.lang = lang.map(str::trim).filter(|l| !l.is_empty()).map(str::to_owned);
+        self.dir = dir
+            .map(|d| d.trim().to_lowercase())
+            .filter(|d| ["ltr", "rtl", "auto"].contains(&d.as_str()));
+    }
+
+    /// Records what the HTTP response tells about the article. Its language
+    /// only applies if `<html>` doesn't declare one.
+    pub fn response(&mut self, response: &ResponseInfo, url: &Url) {
+        self.response_lang = response.language();
+        self.canonical_url = response.canonical_url(url).map(Url::into_string);
+    }
+
+    /// Metadata from the candidates recorded so far.
+    pub fn snapshot(&self, url: &Url) -> ArticleMetadata {
+        self.clone().finish(url)
+    }
+
+    pub fn finish(self, url: &Url) -> ArticleMetadata {
+        let MetadataCollector {
+            document_title,
+            meta,
+            json_ld,
+            byline,
+            time,
+            lang,
+            dir,
+            response_lang,
+            canonical_url,
+            ..
+        } = self;
+        let from_meta = |keys: &[&str]| keys.iter().find_map(|k| meta.get(*k).cloned());
+
+        let title = json_ld
+            .title
+            .or_else(|| from_meta(&["og:title", "twitter:title", "dc.title", "dc:title"]))
+            .or_else(|| non_empty(normalize_whitespace(&document_title)));
+        let byline = json_ld
+            .byline
+            .or_else(|| {
+                from_meta(&["author", "article:author", "dc.creator", "dc:creator"])
+                    .filter(|author| Url::parse(author).is_err())
+            })
+            .or_else(|| non_empty(strip_byline_prefix(&normalize_whitespace(&byline))));
+        let site_name = json_ld
+            .site_name
+            .or_else(|| from_meta(&["og:site_name", "application-name"]));
+        let published = json_ld
+            .published
+            .or_else(|| {
+                from_meta(&[
+                    "article:published_time",
+                    "og:article:published_time",
+                    "datepublished",
+                    "dc.date",
+                    "dc:date",
+                    "date",
+                ])
+            })
+            .or(time);
+        let excerpt = json_ld.excerpt.or_else(|| {
+            from_meta(&[
+                "og:description",
+                "twitter:description",
+                "description",
+                "dc.description",
+            ])
+        });
+        let lead_image = json_ld
+            .lead_image
+            .or_else(|| {
+                from_meta(&[
+                    "og:image",
+                    "og:image:url",
+                    "og:image:secure_url",
+                    "twitter:image",
+                    "twitter:image:src",
+                ])
+            })
+            .and_then(|image| url.join(&image).ok())
+            .map(|image| image.into_string());
+
+        ArticleMetadata {
+            title,
+            byline,
+            site_name,
+            published,
+            excerpt,
+            lead_image,
+            lang: lang.or(response_lang),
+            dir,
+            canonical_url,
+        }
+    }
+
+    /// Walks a parsed document and records all metadata candidates found.
+    pub fn collect_dom(&mut self, handle: &Handle) {
+        if let NodeData::Element {
+            ref name,
+            ref attrs,
+            ..
+        } = handle.data
+        {
+            let attrs = attrs.borrow();
+            let attr = |attr_name: &str| {
+                attrs
+                    .iter()
+                    .find(|a| a.name.local.as_ref() == attr_name)
+                    .map(|a| a.value.to_string())
+            };
+            let attr_is = |attr_name: &str, value: &str| {
+                attrs
+                    .iter()
+                    .any(|a| a.name.local.as_ref() == attr_name && a.value.as_ref() == value)
+            };
+            match name.local.as_ref() {
+                "html" => {
+                    let (lang, dir) = (attr("lang"), attr("dir"));
+                    self.html(lang.as_ref().map(String::as_str), dir.as_ref().map(String::as_str))
+                }
+                "title" if self.document_title.is_empty() => {
+                    self.title_text(&node_text(handle));
+                }
+                "meta" => {
+                    if let Some(content) = attr("content") {
+                        if let Some(key) = attr("property").or_else(|| attr("name")) {
+                            self.meta(&key, &content);
+                        }
+                        if let Some(key) = attr("itemprop") {
+                            self.meta(&key, &content);
+                        }
+                    }
+                }
+                "script" if attr_is("type", "application/ld+json") => {
+                    self.json_ld(&node_text(handle));
+                }
+                "time" => {
+                    if let Some(datetime) = attr("datetime") {
+                        self.time(&datetime);
+                    }
+                }
+                _ => (),
+            }
+            let is_byline = attr_is("rel", "author") || attr_is("itemprop", "author");
+            if is_byline && !self.byline_done {
+                self.byline = node_text(handle);
+                self.byline_done = !self.byline.trim().is_empty();
+                return;
+            }
+        }
+
+        for child in handle.children.borrow().iter() {
+            self.collect_dom(child);
+        }
+    }
+}
+
+lazy_static! {
+    static ref HTML_SELECTOR: Selector = "html".parse().unwrap();
+    static ref TITLE_SELECTOR: Selector = "head > title".parse().unwrap();
+    static ref META_SELECTOR: Selector = "meta[content]".parse().unwrap();
+    static ref JSON_LD_SELECTOR: Selector =
+        r#"script[type="application/ld+json"]"#.parse().unwrap();
+    static ref BYLINE_SELECTOR: Selector =
+        r#"[rel="author"], [itemprop="author"]"#.parse().unwrap();
+    static ref TIME_SELECTOR: Selector = "time[datetime]".parse().unwrap();
+}
+
+/// lol_html content handlers feeding `collector`. These only observe the
+/// document and never modify it, so they can be registered alongside any
+/// rewriting handlers.
+pub fn metadata_content_handlers<'h>(
+    collector: &Rc<RefCell<MetadataCollector>>,
+) -> Vec<(&'static Selector, ElementContentHandlers<'h>)> {
+    let html = collector.clone();
+    let title = collector.clone();
+    let meta = collector.clone();
+    let json_ld = collector.clone();
+    let byline_element = collector.clone();
+    let byline_text = collector.clone();
+    let time = collector.clone();
+
+    vec![
+        (
+            &*HTML_SELECTOR,
+            ElementContentHandlers::default().element(move |el: &mut Element| {
+                let lang = el.get_attribute("lang").map(|l| decode_entities(&l).into_owned());
+                let dir = el.get_attribute("dir");
+                html.borrow_mut()
+                    .html(lang.as_ref().map(String::as_str), dir.as_ref().map(String::as_str));
+                Ok(())
+            }),
+        ),
+        (
+            &*TITLE_SELECTOR,
+            ElementContentHandlers::default().text(move |t: &mut TextChunk| {
+                let mut collector = title.borrow_mut();
+                if let Some(text) = collector.text_chunk(t.as_str(), t.last_in_text_node()) {
+                    collector.title_text(&text);
+                }
+                Ok(())
+            }),
+        ),
+        (
+            &*META_SELECTOR,
+            ElementContentHandlers::default().element(move |el: &mut Element| {
+                let content = el.get_attribute("content").unwrap_or_default();
+                let content = decode_entities(&content);
+                let mut collector = meta.borrow_mut();
+                if let Some(key) = el
+                    .get_attribute("property")
+                    .or_else(|| el.get_attribute("name"))
+                {
+                    collector.meta(&key, &content);
+                }
+                if let Some(key) = el.get_attribute("itemprop") {
+                    collector.meta(&key, &content);
+                }
+                Ok(())
+            }),
+        ),
+        (
+            &*JSON_LD_SELECTOR,
+            ElementContentHandlers::default().text(move |t: &mut TextChunk| {
+                json_ld
+                    .borrow_mut()
+                    .json_ld_chunk(t.as_str(), t.last_in_text_node());
+                Ok(())
+            }),
+        ),
+        (
+            &*BYLINE_SELECTOR,
+            ElementContentHandlers::default()
+                .element(move |_: &mut Element| {
+                    byline_element.borrow_mut().byline_element();
+                    Ok(())
+                })
+                .text(move |t: &mut TextChunk| {
+                    let mut collector = byline_text.borrow_mut();
+                    if let Some(text) = collector.text_chunk(t.as_str(), t.last_in_text_node()) {
+                        collector.byline_text(&text);
+                    }
+                    Ok(())
+                }),
+        ),
+        (
+            &*TIME_SELECTOR,
+            ElementContentHandlers::default().element(move |el: &mut Element| {
+                if let Some(datetime) = el.get_attribute("datetime") {
+                    time.borrow_mut().time(&decode_entities(&datetime));
+                }
+                Ok(())
+            }),
+        ),
+    ]
+}
+
+/// Decodes the character references in raw text or attribute values from
+/// lol_html. References without the closing `;` are left as they are.
+pub(crate) fn decode_entities(text: &str) -> Cow<'_, str> {
+    if !text.contains('&') {
+        return Cow::Borrowed(text);
+    }
+    let mut decoded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(amp) = rest.find('&') {
+        decoded.push_str(&rest[..amp]);
+        rest = &rest[amp..];
+        match decode_reference(rest) {
+            Some((chars, len)) => {
+                decoded.push_str(&chars);
+                rest = &rest[len..];
+            }
+            None => {
+                decoded.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    decoded.push_str(rest);
+    Cow::Owned(decoded)
+}
+
+/// The characters of the reference `text` starts with, and its length.
+fn decode_reference(text: &str) -> Option<(String, usize)> {
+    let end = text.find(';')?;
+    let name = &text[1..end];
+    if name.starts_with('#') {
+        let number = &name[1..];
+        let code = if number.starts_with(&['x', 'X'][..]) {
+            u32::from_str_radix(&number[1..], 16).ok()?
+        } else {
+            number.parse().ok()?
+        };
+        let c = char::from_u32(code).filter(|c| *c != '\0').unwrap_or('\u{FFFD}');
+        return Some((c.to_string(), end + 1));
+    }
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return None;
+    }
+    // Prefixes of longer names map to `(0, 0)`
+    match NAMED_ENTITIES.get(&text[1..=end]) {
+        Some(&(first, second)) if first != 0 => {
+            let chars = [first, second]
+                .iter()
+                .filter(|c| **c != 0)
+                .filter_map(|c| char::from_u32(*c))
+                .collect();
+            Some((chars, end + 1))
+        }
+        _ => None,
+    }
+}
+
+fn find_json_ld_article(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Array(items) => items.iter().find_map(find_json_ld_article),
+        Value::Object(object) => {
+            if json_ld_is_article(object.get("@type")) {
+                Some(value)
+            } else {
+                object.get("@graph").and_then(find_json_ld_article)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn json_ld_is_article(json_type: Option<&Value>) -> bool {
+    match json_type {
+        Some(Value::String(t)) => JSON_LD_ARTICLE_TYPES.contains(&t.as_str()),
+        Some(Value::Array(types)) => types.iter().any(|t| json_ld_is_article(Some(t))),
+        _ => false,
+    }
+}
+
+fn merge_json_ld_article(metadata: &mut ArticleMetadata, article: &Value) {
+    if metadata.title.is_none() {
+        metadata.title = json_ld_text(article.get("headline").or_else(|| article.get("name")));
+    }
+    if metadata.byline.is_none() {
+        metadata.byline = json_ld_names(article.get("author"));
+    }
+    if metadata.site_name.is_none() {
+        metadata.site_name = json_ld_names(article.get("publisher"));
+    }
+    if metadata.published.is_none() {
+        metadata.published = json_ld_text(article.get("datePublished"));
+    }
+    if metadata.excerpt.is_none() {
+        metadata.excerpt = json_ld_text(article.get("description"));
+    }
+    if metadata.lead_image.is_none() {
+        metadata.lead_image = json_ld_image(article.get("image"));
+    }
+}
+
+fn json_ld_text(value: Option<&Value>) -> Option<String> {
+    match value {
+        Some(Value::String(s)) => non_empty(normalize_whitespace(s)),
+        _ => None,
+    }
+}
+
+/// Author and publisher may be given as a plain string, a `Person` or
+/// `Organization` object, or a list of either.
+fn json_ld_names(value: Option<&Value>) -> Option<String> {
+    match value {
+        Some(Value::Array(items)) => {
+            let names = items
+                .iter()
+                .filter_map(|item| json_ld_names(Some(item)))
+                .collect::<Vec<_>>();
+            non_empty(names.join(", "))
+        }
+        Some(Value::Object(object)) => json_ld_text(object.get("name")),
+        value => json_ld_text(value),
+    }
+}
+
+fn json_ld_image(value: Option<&Value>) -> Option<String> {
+    match value {
+        Some(Value::Array(items)) => items.iter().find_map(|item| json_ld_image(Some(item))),
+        Some(Value::Object(object)) => json_ld_text(object.get("url")),
+        value => json_ld_text(value),
+    }
+}
+
+fn node_text(handle: &Handle) -> String {
+    let mut text = String::new();
+    append_node_text(handle, &mut text);
+    text
+}
+
+fn append_node_text(handle: &Handle, text: &mut String) {
+    for child in handle.children.borrow().iter() {
+        match child.data {
+            NodeData::Text { ref contents } => text.push_str(&contents.borrow()),
+            NodeData::Element { .. } => append_node_text(child, text),
+            _ => (),
+        }
+    }
+}
+
+fn normalize_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn strip_byline_prefix(byline: &str) -> String {
+    let lowercase = byline.to_lowercase();
+    if lowercase.starts_with("by ") {
+        byline[3..].trim().to_owned()
+    } else {
+        byline.to_owned()
+    }
+}
+
+fn non_empty(text: String) -> Option<String> {
+    if text.is_empty() {
+        None
+    } else {
+        Some(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use html5ever::tendril::TendrilSink;
+    use markup5ever_rcdom::RcDom;
+
+    static DOCUMENT: &str = r#"<html lang="en-GB" dir="LTR"><head>
+        <title>Document title &amp; more | Example</title>
+        <meta property="og:site_name" content="Example &#x201C;News&#8221;">
+        <meta property="og:image" content="/images/lead.jpg">
+        <meta name="twitter:description" content="  Short   summary. ">
+        </head><body>
+        <article>
+          <p class="byline">By <a rel="author" href="/staff/jane">Jane  Do&eacute;</a></p>
+          <time datetime="2020-04-01T10:00:00Z">April 1</time>
+          <p>Content</p>
+        </article>
+        </body></html>"#;
+
+    static JSON_LD: &str = r#"<script type="application/ld+json">
+        {"@context": "https://schema.org", "@graph": [
+          {"@type": "WebSite", "name": "Not an article"},
+          {"@type": ["NewsArticle"], "headline": "Structured headline",
+           "author": [{"@type": "Person", "name": "Jane Doe"}, "John Roe"],
+           "publisher": {"@type": "Organization", "name": "Example Publishing"},
+           "datePublished": "2020-03-31",
+           "image": {"@type": "ImageObject", "url": "https://cdn.example.com/a.png"}}
+        ]}
+        </script>"#;
+
+    fn expected_from_markup() -> ArticleMetadata {
+        ArticleMetadata {
+            title: Some("Document title & more | Example".to_owned()),
+            byline: Some("Jane Doé".to_owned()),
+            site_name: Some("Example \u{201C}News\u{201D}".to_owned()),
+            published: Some("2020-04-01T10:00:00Z".to_owned()),
+            excerpt: Some("Short summary.".to_owned()),
+            lead_image: Some("https://example.com/images/lead.jpg".to_owned()),
+            lang: Some("en-GB".to_owned()),
+            dir: Some("ltr".to_owned()),
+            canonical_url: None,
+        }
+    }
+
+    fn collect_from_dom(html: &str) -> ArticleMetadata {
+        let dom = html5ever::parse_document(RcDom::default(), Default::default())
+            .from_utf8()
+            .read_from(&mut html.as_bytes())
+            .unwrap();
+        let mut collector = MetadataCollector::default();
+        collector.collect_dom(&dom.document);
+        collector.finish(&Url::parse("https://example.com/news/article").unwrap())
+    }
+
+    fn collect_streaming(html: &str, chunk_size: usize) -> ArticleMetadata {
+        let collector = Rc::new(RefCell::new(MetadataCollector::default()));
+        let mut rewriter = lol_html::HtmlRewriter::try_new(
+            lol_html::Settings {
+                element_content_handlers: metadata_content_handlers(&collector),
+                ..lol_html::Settings::default()
+            },
+            |_: &[u8]| {},
+        )
+        .unwrap();
+        for chunk in html.as_bytes().chunks(chunk_size) {
+            rewriter.write(chunk).unwrap();
+        }
+        rewriter.end().unwrap();
+        drop(rewriter);
+        Rc::try_unwrap(collector)
+            .ok()
+            .unwrap()
+            .into_inner()
+            .finish(&Url::parse("https://example.com/news/article").unwrap())
+    }
+
+    #[test]
+    fn dom_markup_metadata() {
+        assert_eq!(collect_from_dom(DOCUMENT), expected_from_markup());
+    }
+
+    #[test]
+    fn streaming_markup_metadata() {
+        assert_eq!(collect_streaming(DOCUMENT, 7), expected_from_markup());
+    }
+
+    #[test]
+    fn json_ld_takes_precedence() {
+        let html = DOCUMENT.replace("</head>", &format!("{}</head>", JSON_LD));
+        let expected = ArticleMetadata {
+            title: Some("Structured headline".to_owned()),
+            byline: Some("Jane Doe, John Roe".to_owned()),
+            site_name: Some("Example Publishing".to_owned()),
+            published: Some("2020-03-31".to_owned()),
+            excerpt: Some("Short summary.".to_owned()),
+            lead_image: Some("https://cdn.example.com/a.png".to_owned()),
+            ..expected_from_markup()
+        };
+        assert_eq!(collect_from_dom(&html), expected);
+        assert_eq!(collect_streaming(&html, 16), expected);
+    }
+
+    #[test]
+    fn invalid_json_ld_ignored() {
+        let html = DOCUMENT.replace(
+            "</head>",
+            r#"<script type="application/ld+json">{"@type": "NewsArticle",</script></head>"#,
+        );
+        assert_eq!(collect_from_dom(&html), expected_from_markup());
+        assert_eq!(collect_streaming(&html, 16), expected_from_markup());
+    }
+
+    #[test]
+    fn response_fills_in_metadata() {
+        let url = Url::parse("https://example.com/news/article?page=2").unwrap();
+        let response = ResponseInfo {
+            content_language: Some("fr".to_owned()),
+            link: Some("</news/article>; rel=canonical".to_owned()),
+            ..ResponseInfo::default()
+        };
+        let mut collector = MetadataCollector::default();
+        collector.response(&response, &url);
+        let metadata = collector.snapshot(&url);
+        assert_eq!(metadata.lang, Some("fr".to_owned()));
+        assert_eq!(
+            metadata.canonical_url,
+            Some("https://example.com/news/article".to_owned())
+        );
+
+        // The document language wins
+        collector.html(Some("en"), None);
+        assert_eq!(collector.finish(&url).lang, Some("en".to_owned()));
+    }
+
+    #[test]
+    fn empty_document_no_metadata() {
+        let html = "<html><body><p>Nothing here</p></body></html>";
+        assert_eq!(collect_from_dom(html), ArticleMetadata::default());
+        assert_eq!(collect_streaming(html, 4), ArticleMetadata::default());
+    }
+}
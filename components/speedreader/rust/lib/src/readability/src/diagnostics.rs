@@ -0,0 +1,134 @@
+use dom;
+use markup5ever_rcdom::Handle;
+use markup5ever_rcdom::NodeData::{Comment, Document, Element, Text};
+use std::path::Path;
+
+/// Explains an extraction: how every candidate scored and which nodes were
+/// dropped on the way. Paths are positions in the DOM tree like the ids of
+/// candidates, `/0/1` being the second child of the first child of the
+/// document. Nodes removed by `preprocess` are located in the parsed
+/// document; candidates and everything after in the preprocessed one.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Diagnostics {
+    pub candidates: Vec<CandidateReport>,
+    /// Path of the top candidate, none if no node scored above zero.
+    pub top_candidate: Option<String>,
+    /// Paths of the nodes the content was serialized from, the top candidate
+    /// and the siblings merged with it.
+    pub content: Vec<String>,
+    pub removed: Vec<RemovedNode>,
+}
+
+impl Diagnostics {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap_or_default()
+    }
+
+    pub(crate) fn removed(&mut self, stage: Stage, id: &Path, handle: &Handle, reason: Removal) {
+        // Whitespace and comments are dropped everywhere, listing them is noise
+        if let Element { .. } = handle.data {
+            let (tag, class, id_attr) = describe(handle);
+            self.removed.push(RemovedNode {
+                path: id.to_string_lossy().into_owned(),
+                tag,
+                class,
+                id: id_attr,
+                stage,
+                reason,
+            });
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CandidateReport {
+    pub path: String,
+    pub tag: String,
+    pub class: Option<String>,
+    pub id: Option<String>,
+    /// Score of the tag and the class and id patterns alone.
+    pub base_score: f32,
+    /// Base score plus the scores of the paragraphs below the node.
+    pub content_score: f32,
+    pub link_density: f32,
+    /// Content score scaled down by the link density.
+    pub score: f32,
+}
+
+impl CandidateReport {
+    pub(crate) fn new(id: &str, handle: &Handle, base_score: f32, content_score: f32) -> Self {
+        let (tag, class, id_attr) = describe(handle);
+        CandidateReport {
+            path: id.to_owned(),
+            tag,
+            class,
+            id: id_attr,
+            base_score,
+            content_score,
+            link_density: 0.0,
+            score: content_score,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RemovedNode {
+    pub path: String,
+    pub tag: String,
+    pub class: Option<String>,
+    pub id: Option<String>,
+    pub stage: Stage,
+    pub reason: Removal,
+}
+
+/// Extraction step that removed a node.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    /// Before scoring, see `scorer::preprocess`.
+    Preprocess,
+    /// After picking the content, see `scorer::clean`.
+    Clean,
+}
+
+/// Why a node was removed from the document.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Removal {
+    /// Scripts, styles and other elements that are never content.
+    UnwantedTag,
+    /// Class, id or item property matching `unlikely_candidates` but not
+    /// `likely_candidates`.
+    UnlikelyCandidate,
+    /// Whitespace-only text.
+    Blank,
+    Comment,
+    /// Nothing left after cleaning its children.
+    Empty,
+    /// Class weight and score adding up to less than zero.
+    NegativeScore,
+    /// More list items than paragraphs, outside of lists.
+    ListItems,
+    /// More inputs than the paragraphs allow.
+    Inputs,
+    /// Little text, with no images or too many.
+    LittleText,
+    /// Embeds with little text around, or more than one.
+    Embeds,
+    /// Mostly link text, without a class weight to make up for it.
+    LinkDensity,
+    /// Images without a source.
+    MissingImage,
+}
+
+/// Tag, class and id of the node. The document node is `#document`.
+fn describe(handle: &Handle) -> (String, Option<String>, Option<String>) {
+    let tag = match handle.data {
+        Document => "#document".to_owned(),
+        Element { ref name, .. } => name.local.to_string(),
+        Text { .. } => "#text".to_owned(),
+        Comment { .. } => "#comment".to_owned(),
+        _ => String::new(),
+    };
+    (tag, dom::get_attr("class", handle), dom::get_attr("id", handle))
+}
@@ -3,6 +3,7 @@ use markup5ever_rcdom::{Handle, Node};
 use html5ever::tendril::StrTendril;
 use html5ever::Attribute;
 use html5ever::LocalName;
+use html5ever::QualName;
 use std::rc::Rc;
 use std::str::FromStr;
 
@@ -42,10 +43,21 @@ pub fn set_attr(attr_name: &str, value: &str, handle: Handle) {
                     value,
                 }
             }
+        } else if let Ok(value) = StrTendril::from_str(value) {
+            attrs.push(Attribute {
+                name: QualName::new(None, ns!(), LocalName::from(attr_name)),
+                value,
+            })
         }
     }
 }
 
+pub fn remove_attr(attr_name: &str, handle: &Handle) {
+    if let Element { ref attrs, .. } = handle.data {
+        clean_attr(attr_name, &mut attrs.borrow_mut());
+    }
+}
+
 pub fn clean_attr(attr_name: &str, attrs: &mut Vec<Attribute>) {
     if let Some(index) = attrs.iter().position(|attr| {
         let name = attr.name.local.as_ref();
@@ -1,21 +1,29 @@
-use markup5ever_rcdom::RcDom;
+use diagnostics::{CandidateReport, Diagnostics, Stage};
+use dom;
+use markup5ever_rcdom::{Handle, RcDom};
+use markup5ever_rcdom::NodeData::{Document, Element};
 use markup5ever_rcdom::SerializableHandle;
 use html5ever::tendril::TendrilSink;
+use html5ever::serialize::{SerializeOpts, TraversalScope};
 use html5ever::{parse_document, serialize};
+use images::ImageTarget;
 use scorer;
-use scorer::Candidate;
+use scorer::{Candidate, ReadabilityOptions};
 use std::cell::Cell;
 use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::default::Default;
 use std::io::Read;
 use std::path::Path;
+use std::rc::Rc;
 use url::Url;
+use urls;
 
 #[derive(Debug)]
 pub struct Product {
     pub title: String,
     pub content: String,
+    pub text_length: usize,
 }
 
 pub fn extract<R>(input: &mut R, url: &Url) -> Result<Product, std::io::Error>
@@ -26,22 +34,42 @@ where
         .from_utf8()
         .read_from(input)?;
 
-    extract_dom(&mut dom, url, &HashMap::new())
+    extract_dom(
+        &mut dom,
+        url,
+        &HashMap::new(),
+        &ImageTarget::default(),
+        &ReadabilityOptions::default(),
+        None,
+    )
 }
 
+/// Extracts the article content of the document. With `diagnostics`, also
+/// records how the candidates scored and which nodes were removed.
 pub fn extract_dom<S: ::std::hash::BuildHasher>(
     mut dom: &mut RcDom,
     url: &Url,
     features: &HashMap<String, u32, S>,
+    image_target: &ImageTarget,
+    options: &ReadabilityOptions,
+    mut diagnostics: Option<&mut Diagnostics>,
 ) -> Result<Product, std::io::Error> {
     let mut title = String::new();
     let mut candidates = BTreeMap::new();
     let mut nodes = BTreeMap::new();
     let handle = dom.document.clone();
+    let base = urls::document_base(&handle, url);
 
     // extracts title (if it exists) pre-processes the DOM by removing script
     // tags, css, links
-    scorer::preprocess(&mut dom, handle.clone(), &mut title);
+    scorer::preprocess(
+        &mut dom,
+        Path::new("/"),
+        handle.clone(),
+        &mut title,
+        options,
+        diagnostics.as_mut().map(|d| &mut **d),
+    );
 
     // now that the dom has been preprocessed, get the set of potential dom
     // candidates and their scoring. a candidate contains the node parent of the
@@ -53,6 +81,7 @@ pub fn extract_dom<S: ::std::hash::BuildHasher>(
         handle.clone(),
         &mut candidates,
         &mut nodes,
+        options,
     );
     let mut id: &str = "/";
 
@@ -65,7 +94,15 @@ pub fn extract_dom<S: ::std::hash::BuildHasher>(
 
     // scores all candidate nodes
     for (i, c) in candidates.iter() {
-        let score = c.score.get() * (1.0 - scorer::get_link_density(&c.node));
+        let link_density = scorer::get_link_density(&c.node);
+        let score = c.score.get() * (1.0 - link_density);
+        if let Some(diagnostics) = diagnostics.as_mut() {
+            let base_score = scorer::init_content_score(&c.node, options);
+            let mut report = CandidateReport::new(i, &c.node, base_score, c.score.get());
+            report.link_density = link_density;
+            report.score = score;
+            diagnostics.candidates.push(report);
+        }
         c.score.set(score);
         if score <= top_candidate.score.get() {
             continue;
@@ -74,23 +111,82 @@ pub fn extract_dom<S: ::std::hash::BuildHasher>(
         top_candidate = c;
     }
 
-    let mut bytes = vec![];
-
-    scorer::clean(
-        &mut dom,
+    // siblings of the top candidate may hold more of the content, e.g. when
+    // inline ads split the article in several containers
+    let content_nodes = scorer::content_siblings(
         Path::new(id),
-        top_candidate.node.clone(),
-        url,
-        &title,
-        features,
+        top_candidate,
         &candidates,
+        &nodes,
+        &options.siblings,
     );
+    if let Some(diagnostics) = diagnostics.as_mut() {
+        if !Rc::ptr_eq(&top_candidate.node, &dom.document) {
+            diagnostics.top_candidate = Some(id.to_owned());
+        }
+    }
+
+    let mut bytes = vec![];
+    let mut text_length = 0;
 
-    let document: SerializableHandle = top_candidate.node.clone().into();
-    serialize(&mut bytes, &document, Default::default())?;
+    // every content node is kept whole, whether it is the top candidate alone
+    // or merged with its siblings. the document and its root containers have
+    // no markup worth keeping, so only their children are serialized.
+    for (node_id, node) in content_nodes {
+        let removal = scorer::clean(
+            &mut dom,
+            &node_id,
+            node.clone(),
+            &base,
+            image_target,
+            &title,
+            features,
+            &candidates,
+            options,
+            diagnostics.as_mut().map(|d| &mut **d),
+        );
+        match removal {
+            Some(removal) if !Rc::ptr_eq(&node, &top_candidate.node) => {
+                if let Some(diagnostics) = diagnostics.as_mut() {
+                    diagnostics.removed(Stage::Clean, &node_id, &node, removal);
+                }
+                continue;
+            }
+            _ => (),
+        }
+        if let Some(diagnostics) = diagnostics.as_mut() {
+            diagnostics.content.push(node_id.to_string_lossy().into_owned());
+        }
+        text_length += dom::text_len(&node);
+        let traversal_scope = if is_root_container(&node) {
+            TraversalScope::ChildrenOnly(None)
+        } else {
+            TraversalScope::IncludeNode
+        };
+        let opts = SerializeOpts {
+            traversal_scope,
+            ..Default::default()
+        };
+        let document: SerializableHandle = node.into();
+        serialize(&mut bytes, &document, opts)?;
+    }
     let content = String::from_utf8(bytes).unwrap_or_default();
 
-    Ok(Product { title, content })
+    Ok(Product {
+        title,
+        content,
+        text_length,
+    })
+}
+
+fn is_root_container(handle: &Handle) -> bool {
+    match handle.data {
+        Document => true,
+        Element { ref name, .. } => {
+            name.local == local_name!("html") || name.local == local_name!("body")
+        }
+        _ => false,
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,181 @@
+use markup5ever_rcdom::Handle;
+use std::rc::Rc;
+use url::Url;
+
+use dom;
+use urls;
+
+/// Width, in CSS pixels, images are laid out at in reader view.
+pub const IMAGE_TARGET_WIDTH: u32 = 600;
+
+/// Display the image candidates are picked for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImageTarget {
+    /// Layout width of images, in CSS pixels.
+    pub width: u32,
+    /// Device pixels per CSS pixel.
+    pub pixel_ratio: f32,
+}
+
+impl Default for ImageTarget {
+    fn default() -> Self {
+        ImageTarget {
+            width: IMAGE_TARGET_WIDTH,
+            pixel_ratio: 1.0,
+        }
+    }
+}
+
+impl ImageTarget {
+    /// Device pixels per CSS pixel a candidate provides when laid out at the
+    /// target width. `None` for descriptors that don't parse.
+    fn density(&self, descriptor: &str) -> Option<f32> {
+        // Only the width or density descriptor matters, `h` is ignored
+        let descriptor = descriptor
+            .split_whitespace()
+            .find(|d| d.ends_with('w') || d.ends_with('x'));
+        match descriptor {
+            None => Some(1.0),
+            Some(d) if d.ends_with('w') => d[..d.len() - 1]
+                .parse::<u32>()
+                .ok()
+                .filter(|w| *w > 0)
+                .map(|w| w as f32 / self.width.max(1) as f32),
+            Some(d) => d[..d.len() - 1]
+                .parse::<f32>()
+                .ok()
+                .filter(|x| *x > 0.0),
+        }
+    }
+
+    /// Picks the smallest candidate that is still sharp at the target width
+    /// and pixel ratio, or the largest one if none is. Candidates are
+    /// `srcset` values, earlier ones win ties.
+    pub fn select<'a, I>(&self, srcsets: I) -> Option<&'a str>
+    where
+        I: IntoIterator<Item = &'a str>,
+    {
+        let mut sharp: Option<(&str, f32)> = None;
+        let mut largest: Option<(&str, f32)> = None;
+        for (url, descriptor) in srcsets.into_iter().flat_map(urls::parse_srcset) {
+            let density = match self.density(descriptor) {
+                Some(density) => density,
+                None => continue,
+            };
+            if density >= self.pixel_ratio && sharp.map_or(true, |(_, d)| density < d) {
+                sharp = Some((url, density));
+            }
+            if largest.map_or(true, |(_, d)| density > d) {
+                largest = Some((url, density));
+            }
+        }
+        sharp.or(largest).map(|(url, _)| url)
+    }
+}
+
+/// Inline style keeping the space of an image with the given `width` and
+/// `height` attributes reserved while it loads. `None` unless both are
+/// plain pixel values.
+pub fn aspect_ratio_style(width: &str, height: &str) -> Option<String> {
+    let pixels = |value: &str| {
+        value
+            .trim()
+            .trim_end_matches("px")
+            .parse::<u32>()
+            .ok()
+            .filter(|v| *v > 0)
+    };
+    Some(format!(
+        "aspect-ratio: {} / {}",
+        pixels(width)?,
+        pixels(height)?
+    ))
+}
+
+/// Replaces the `srcset` of an image, and the candidates of the `<source>`
+/// elements of its `<picture>` passed in `sources`, with a single resolved
+/// `src`. The `width` and `height` are replaced with an aspect ratio hint,
+/// the only style kept on images.
+pub fn fix_image(handle: &Handle, base: &Url, target: &ImageTarget, sources: &[String]) {
+    let srcset = dom::get_attr("srcset", handle);
+    let srcsets = sources.iter().chain(srcset.iter()).map(String::as_str);
+    if let Some(src) = target.select(srcsets) {
+        let src = urls::resolve(base, src).unwrap_or_else(|| src.to_owned());
+        dom::set_attr("src", &src, Rc::clone(handle));
+    }
+    dom::remove_attr("srcset", handle);
+    dom::remove_attr("sizes", handle);
+
+    let width = dom::get_attr("width", handle).unwrap_or_default();
+    let height = dom::get_attr("height", handle).unwrap_or_default();
+    dom::remove_attr("style", handle);
+    if let Some(style) = aspect_ratio_style(&width, &height) {
+        dom::set_attr("style", &style, Rc::clone(handle));
+    }
+    dom::remove_attr("width", handle);
+    dom::remove_attr("height", handle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use html5ever::parse_document;
+    use html5ever::tendril::TendrilSink;
+    use markup5ever_rcdom::RcDom;
+
+    #[test]
+    fn selects_candidate_for_target() {
+        let target = ImageTarget::default();
+        let srcset = "s.jpg 300w, m.jpg 600w, l.jpg 1200w";
+        assert_eq!(target.select(vec![srcset]), Some("m.jpg"));
+        let retina = ImageTarget {
+            pixel_ratio: 2.0,
+            ..target
+        };
+        assert_eq!(retina.select(vec![srcset]), Some("l.jpg"));
+        let wide = ImageTarget {
+            width: 2000,
+            ..target
+        };
+        assert_eq!(wide.select(vec![srcset]), Some("l.jpg"));
+
+        assert_eq!(target.select(vec!["a.jpg, b.jpg 2x"]), Some("a.jpg"));
+        assert_eq!(retina.select(vec!["a.jpg, b.jpg 2x"]), Some("b.jpg"));
+        assert_eq!(target.select(vec!["a.jpg 0w, b.jpg 700w"]), Some("b.jpg"));
+        assert_eq!(target.select(vec!["x.avif 800w", "y.jpg 650w"]), Some("y.jpg"));
+        assert_eq!(target.select(vec![""]), None);
+    }
+
+    #[test]
+    fn aspect_ratio_from_dimensions() {
+        assert_eq!(
+            aspect_ratio_style("800", " 450px"),
+            Some("aspect-ratio: 800 / 450".to_owned())
+        );
+        assert_eq!(aspect_ratio_style("100%", "300"), None);
+        assert_eq!(aspect_ratio_style("800", ""), None);
+    }
+
+    #[test]
+    fn fix_image_drops_page_style() {
+        let dom = parse_document(RcDom::default(), Default::default())
+            .from_utf8()
+            .read_from(
+                &mut "<img src=a.jpg width=800 height=450 style=\"position: fixed\">\
+                      <img src=b.jpg width=100% style=\"position: fixed\">"
+                    .as_bytes(),
+            )
+            .unwrap();
+        let mut imgs = vec![];
+        dom::find_node(&dom.document, "img", &mut imgs);
+        let base = Url::parse("https://example.com/").unwrap();
+        for img in imgs.iter() {
+            fix_image(img, &base, &ImageTarget::default(), &[]);
+        }
+        assert_eq!(
+            dom::get_attr("style", &imgs[0]),
+            Some("aspect-ratio: 800 / 450".to_owned())
+        );
+        assert_eq!(dom::get_attr("style", &imgs[1]), None);
+    }
+}
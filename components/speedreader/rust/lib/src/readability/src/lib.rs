@@ -2,11 +2,17 @@
 extern crate html5ever;
 extern crate markup5ever_rcdom;
 extern crate regex;
+extern crate serde_json;
 extern crate url;
 #[macro_use]
 extern crate lazy_static;
+#[macro_use]
+extern crate serde;
 
+pub mod diagnostics;
 pub mod dom;
 pub mod error;
 pub mod extractor;
+pub mod images;
 pub mod scorer;
+pub mod urls;
@@ -1,3 +1,4 @@
+use diagnostics::{Diagnostics, Removal, Stage};
 use dom;
 use markup5ever_rcdom::{Handle, Node, RcDom};
 use markup5ever_rcdom::NodeData::{Comment, Doctype, Document, ProcessingInstruction, Element, Text};
@@ -5,11 +6,16 @@ use html5ever::tree_builder::TreeSink;
 use html5ever::tree_builder::{ElementFlags, NodeOrText};
 use html5ever::{LocalName, QualName};
 use regex::Regex;
+use serde::de::{Deserialize, Deserializer, Error};
+use serde::ser::{Serialize, Serializer};
 use std::cell::Cell;
 use std::collections::{BTreeMap, HashMap};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use images;
+use images::ImageTarget;
 use std::rc::Rc;
 use url::Url;
+use urls;
 
 pub static PUNCTUATIONS_REGEX: &str = r"([,]\?)";
 pub static UNLIKELY_CANDIDATES: &str = "-ad-|ai2html|banner\
@@ -26,7 +32,7 @@ pub static NEGATIVE_CANDIDATES: &str = "hidden|^hid$|hid$|hid|^hid\
         |banner|combx|comment|com-|contact|foot|footer|footnote|gdpr|header\
         |legends|menu|related|remark|replies|rss|shoutbox|sidebar|skyscraper\
         |social|sponsor|supplemental|ad-break|agegate|pagination|pager|popup\
-        yom-remote";
+        |yom-remote";
 static BLOCK_CHILD_TAGS: [&LocalName; 9] = [
     &local_name!("a"),
     &local_name!("blockquote"),
@@ -40,13 +46,144 @@ static BLOCK_CHILD_TAGS: [&LocalName; 9] = [
 ];
 
 static DECAY_FACTOR: f32 = 3.0;
+static CLASS_WEIGHT: f32 = 25.0;
+static TAG_SCORES: [(&str, f32); 18] = [
+    ("article", 10.0),
+    ("div", 5.0),
+    ("h1", 5.0),
+    ("h2", 5.0),
+    ("h3", 5.0),
+    ("h4", 5.0),
+    ("blockquote", 3.0),
+    ("pre", 3.0),
+    ("td", 3.0),
+    ("th", 5.0),
+    ("address", -3.0),
+    ("ol", -3.0),
+    ("ul", -3.0),
+    ("dl", -3.0),
+    ("dd", -3.0),
+    ("dt", -3.0),
+    ("li", -3.0),
+    ("form", -3.0),
+];
 
 lazy_static! {
     static ref PUNCTUATIONS: Regex = Regex::new(PUNCTUATIONS_REGEX).unwrap();
-    static ref LIKELY: Regex = Regex::new(LIKELY_CANDIDATES).unwrap();
-    static ref UNLIKELY: Regex = Regex::new(UNLIKELY_CANDIDATES).unwrap();
-    static ref POSITIVE: Regex = Regex::new(POSITIVE_CANDIDATES).unwrap();
-    static ref NEGATIVE: Regex = Regex::new(NEGATIVE_CANDIDATES).unwrap();
+    static ref DEFAULT_OPTIONS: ReadabilityOptions = ReadabilityOptions {
+        unlikely_candidates: Pattern::new(UNLIKELY_CANDIDATES).unwrap(),
+        likely_candidates: Pattern::new(LIKELY_CANDIDATES).unwrap(),
+        positive_candidates: Pattern::new(POSITIVE_CANDIDATES).unwrap(),
+        negative_candidates: Pattern::new(NEGATIVE_CANDIDATES).unwrap(),
+        class_weight: CLASS_WEIGHT,
+        decay_factor: DECAY_FACTOR,
+        tag_scores: TAG_SCORES
+            .iter()
+            .map(|&(tag, score)| (tag.to_owned(), score))
+            .collect(),
+        useless: UselessThresholds::default(),
+        siblings: SiblingThresholds::default(),
+    };
+}
+
+/// Regular expression, serialized as its pattern.
+#[derive(Clone, Debug)]
+pub struct Pattern(Regex);
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Pattern, regex::Error> {
+        Regex::new(pattern).map(Pattern)
+    }
+
+    pub fn is_match(&self, text: &str) -> bool {
+        self.0.is_match(text)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Pattern) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Pattern, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Pattern::new(&pattern).map_err(D::Error::custom)
+    }
+}
+
+/// Tunes how the scorer picks and cleans up content. Fields left out when
+/// deserializing keep their defaults, the constants of this module.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ReadabilityOptions {
+    /// Nodes with an id, class or `itemProp` matching `unlikely_candidates`,
+    /// but not `likely_candidates`, are removed before scoring.
+    pub unlikely_candidates: Pattern,
+    pub likely_candidates: Pattern,
+    /// Ids and classes matching these add or subtract `class_weight` from
+    /// the score of a node.
+    pub positive_candidates: Pattern,
+    pub negative_candidates: Pattern,
+    pub class_weight: f32,
+    /// Ancestors above the grandparent of a paragraph get its score divided
+    /// by `decay_factor` times their level.
+    pub decay_factor: f32,
+    /// Initial scores of candidates by tag name, 0 for tags not listed.
+    pub tag_scores: BTreeMap<String, f32>,
+    pub useless: UselessThresholds,
+    pub siblings: SiblingThresholds,
+}
+
+impl Default for ReadabilityOptions {
+    fn default() -> Self {
+        DEFAULT_OPTIONS.clone()
+    }
+}
+
+/// Limits beyond which `clean` drops a `div`, `form`, `table` or `ul` from
+/// the content.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UselessThresholds {
+    /// List items allowed beyond the number of paragraphs, outside of lists.
+    pub extra_list_items: u32,
+    /// Paragraphs needed for every input field.
+    pub paragraphs_per_input: f32,
+    /// Nodes with less text need one to `max_images` images.
+    pub min_text_length: usize,
+    pub max_images: u32,
+    /// Nodes with a single embed need at least this much text.
+    pub min_embed_text_length: usize,
+    /// Nodes with a class weight under `min_link_class_weight` may have at
+    /// most this link density.
+    pub max_link_density: f32,
+    pub min_link_class_weight: f32,
+}
+
+impl Default for UselessThresholds {
+    fn default() -> Self {
+        UselessThresholds {
+            extra_list_items: 100,
+            paragraphs_per_input: 3.0,
+            min_text_length: 10,
+            max_images: 2,
+            min_embed_text_length: 35,
+            max_link_density: 0.1,
+            min_link_class_weight: 10.0,
+        }
+    }
 }
 
 pub struct Candidate {
@@ -54,22 +191,64 @@ pub struct Candidate {
     pub score: Cell<f32>,
 }
 
-pub fn fix_img_path(handle: Handle, url: &Url) -> bool {
-    if let Some(src) = dom::get_attr("src", &handle) {
-        if !src.starts_with("//") && !src.starts_with("http://") && src.starts_with("https://") {
-            if let Ok(new_url) = url.join(&src) {
-                dom::set_attr("src", new_url.as_str(), handle);
-                true
-            } else {
-                // failed to fix
-                false
-            }
-        } else {
-            // all OK
-            true
+/// Decides which siblings of the top candidate are merged into the extracted
+/// content, same as Mozilla's Readability. Siblings qualify with a score of
+/// at least `score_ratio` of the top candidate's, and no less than
+/// `min_score`; those sharing its class get `class_bonus_ratio` of its score
+/// added. Paragraphs qualify by their text instead: long ones with a link
+/// density under `max_link_density`, and shorter sentences without links.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SiblingThresholds {
+    pub score_ratio: f32,
+    pub min_score: f32,
+    pub class_bonus_ratio: f32,
+    pub long_paragraph_length: usize,
+    pub max_link_density: f32,
+}
+
+impl Default for SiblingThresholds {
+    fn default() -> Self {
+        SiblingThresholds {
+            score_ratio: 0.2,
+            min_score: 10.0,
+            class_bonus_ratio: 0.2,
+            long_paragraph_length: 80,
+            max_link_density: 0.25,
         }
-    } else {
-        false
+    }
+}
+
+/// Resolves the image URLs against the base URL and picks the `srcset`
+/// candidate for the target. Returns false if the image has no `src`.
+pub fn fix_img_path(handle: Handle, url: &Url, target: &ImageTarget) -> bool {
+    urls::fix_urls(&handle, url);
+    images::fix_image(&handle, url, target, &[]);
+    dom::get_attr("src", &handle).is_some()
+}
+
+/// Moves the candidates of the `<source>` elements of a `<picture>` into
+/// the choice of its `<img>`, dropping the sources.
+pub fn fix_picture(dom: &mut RcDom, handle: &Handle, url: &Url, target: &ImageTarget) {
+    let mut sources = vec![];
+    let mut images = vec![];
+    for child in handle.children.borrow().iter() {
+        match dom::get_tag_name(child) {
+            Some(&local_name!("source")) => sources.push(child.clone()),
+            Some(&local_name!("img")) => images.push(child.clone()),
+            _ => (),
+        }
+    }
+    let srcsets: Vec<String> = sources
+        .iter()
+        .filter_map(|source| dom::get_attr("srcset", source))
+        .map(|srcset| urls::resolve_srcset(url, &srcset))
+        .collect();
+    for source in sources.iter() {
+        dom.remove_from_parent(source);
+    }
+    for img in images.iter() {
+        images::fix_image(img, url, target, &srcsets);
     }
 }
 
@@ -111,29 +290,16 @@ pub fn is_candidate(handle: &Handle) -> bool {
     }
 }
 
-pub fn init_content_score(handle: &Handle) -> f32 {
+pub fn init_content_score(handle: &Handle, options: &ReadabilityOptions) -> f32 {
     let score = match handle.data {
-        Element { ref name, .. } => match name.local {
-            local_name!("article") => 10.0,
-            local_name!("div") => 5.0,
-            local_name!("h1") | local_name!("h2") | local_name!("h3") | local_name!("h4") => 5.0,
-            local_name!("blockquote") => 3.0,
-            local_name!("pre") => 3.0,
-            local_name!("td") => 3.0,
-            local_name!("th") => 5.0,
-            local_name!("address") => -3.0,
-            local_name!("ol") => -3.0,
-            local_name!("ul") => -3.0,
-            local_name!("dl") => -3.0,
-            local_name!("dd") => -3.0,
-            local_name!("dt") => -3.0,
-            local_name!("li") => -3.0,
-            local_name!("form") => -3.0,
-            _ => 0.0,
-        },
+        Element { ref name, .. } => options
+            .tag_scores
+            .get(name.local.as_ref())
+            .cloned()
+            .unwrap_or(0.0),
         _ => 0.0,
     };
-    score + get_class_weight(handle)
+    score + get_class_weight(handle, options)
 }
 
 pub fn calc_content_score(handle: &Handle) -> f32 {
@@ -146,7 +312,7 @@ pub fn calc_content_score(handle: &Handle) -> f32 {
     score
 }
 
-pub fn get_class_weight(handle: &Handle) -> f32 {
+pub fn get_class_weight(handle: &Handle, options: &ReadabilityOptions) -> f32 {
     let mut weight: f32 = 0.0;
     if let Element { ref attrs, .. } = handle.data {
         for name in ["id", "class"].iter() {
@@ -154,11 +320,11 @@ pub fn get_class_weight(handle: &Handle) -> f32 {
                 if val == "" {
                     weight -= 3.0
                 }
-                if POSITIVE.is_match(&val) {
-                    weight += 25.0
+                if options.positive_candidates.is_match(&val) {
+                    weight += options.class_weight
                 };
-                if NEGATIVE.is_match(&val) {
-                    weight -= 25.0
+                if options.negative_candidates.is_match(&val) {
+                    weight -= options.class_weight
                 }
             }
         }
@@ -166,7 +332,16 @@ pub fn get_class_weight(handle: &Handle) -> f32 {
     weight
 }
 
-pub fn preprocess(mut dom: &mut RcDom, handle: Handle, mut title: &mut String) -> bool {
+/// Extracts the title and drops the nodes that can't be content before
+/// scoring. Returns why the node itself should be removed, if it should.
+pub fn preprocess(
+    mut dom: &mut RcDom,
+    id: &Path,
+    handle: Handle,
+    mut title: &mut String,
+    options: &ReadabilityOptions,
+    mut diagnostics: Option<&mut Diagnostics>,
+) -> Option<Removal> {
     if let Element {
         ref name,
         ref attrs,
@@ -174,17 +349,19 @@ pub fn preprocess(mut dom: &mut RcDom, handle: Handle, mut title: &mut String) -
     } = handle.data
     {
         match name.local {
-            local_name!("script") | local_name!("link") | local_name!("style") => return true,
+            local_name!("script") | local_name!("link") | local_name!("style") => {
+                return Some(Removal::UnwantedTag)
+            }
             local_name!("title") => dom::extract_text(&handle, &mut title, true),
             _ => (),
         }
         for attr_name in ["id", "class", "itemProp"].iter() {
             if let Some(val) = dom::attr(attr_name, &attrs.borrow()) {
                 if name.local != local_name!("body")
-                    && UNLIKELY.is_match(&val)
-                    && !LIKELY.is_match(&val)
+                    && options.unlikely_candidates.is_match(&val)
+                    && !options.likely_candidates.is_match(&val)
                 {
-                    return true;
+                    return Some(Removal::UnlikelyCandidate);
                 }
             }
         }
@@ -192,8 +369,20 @@ pub fn preprocess(mut dom: &mut RcDom, handle: Handle, mut title: &mut String) -
     let mut useless_nodes = vec![];
     let mut paragraph_nodes = vec![];
     let mut br_count = 0;
-    for child in handle.children.borrow().iter() {
-        if preprocess(&mut dom, child.clone(), &mut title) {
+    for (i, child) in handle.children.borrow().iter().enumerate() {
+        let cid = id.join(i.to_string());
+        let removal = preprocess(
+            &mut dom,
+            cid.as_path(),
+            child.clone(),
+            &mut title,
+            options,
+            diagnostics.as_mut().map(|d| &mut **d),
+        );
+        if let Some(removal) = removal {
+            if let Some(diagnostics) = diagnostics.as_mut() {
+                diagnostics.removed(Stage::Preprocess, &cid, child, removal);
+            }
             useless_nodes.push(child.clone());
         }
         match child.data {
@@ -223,7 +412,7 @@ pub fn preprocess(mut dom: &mut RcDom, handle: Handle, mut title: &mut String) -
             dom.append(&p, NodeOrText::AppendText(contents.borrow().clone()))
         }
     }
-    false
+    None
 }
 
 pub fn find_candidates(
@@ -232,6 +421,7 @@ pub fn find_candidates(
     handle: Handle,
     candidates: &mut BTreeMap<String, Candidate>,
     nodes: &mut BTreeMap<String, Rc<Node>>,
+    options: &ReadabilityOptions,
 ) {
     // Id of a particular node maps to its position in the dom tree, represented
     // as std::path::Path data structure
@@ -252,12 +442,12 @@ pub fn find_candidates(
         // tree is traverse backwards:
         //   parent: no decay
         //   grandparent: scoring divided by 2
-        //   subsequent parent nodes: level * DECAY_FACTOR (3)
+        //   subsequent parent nodes: level * decay factor (3 by default)
 
         // parent
         if let Some(c) = id
             .parent()
-            .and_then(|pid| find_or_create_candidate(pid, candidates, nodes))
+            .and_then(|pid| find_or_create_candidate(pid, candidates, nodes, options))
         {
             c.score.set(c.score.get() + score)
         }
@@ -266,7 +456,7 @@ pub fn find_candidates(
         if let Some(c) = id
             .parent()
             .and_then(|pid| pid.parent())
-            .and_then(|gpid| find_or_create_candidate(gpid, candidates, nodes))
+            .and_then(|gpid| find_or_create_candidate(gpid, candidates, nodes, options))
         {
             c.score.set(c.score.get() + (score / 2.0))
         }
@@ -280,8 +470,8 @@ pub fn find_candidates(
             let paths = get_all_ancestor_paths(distant_ancs);
             let mut level = 2.0;
             for p in paths {
-                let add_score = score / (level * DECAY_FACTOR);
-                if let Some(c) = find_or_create_candidate(p, candidates, nodes) {
+                let add_score = score / (level * options.decay_factor);
+                if let Some(c) = find_or_create_candidate(p, candidates, nodes, options) {
                     c.score.set(c.score.get() + add_score);
                     level += 1.0;
                 }
@@ -297,6 +487,7 @@ pub fn find_candidates(
             child.clone(),
             candidates,
             nodes,
+            options,
         )
     }
 }
@@ -314,6 +505,7 @@ fn find_or_create_candidate<'a>(
     id: &Path,
     candidates: &'a mut BTreeMap<String, Candidate>,
     nodes: &BTreeMap<String, Rc<Node>>,
+    options: &ReadabilityOptions,
 ) -> Option<&'a Candidate> {
     if let Some(id) = id.to_str().map(|id| id.to_string()) {
         if let Some(node) = nodes.get(&id) {
@@ -322,7 +514,7 @@ fn find_or_create_candidate<'a>(
                     id.clone(),
                     Candidate {
                         node: node.clone(),
-                        score: Cell::new(init_content_score(&node)),
+                        score: Cell::new(init_content_score(&node, options)),
                     },
                 );
             }
@@ -332,28 +524,95 @@ fn find_or_create_candidate<'a>(
     None
 }
 
-// decides whether the handle node is useless (should be dropped) or not.
+/// The top candidate and those of its siblings that pass the `thresholds`,
+/// with their ids, in document order.
+pub fn content_siblings(
+    id: &Path,
+    top_candidate: &Candidate,
+    candidates: &BTreeMap<String, Candidate>,
+    nodes: &BTreeMap<String, Rc<Node>>,
+    thresholds: &SiblingThresholds,
+) -> Vec<(PathBuf, Handle)> {
+    let top = (id.to_path_buf(), top_candidate.node.clone());
+    let parent_id = match id.parent() {
+        Some(parent_id) => parent_id,
+        None => return vec![top],
+    };
+    let parent = match parent_id.to_str().and_then(|pid| nodes.get(pid)) {
+        Some(parent) => parent,
+        None => return vec![top],
+    };
+    let top_score = top_candidate.score.get();
+    let threshold = f32::max(thresholds.min_score, top_score * thresholds.score_ratio);
+    let top_class = dom::get_attr("class", &top_candidate.node).filter(|c| !c.is_empty());
+
+    let mut siblings = vec![];
+    for (i, sibling) in parent.children.borrow().iter().enumerate() {
+        let sibling_id = parent_id.join(i.to_string());
+        if Rc::ptr_eq(sibling, &top_candidate.node) {
+            siblings.push(top.clone());
+            continue;
+        }
+        if let Element { ref name, .. } = sibling.data {
+            let mut score = sibling_id
+                .to_str()
+                .and_then(|sid| candidates.get(sid))
+                .map(|c| c.score.get())
+                .unwrap_or(0.0);
+            if top_class.is_some() && dom::get_attr("class", sibling) == top_class {
+                score += top_score * thresholds.class_bonus_ratio;
+            }
+            let is_content = if score >= threshold {
+                true
+            } else if name.local == local_name!("p") {
+                let link_density = get_link_density(sibling);
+                let text_len = dom::text_len(sibling);
+                let mut text = String::new();
+                dom::extract_text(sibling, &mut text, true);
+                if text_len >= thresholds.long_paragraph_length {
+                    link_density < thresholds.max_link_density
+                } else {
+                    text_len > 0
+                        && link_density == 0.0
+                        && (text.contains(". ") || text.ends_with('.'))
+                }
+            } else {
+                false
+            };
+            if is_content {
+                siblings.push((sibling_id, sibling.clone()));
+            }
+        }
+    }
+    siblings
+}
+
+// decides whether the handle node is useless (should be dropped) or not, and
+// why.
 pub fn clean<S: ::std::hash::BuildHasher>(
     mut dom: &mut RcDom,
     id: &Path,
     handle: Handle,
     url: &Url,
+    target: &ImageTarget,
     title: &str,
     features: &HashMap<String, u32, S>,
     candidates: &BTreeMap<String, Candidate>,
-) -> bool {
+    options: &ReadabilityOptions,
+    mut diagnostics: Option<&mut Diagnostics>,
+) -> Option<Removal> {
     let useless = match handle.data {
-        Document => false,
-        Doctype { .. } => false,
+        Document => None,
+        Doctype { .. } => None,
         Text { ref contents } => {
             let s = contents.borrow();
             if s.trim().is_empty() {
-                true
+                Some(Removal::Blank)
             } else {
-                false
+                None
             }
         }
-        Comment { .. } => true,
+        Comment { .. } => Some(Removal::Comment),
         Element {
             ref name,
             // ref attrs,
@@ -369,13 +628,26 @@ pub fn clean<S: ::std::hash::BuildHasher>(
                 | local_name!("object")
                 | local_name!("header")
                 | local_name!("footer")
-                | local_name!("aside") => true,
+                | local_name!("aside") => Some(Removal::UnwantedTag),
                 local_name!("form")
                 | local_name!("table")
                 | local_name!("ul")
-                | local_name!("div") => is_useless(id, &handle, candidates),
-                local_name!("img") => !fix_img_path(handle.clone(), url),
-                _ => false,
+                | local_name!("div") => useless_reason(id, &handle, candidates, options),
+                local_name!("img") => {
+                    if fix_img_path(handle.clone(), url, target) {
+                        None
+                    } else {
+                        Some(Removal::MissingImage)
+                    }
+                }
+                local_name!("picture") => {
+                    fix_picture(dom, &handle, url, target);
+                    None
+                }
+                _ => {
+                    urls::fix_urls(&handle, url);
+                    None
+                }
             }
 
             // // cleans all ids, classes and styles in node
@@ -389,15 +661,22 @@ pub fn clean<S: ::std::hash::BuildHasher>(
     let mut useless_nodes = vec![];
     for (i, child) in handle.children.borrow().iter().enumerate() {
         let pid = id.join(i.to_string());
-        if clean(
+        let removal = clean(
             &mut dom,
             pid.as_path(),
             child.clone(),
             url,
+            target,
             title,
             features,
             candidates,
-        ) {
+            options,
+            diagnostics.as_mut().map(|d| &mut **d),
+        );
+        if let Some(removal) = removal {
+            if let Some(diagnostics) = diagnostics.as_mut() {
+                diagnostics.removed(Stage::Clean, &pid, child, removal);
+            }
             useless_nodes.push(child.clone());
         }
     }
@@ -405,54 +684,73 @@ pub fn clean<S: ::std::hash::BuildHasher>(
         dom.remove_from_parent(node);
     }
     if dom::is_empty(&handle) {
-        return true;
+        return useless.or(Some(Removal::Empty));
     }
     useless
 }
 
-pub fn is_useless(id: &Path, handle: &Handle, candidates: &BTreeMap<String, Candidate>) -> bool {
+pub fn is_useless(
+    id: &Path,
+    handle: &Handle,
+    candidates: &BTreeMap<String, Candidate>,
+    options: &ReadabilityOptions,
+) -> bool {
+    useless_reason(id, handle, candidates, options).is_some()
+}
+
+/// Why `is_useless` considers the node useless, if it does.
+pub fn useless_reason(
+    id: &Path,
+    handle: &Handle,
+    candidates: &BTreeMap<String, Candidate>,
+    options: &ReadabilityOptions,
+) -> Option<Removal> {
+    let thresholds = &options.useless;
     let tag_name = dom::get_tag_name(&handle);
-    let weight = get_class_weight(&handle);
+    let weight = get_class_weight(&handle, options);
     let score = id
         .to_str()
         .and_then(|id| candidates.get(id))
         .map(|c| c.score.get())
         .unwrap_or(0.0);
     if weight + score < 0.0 {
-        return true;
+        return Some(Removal::NegativeScore);
     }
 
     let para_count =
         dom::count_nodes(&handle, &local_name!("p")) + dom::text_children_count(&handle) as u32;
-    let li_count = dom::count_nodes(&handle, &local_name!("li")) as i32 - 100;
+    let li_count =
+        dom::count_nodes(&handle, &local_name!("li")) as i64 - thresholds.extra_list_items as i64;
 
     if tag_name != Some(&local_name!("ul"))
         && tag_name != Some(&local_name!("ol"))
-        && li_count > para_count as i32
+        && li_count > para_count as i64
     {
-        return true;
+        return Some(Removal::ListItems);
     }
 
     let input_count = dom::count_nodes(&handle, &local_name!("input"));
-    if input_count as f32 > f32::floor(para_count as f32 / 3.0) {
-        return true;
+    if input_count as f32 > f32::floor(para_count as f32 / thresholds.paragraphs_per_input) {
+        return Some(Removal::Inputs);
     }
 
     let img_count = dom::count_nodes(&handle, &local_name!("img"));
     let content_length = dom::text_len(&handle);
 
-    if content_length < 10 && (img_count == 0 || img_count > 2) {
-        return true;
+    if content_length < thresholds.min_text_length
+        && (img_count == 0 || img_count > thresholds.max_images)
+    {
+        return Some(Removal::LittleText);
     }
 
     let embed_count = dom::count_nodes(&handle, &local_name!("embed"));
-    if (embed_count == 1 && content_length < 35) || embed_count > 1 {
-        return true;
+    if (embed_count == 1 && content_length < thresholds.min_embed_text_length) || embed_count > 1 {
+        return Some(Removal::Embeds);
     }
 
     let link_density = get_link_density(handle);
-    if weight < 10.0 && link_density > 0.1 {
-        return true;
+    if weight < thresholds.min_link_class_weight && link_density > thresholds.max_link_density {
+        return Some(Removal::LinkDensity);
     }
-    false
+    None
 }
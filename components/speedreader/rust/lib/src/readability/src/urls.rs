@@ -0,0 +1,139 @@
+use markup5ever_rcdom::Handle;
+use std::rc::Rc;
+use url::Url;
+
+use dom;
+
+/// Attributes holding a single URL that are resolved against the base URL.
+pub const URL_ATTRIBUTES: [&str; 4] = ["href", "src", "poster", "cite"];
+
+/// Resolves a possibly relative URL attribute value against the base URL.
+/// Returns `None` for values that should be kept as they are: absolute URLs
+/// of any scheme, including `mailto:` and `data:`, fragment-only links and
+/// values that don't resolve.
+pub fn resolve(base: &Url, value: &str) -> Option<String> {
+    let value = value.trim();
+    if value.is_empty() || value.starts_with('#') || Url::parse(value).is_ok() {
+        return None;
+    }
+    base.join(value).ok().map(Url::into_string)
+}
+
+/// Resolves every candidate URL of a `srcset` attribute, keeping their
+/// descriptors.
+pub fn resolve_srcset(base: &Url, srcset: &str) -> String {
+    parse_srcset(srcset)
+        .into_iter()
+        .map(|(url, descriptor)| {
+            let url = resolve(base, url).unwrap_or_else(|| url.to_owned());
+            if descriptor.is_empty() {
+                url
+            } else {
+                format!("{} {}", url, descriptor)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Splits a `srcset` into URL and descriptor pairs. URLs may contain commas,
+/// only a comma ending a URL or following a descriptor separates candidates.
+pub fn parse_srcset(srcset: &str) -> Vec<(&str, &str)> {
+    let mut candidates = vec![];
+    let mut rest = srcset;
+    loop {
+        rest = rest.trim_start_matches(|c: char| c.is_whitespace() || c == ',');
+        if rest.is_empty() {
+            return candidates;
+        }
+        let url_end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        let (url, after) = rest.split_at(url_end);
+        if url.ends_with(',') {
+            candidates.push((url.trim_end_matches(','), ""));
+            rest = after;
+        } else {
+            let descriptor_end = after.find(',').unwrap_or(after.len());
+            candidates.push((url, after[..descriptor_end].trim()));
+            rest = &after[descriptor_end..];
+        }
+    }
+}
+
+/// URL relative links of the document resolve against: the first
+/// `<base href>`, itself resolved against the document URL, or the document
+/// URL.
+pub fn document_base(document: &Handle, url: &Url) -> Url {
+    let mut bases = vec![];
+    dom::find_node(document, "base", &mut bases);
+    bases
+        .iter()
+        .filter_map(|base| dom::get_attr("href", base))
+        .next()
+        .and_then(|href| url.join(href.trim()).ok())
+        .unwrap_or_else(|| url.clone())
+}
+
+/// Resolves the URL attributes of an element in place.
+pub fn fix_urls(handle: &Handle, base: &Url) {
+    for name in URL_ATTRIBUTES.iter() {
+        if let Some(resolved) = dom::get_attr(name, handle).and_then(|v| resolve(base, &v)) {
+            dom::set_attr(name, &resolved, Rc::clone(handle));
+        }
+    }
+    if let Some(srcset) = dom::get_attr("srcset", handle) {
+        dom::set_attr("srcset", &resolve_srcset(base, &srcset), Rc::clone(handle));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolves_relative_urls() {
+        let base = Url::parse("https://example.com/news/2020/article.html?page=1").unwrap();
+        let resolve = |value| resolve(&base, value);
+        assert_eq!(
+            resolve("../img.png"),
+            Some("https://example.com/news/img.png".to_owned())
+        );
+        assert_eq!(
+            resolve("page2"),
+            Some("https://example.com/news/2020/page2".to_owned())
+        );
+        assert_eq!(
+            resolve("/about"),
+            Some("https://example.com/about".to_owned())
+        );
+        assert_eq!(
+            resolve("//cdn.example.com/a.png"),
+            Some("https://cdn.example.com/a.png".to_owned())
+        );
+        assert_eq!(
+            resolve("?page=2"),
+            Some("https://example.com/news/2020/article.html?page=2".to_owned())
+        );
+        assert_eq!(resolve("#comments"), None);
+        assert_eq!(resolve("mailto:me@example.com"), None);
+        assert_eq!(resolve("data:image/png;base64,AAAA"), None);
+        assert_eq!(resolve("http://other.com/x"), None);
+        assert_eq!(resolve(""), None);
+    }
+
+    #[test]
+    fn resolves_srcset() {
+        let base = Url::parse("https://example.com/a/").unwrap();
+        assert_eq!(
+            resolve_srcset(&base, "small.jpg 480w, /large.jpg 1080w"),
+            "https://example.com/a/small.jpg 480w, https://example.com/large.jpg 1080w"
+        );
+        assert_eq!(
+            resolve_srcset(&base, "img/w_600,h_400.jpg 2x,img/b.jpg"),
+            "https://example.com/a/img/w_600,h_400.jpg 2x, https://example.com/a/img/b.jpg"
+        );
+        assert_eq!(
+            parse_srcset(" a.jpg, b.jpg 2x "),
+            vec![("a.jpg", ""), ("b.jpg", "2x")]
+        );
+    }
+}
@@ -1,7 +1,18 @@
+extern crate html5ever;
+extern crate markup5ever_rcdom;
 extern crate readability;
+extern crate serde_json;
 extern crate url;
 
-use readability::extractor::extract;
+use html5ever::parse_document;
+use html5ever::tendril::TendrilSink;
+use markup5ever_rcdom::RcDom;
+use readability::diagnostics::{Diagnostics, Removal, Stage};
+use readability::extractor::{extract, extract_dom, Product};
+use readability::images::ImageTarget;
+use readability::dom;
+use readability::scorer::{get_class_weight, Pattern, ReadabilityOptions, SiblingThresholds};
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::Read;
 use url::Url;
@@ -16,6 +27,194 @@ fn load_test_files(test_name: &str) -> String {
     expected
 }
 
+fn extract_with(html: &str, options: &ReadabilityOptions) -> Product {
+    let mut dom = parse_document(RcDom::default(), Default::default())
+        .from_utf8()
+        .read_from(&mut html.as_bytes())
+        .unwrap();
+    let url = Url::parse("http://url.com").unwrap();
+    let features: HashMap<String, u32> = HashMap::new();
+    extract_dom(&mut dom, &url, &features, &ImageTarget::default(), options, None).unwrap()
+}
+
+// Article text split in two containers by an inline ad
+static SPLIT_CONTAINERS: &str = r#"<html><body><div id="wrapper">
+    <div class="article-body">
+        <p>The first paragraph of the story, which sets the scene, runs well over a hundred characters long.</p>
+        <p>A second paragraph, also in the first container, with a comma or two, continues the story.</p>
+    </div>
+    <div class="inline-promo"><span>Advertisement</span></div>
+    <div class="article-body">
+        <p>The story continues after the advertisement, in a second container with the same class.</p>
+        <p>Its last paragraph, with some more commas, wraps the story up and ends the article.</p>
+    </div>
+    <ul class="more"><li><a href="/a">Another story</a></li><li><a href="/b">And another</a></li></ul>
+</div></body></html>"#;
+
+// Loose paragraphs following the container of the rest of the article
+static SIBLING_PARAGRAPHS: &str = r#"<html><body><div id="wrapper">
+    <div class="article-body">
+        <p>The first paragraph of the story, which sets the scene, runs well over a hundred characters long.</p>
+        <p>A second paragraph, also in the container, with a comma or two, continues the story for a while.</p>
+        <p>A third paragraph, with a few more commas, keeps the container scoring well above its siblings.</p>
+    </div>
+    <p>A closing paragraph outside of the container that is long enough to be part of the article text.</p>
+    <p>Short closing words.</p>
+    <p>Read more: <a href="/other">another story about something else entirely</a></p>
+</div></body></html>"#;
+
+#[test]
+fn merges_split_containers() {
+    let product = extract_with(SPLIT_CONTAINERS, &ReadabilityOptions::default());
+    assert!(product.content.contains("sets the scene"), "{}", product.content);
+    assert!(product.content.contains("wraps the story up"), "{}", product.content);
+    assert!(!product.content.contains("Advertisement"), "{}", product.content);
+    assert!(!product.content.contains("Another story"), "{}", product.content);
+    assert_eq!(product.content.matches("<div class=\"article-body\">").count(), 2);
+}
+
+#[test]
+fn merges_sibling_paragraphs() {
+    let product = extract_with(SIBLING_PARAGRAPHS, &ReadabilityOptions::default());
+    assert!(product.content.contains("keeps the container"), "{}", product.content);
+    assert!(product.content.contains("A closing paragraph"), "{}", product.content);
+    assert!(product.content.contains("Short closing words."), "{}", product.content);
+    assert!(!product.content.contains("Read more"), "{}", product.content);
+}
+
+#[test]
+fn sibling_thresholds_apply() {
+    let options = ReadabilityOptions {
+        siblings: SiblingThresholds {
+            min_score: std::f32::INFINITY,
+            long_paragraph_length: std::usize::MAX,
+            ..SiblingThresholds::default()
+        },
+        ..ReadabilityOptions::default()
+    };
+    let product = extract_with(SPLIT_CONTAINERS, &options);
+    assert!(product.content.contains("sets the scene"), "{}", product.content);
+    assert!(!product.content.contains("wraps the story up"), "{}", product.content);
+    assert!(product.content.starts_with("<div class=\"article-body\">"), "{}", product.content);
+    assert_eq!(product.content.matches("<div class=\"article-body\">").count(), 1);
+
+    let options = ReadabilityOptions {
+        siblings: SiblingThresholds {
+            min_score: std::f32::INFINITY,
+            max_link_density: 0.0,
+            ..SiblingThresholds::default()
+        },
+        ..ReadabilityOptions::default()
+    };
+    let product = extract_with(SIBLING_PARAGRAPHS, &options);
+    assert!(!product.content.contains("A closing paragraph"), "{}", product.content);
+    // Short sentences without links still count
+    assert!(product.content.contains("Short closing words."), "{}", product.content);
+}
+
+#[test]
+fn options_deserialize_over_defaults() {
+    let options: ReadabilityOptions = serde_json::from_str(
+        r#"{"negative_candidates": "sidebar|promo", "decay_factor": 2.0, "siblings": {"min_score": 5.0}}"#,
+    )
+    .unwrap();
+    let expected = ReadabilityOptions {
+        negative_candidates: Pattern::new("sidebar|promo").unwrap(),
+        decay_factor: 2.0,
+        siblings: SiblingThresholds {
+            min_score: 5.0,
+            ..SiblingThresholds::default()
+        },
+        ..ReadabilityOptions::default()
+    };
+    assert_eq!(options, expected);
+    let json = serde_json::to_string(&options).unwrap();
+    assert_eq!(serde_json::from_str::<ReadabilityOptions>(&json).unwrap(), expected);
+
+    assert!(serde_json::from_str::<ReadabilityOptions>(r#"{"likely_candidates": "("}"#).is_err());
+}
+
+#[test]
+fn negative_candidates_match_separately() {
+    let dom = parse_document(RcDom::default(), Default::default())
+        .from_utf8()
+        .read_from(&mut &b"<div class=\"popup\"></div><div class=\"yom-remote\"></div>"[..])
+        .unwrap();
+    let mut divs = vec![];
+    dom::find_node(&dom.document, "div", &mut divs);
+    let options = ReadabilityOptions::default();
+    assert_eq!(get_class_weight(&divs[0], &options), -25.0);
+    assert_eq!(get_class_weight(&divs[1], &options), -25.0);
+}
+
+#[test]
+fn options_tune_extraction() {
+    let options = ReadabilityOptions {
+        unlikely_candidates: Pattern::new("article-body").unwrap(),
+        likely_candidates: Pattern::new("^main$").unwrap(),
+        ..ReadabilityOptions::default()
+    };
+    let product = extract_with(SPLIT_CONTAINERS, &options);
+    assert!(!product.content.contains("sets the scene"), "{}", product.content);
+}
+
+#[test]
+fn diagnostics_explain_extraction() {
+    let html = r#"<html><head><script>track()</script></head><body>
+    <div class="comments"><p>First comment, with a comma, that is not part of the article at all.</p></div>
+    <article>
+        <p>The first paragraph of the story, which sets the scene, runs well over a hundred characters long.</p>
+        <p>A second paragraph, with a comma or two, continues the story and ends the article.</p>
+        <div><a href="/share">Share on this network</a> <a href="/tweet">or that one</a></div>
+    </article>
+</body></html>"#;
+    let mut dom = parse_document(RcDom::default(), Default::default())
+        .from_utf8()
+        .read_from(&mut html.as_bytes())
+        .unwrap();
+    let url = Url::parse("http://url.com").unwrap();
+    let features: HashMap<String, u32> = HashMap::new();
+    let mut diagnostics = Diagnostics::default();
+    let options = ReadabilityOptions::default();
+    let product = extract_dom(
+        &mut dom,
+        &url,
+        &features,
+        &ImageTarget::default(),
+        &options,
+        Some(&mut diagnostics),
+    )
+    .unwrap();
+    assert!(!product.content.contains("Share"), "{}", product.content);
+
+    let article = diagnostics.candidates.iter().find(|c| c.tag == "article").unwrap();
+    assert_eq!(diagnostics.top_candidate.as_ref(), Some(&article.path));
+    assert_eq!(diagnostics.content, vec![article.path.clone()]);
+    assert_eq!(article.base_score, 10.0);
+    assert!(article.content_score > article.base_score);
+    assert!(article.link_density > 0.0);
+    assert_eq!(article.score, article.content_score * (1.0 - article.link_density));
+
+    let removed: Vec<_> = diagnostics
+        .removed
+        .iter()
+        .map(|r| (r.tag.as_str(), r.class.as_ref().map(String::as_str), r.stage, r.reason))
+        .collect();
+    assert_eq!(
+        removed,
+        vec![
+            ("script", None, Stage::Preprocess, Removal::UnwantedTag),
+            ("div", Some("comments"), Stage::Preprocess, Removal::UnlikelyCandidate),
+            ("div", None, Stage::Clean, Removal::LinkDensity),
+        ]
+    );
+    assert_eq!(diagnostics.removed[0].path, "/0/0/0");
+
+    let json: serde_json::Value = serde_json::from_str(&diagnostics.to_json()).unwrap();
+    assert_eq!(json["removed"][2]["reason"], "link_density");
+    assert_eq!(json["removed"][2]["stage"], "clean");
+}
+
 #[macro_use]
 #[cfg(test)]
 mod test {
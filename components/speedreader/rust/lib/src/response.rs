@@ -0,0 +1,168 @@
+use encoding_rs::Encoding;
+use url::Url;
+
+use crate::charset::encoding_from_content_type;
+use crate::speedreader::SpeedReaderError;
+
+/// MIME types of documents that can be distilled.
+const HTML_MIME_TYPES: [&str; 2] = ["text/html", "application/xhtml+xml"];
+
+/// HTTP response the document comes from, as far as it is known. Each
+/// header holds the raw header value.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ResponseInfo {
+    pub status: Option<u16>,
+    pub content_type: Option<String>,
+    pub content_language: Option<String>,
+    pub link: Option<String>,
+}
+
+impl ResponseInfo {
+    /// Lowercased MIME type of `Content-Type`, without parameters.
+    pub fn mime_type(&self) -> Option<String> {
+        let content_type = self.content_type.as_ref()?;
+        let essence = content_type.split(';').next().unwrap_or_default().trim();
+        Some(essence.to_ascii_lowercase()).filter(|m| !m.is_empty())
+    }
+
+    /// Fails for responses that are not successful HTML documents. Unknown
+    /// status or type don't count against a response.
+    pub fn check_distillable(&self) -> Result<(), SpeedReaderError> {
+        if let Some(status) = self.status.filter(|s| !(200..300).contains(s)) {
+            return Err(SpeedReaderError::UnsupportedResponse(format!("status {}", status)));
+        }
+        match self.mime_type() {
+            Some(mime_type) if !HTML_MIME_TYPES.contains(&mime_type.as_str()) => Err(
+                SpeedReaderError::UnsupportedResponse(format!("content type {}", mime_type)),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Encoding named by the `Content-Type` charset.
+    pub fn encoding(&self) -> Option<&'static Encoding> {
+        self.content_type
+            .as_ref()
+            .and_then(|content_type| encoding_from_content_type(content_type))
+    }
+
+    /// Language of `Content-Language`, if it names a single one.
+    pub fn language(&self) -> Option<String> {
+        let language = self.content_language.as_ref()?.trim();
+        if language.is_empty() || language.contains(',') {
+            None
+        } else {
+            Some(language.to_owned())
+        }
+    }
+
+    /// Target of the `rel="canonical"` link in the `Link` header, resolved
+    /// against the document URL.
+    pub fn canonical_url(&self, url: &Url) -> Option<Url> {
+        link_values(self.link.as_ref()?)
+            .into_iter()
+            .find(|(_, params)| {
+                params.iter().any(|(name, value)| {
+                    name.eq_ignore_ascii_case("rel")
+                        && value
+                            .split_whitespace()
+                            .any(|rel| rel.eq_ignore_ascii_case("canonical"))
+                })
+            })
+            .and_then(|(target, _)| url.join(target).ok())
+    }
+}
+
+/// Targets and parameters of the links in a `Link` header value, e.g.
+/// `<https://e.com/a>; rel="canonical", <https://e.com/b>; rel=next`.
+fn link_values(header: &str) -> Vec<(&str, Vec<(&str, String)>)> {
+    let mut links = vec![];
+    let mut rest = header;
+    while let Some(start) = rest.find('<') {
+        let end = match rest[start..].find('>') {
+            Some(end) => start + end,
+            None => break,
+        };
+        let target = rest[start + 1..end].trim();
+        rest = &rest[end + 1..];
+
+        // Parameters run up to the next link, quoted values may have commas
+        let mut params_end = rest.len();
+        let mut quoted = false;
+        for (i, c) in rest.char_indices() {
+            match c {
+                '"' => quoted = !quoted,
+                ',' if !quoted => {
+                    params_end = i;
+                    break;
+                }
+                _ => (),
+            }
+        }
+        let params = rest[..params_end]
+            .split(';')
+            .filter_map(|param| {
+                let separator = param.find('=')?;
+                let (name, value) = (&param[..separator], &param[separator + 1..]);
+                Some((name.trim(), value.trim().trim_matches('"').to_owned()))
+            })
+            .collect();
+        links.push((target, params));
+        rest = &rest[params_end..];
+    }
+    links
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distillable_responses() {
+        let response = |status, content_type: &str| ResponseInfo {
+            status,
+            content_type: Some(content_type.to_owned()),
+            ..ResponseInfo::default()
+        };
+        assert!(ResponseInfo::default().check_distillable().is_ok());
+        assert!(response(Some(200), "text/html; charset=utf-8").check_distillable().is_ok());
+        assert!(response(None, "Application/XHTML+XML").check_distillable().is_ok());
+        assert_eq!(
+            response(Some(200), "application/pdf").check_distillable(),
+            Err(SpeedReaderError::UnsupportedResponse("content type application/pdf".to_owned()))
+        );
+        assert_eq!(
+            response(Some(404), "text/html").check_distillable(),
+            Err(SpeedReaderError::UnsupportedResponse("status 404".to_owned()))
+        );
+    }
+
+    #[test]
+    fn header_values() {
+        let url = Url::parse("https://example.com/news/article?utm_source=x").unwrap();
+        let response = ResponseInfo {
+            status: Some(200),
+            content_type: Some("text/html; charset=windows-1251".to_owned()),
+            content_language: Some(" de-DE ".to_owned()),
+            link: Some(
+                "<https://cdn.example.com/style.css>; rel=preload; title=\"a, b\", \
+                 </news/article>; rel=\"alternate canonical\""
+                    .to_owned(),
+            ),
+        };
+        assert_eq!(response.encoding(), Some(encoding_rs::WINDOWS_1251));
+        assert_eq!(response.language(), Some("de-DE".to_owned()));
+        assert_eq!(
+            response.canonical_url(&url).map(Url::into_string),
+            Some("https://example.com/news/article".to_owned())
+        );
+
+        let response = ResponseInfo {
+            content_language: Some("de, en".to_owned()),
+            link: Some("<https://example.com/next>; rel=next".to_owned()),
+            ..ResponseInfo::default()
+        };
+        assert_eq!(response.language(), None);
+        assert_eq!(response.canonical_url(&url), None);
+    }
+}
@@ -1,18 +1,31 @@
+use lazy_static::lazy_static;
 use lol_html::html_content::*;
 use lol_html::ElementContentHandlers;
 use lol_html::Selector;
+use regex::Regex;
+use readability::images::{self, ImageTarget};
+use readability::urls;
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::error::Error;
+use std::rc::Rc;
+use url::Url;
 
+use crate::metadata::decode_entities;
 use crate::speedreader::RewriteRules;
 use crate::speedreader::SpeedReaderError;
+use crate::speedreader::{RewriteOp, SelectorOps};
 
 pub type HandlerResult = Result<(), Box<dyn Error>>;
-pub type ElementHandler = Box<dyn Fn(&mut Element) -> HandlerResult>;
-pub type TextHandler = Box<dyn Fn(&mut TextChunk) -> HandlerResult>;
+pub type ElementHandler = Box<dyn Fn(&mut Element) -> HandlerResult + Send + Sync>;
+pub type TextHandler = Box<dyn Fn(&mut TextChunk) -> HandlerResult + Send + Sync>;
+/// Rewrites the decoded text of a whole text node, `None` keeps it as is.
+pub type TextNodeHandler = Box<dyn Fn(&str) -> Option<String> + Send + Sync>;
 
 pub struct ContentFunction {
     pub element: Option<ElementHandler>,
     pub text: Option<TextHandler>,
+    pub text_node: Option<TextNodeHandler>,
 }
 
 impl From<ElementHandler> for ContentFunction {
@@ -21,6 +34,7 @@ impl From<ElementHandler> for ContentFunction {
         ContentFunction {
             element: Some(handler),
             text: None,
+            text_node: None,
         }
     }
 }
@@ -31,22 +45,20 @@ impl From<TextHandler> for ContentFunction {
         ContentFunction {
             element: None,
             text: Some(handler),
+            text_node: None,
         }
     }
 }
 
-pub fn rewrite_rules_to_content_handlers(
-    conf: &RewriteRules,
-    origin: &str,
-) -> Vec<(Selector, ContentFunction)> {
+/// Handlers that only depend on the site's `RewriteRules`, and can therefore
+/// be compiled once and shared by every rewriter for that site.
+pub fn site_content_handlers(conf: &RewriteRules) -> Vec<(Selector, ContentFunction)> {
     let mut element_content_handlers = vec![];
-    let mut errors = vec![];
 
     for attr_rewrite in &conf.preprocess {
         let rewrite = attr_rewrite.clone();
         add_element_function(
             &mut element_content_handlers,
-            &mut errors,
             &attr_rewrite.selector,
             Box::new(move |el| {
                 if let Some((attribute_from, attribute_to)) = rewrite.attribute.as_ref() {
@@ -60,67 +72,48 @@ pub fn rewrite_rules_to_content_handlers(
         );
     }
 
-    // Keep the basic HTML structure
-    add_element_function(
-        &mut element_content_handlers,
-        &mut errors,
-        "html, html > head, html > body",
-        Box::new(mark_retained_element),
-    );
-
-    let maybe_script = conf.content_script.clone();
-    add_element_function(
-        &mut element_content_handlers,
-        &mut errors,
-        "body",
-        Box::new(move |el| {
-            el.before("<article id=\"article\">", ContentType::Html);
-            el.after("</article>", ContentType::Html);
-            if let Some(script) = &maybe_script {
-                el.append(&script, ContentType::Html);
-            }
-            el.set_attribute("dir", "auto")?;
-            Ok(())
-        }),
-    );
-
     collect_main_content(
         &mut element_content_handlers,
-        &mut errors,
         &conf.get_main_content_selectors(),
         &conf.get_content_cleanup_selectors(),
     );
     if conf.delazify {
-        delazify(&mut element_content_handlers, &mut errors);
+        delazify(&mut element_content_handlers);
     }
     if conf.fix_embeds {
-        fix_social_embeds(&mut element_content_handlers, &mut errors);
+        fix_social_embeds(&mut element_content_handlers);
     }
-    correct_relative_links(
-        &mut element_content_handlers,
-        &mut errors,
-        origin.to_owned(),
-    );
+    // Last, so site fixes win over the generic ones
+    rewrite_operations(&mut element_content_handlers, &conf.operations);
 
-    if !errors.is_empty() {
-        eprintln!(
-            "Rewriter rules include invalid content selectors: {}",
-            errors
-                .iter()
-                .map(|e| e.to_string())
-                .collect::<Vec<_>>()
-                .join(", ")
-        )
-    }
+    // Handlers with invalid selectors or operations are left out.
+    // `Whitelist::validate` reports them.
 
     element_content_handlers
 }
 
-pub fn content_handlers<'h>(
-    handlers: &'h [(Selector, ContentFunction)],
-) -> Vec<(&Selector, ElementContentHandlers<'h>)> {
+/// Handlers that depend on the URL of the document being rewritten. They
+/// track the document's `<base>` and `<picture>` sources, so a new set is
+/// needed for every rewriter. Applied after the site handlers.
+pub fn url_content_handlers<'h>(
+    url: &Url,
+    image_target: ImageTarget,
+) -> Vec<(&'static Selector, ElementContentHandlers<'h>)> {
+    let document = Rc::new(DocumentBase {
+        url: url.clone(),
+        base: RefCell::new(None),
+    });
+    let mut element_content_handlers = correct_relative_links(&document);
+    element_content_handlers.extend(select_images(&document, image_target));
+    element_content_handlers
+}
+
+pub fn content_handlers<'h, I>(handlers: I) -> Vec<(&'h Selector, ElementContentHandlers<'h>)>
+where
+    I: IntoIterator<Item = &'h (Selector, ContentFunction)>,
+{
     handlers
-        .iter()
+        .into_iter()
         .map(|(selector, function)| (selector, get_content_handlers(function)))
         .collect::<Vec<_>>()
 }
@@ -131,6 +124,26 @@ fn get_content_handlers(function: &ContentFunction) -> ElementContentHandlers<'_
         ElementContentHandlers::default().element(f_element)
     } else if let Some(t_element) = function.text.as_ref() {
         ElementContentHandlers::default().text(t_element)
+    } else if let Some(t_node) = function.text_node.as_ref() {
+        // lol_html splits text wherever the input does, so the chunks are
+        // held back until the text node is complete. The buffer belongs to
+        // this rewriter, the handler is shared.
+        let mut buffer = String::new();
+        ElementContentHandlers::default().text(move |t: &mut TextChunk| {
+            buffer.push_str(t.as_str());
+            if !t.last_in_text_node() {
+                t.remove();
+            } else if !t.removed() {
+                let raw = std::mem::replace(&mut buffer, String::new());
+                match t_node(&decode_entities(&raw)) {
+                    Some(text) => t.replace(&escape_html(&text), ContentType::Html),
+                    None => t.replace(&raw, ContentType::Html),
+                }
+            } else {
+                buffer.clear();
+            }
+            Ok(())
+        })
     } else {
         ElementContentHandlers::default()
     }
@@ -139,46 +152,55 @@ fn get_content_handlers(function: &ContentFunction) -> ElementContentHandlers<'_
 #[inline]
 fn add_element_function(
     handlers: &mut Vec<(Selector, ContentFunction)>,
-    errors: &mut Vec<SpeedReaderError>,
     selector: &str,
     handler: ElementHandler,
 ) {
-    match selector.parse::<Selector>() {
-        Ok(selector) => handlers.push((selector, ContentFunction::from(handler))),
-        Err(error) => errors.push(SpeedReaderError::SelectorError(selector.to_owned(), error)),
+    if let Ok(selector) = selector.parse::<Selector>() {
+        handlers.push((selector, ContentFunction::from(handler)));
     }
 }
 
 #[inline]
 fn add_text_function(
     handlers: &mut Vec<(Selector, ContentFunction)>,
-    errors: &mut Vec<SpeedReaderError>,
     selector: &str,
     handler: TextHandler,
 ) {
-    match selector.parse::<Selector>() {
-        Ok(selector) => handlers.push((selector, ContentFunction::from(handler))),
-        Err(error) => errors.push(SpeedReaderError::SelectorError(selector.to_owned(), error)),
+    if let Ok(selector) = selector.parse::<Selector>() {
+        handlers.push((selector, ContentFunction::from(handler)));
+    }
+}
+
+#[inline]
+fn add_text_node_function(
+    handlers: &mut Vec<(Selector, ContentFunction)>,
+    selector: &str,
+    handler: TextNodeHandler,
+) {
+    if let Ok(selector) = selector.parse::<Selector>() {
+        let function = ContentFunction {
+            element: None,
+            text: None,
+            text_node: Some(handler),
+        };
+        handlers.push((selector, function));
     }
 }
 
 #[inline]
 fn collect_main_content(
     handlers: &mut Vec<(Selector, ContentFunction)>,
-    errors: &mut Vec<SpeedReaderError>,
     content_selectors: &[&str],
     cleanup_selectors: &[&str],
 ) {
     content_selectors.iter().for_each(|selector| {
         add_element_function(
             handlers,
-            errors,
             &format!("{}, {} *", selector, selector),
             Box::new(mark_retained_element),
         );
         add_text_function(
             handlers,
-            errors,
             &format!("{}, {} *", selector, selector),
             Box::new(mark_retained_text),
         );
@@ -187,7 +209,6 @@ fn collect_main_content(
     cleanup_selectors.iter().for_each(|selector| {
         add_element_function(
             handlers,
-            errors,
             selector,
             Box::new(|el| {
                 el.remove();
@@ -197,11 +218,10 @@ fn collect_main_content(
     });
 
     // Drop everything else
-    add_text_function(handlers, errors, "*", Box::new(remove_unmarked_text));
-    add_element_function(handlers, errors, "*", Box::new(unwrap_unmarked_element));
+    add_text_function(handlers, "*", Box::new(remove_unmarked_text));
+    add_element_function(handlers, "*", Box::new(unwrap_unmarked_element));
     add_element_function(
         handlers,
-        errors,
         "[style]",
         Box::new(|el| {
             el.remove_attribute("style");
@@ -210,45 +230,146 @@ fn collect_main_content(
     );
 }
 
+const URL_ATTRIBUTES: [(&str, &str); 4] = [
+    ("a[href], area[href]", "href"),
+    ("[src]", "src"),
+    ("video[poster]", "poster"),
+    ("blockquote[cite], q[cite], del[cite], ins[cite]", "cite"),
+];
+
+lazy_static! {
+    static ref BASE_SELECTOR: Selector = "base[href]".parse().unwrap();
+    static ref URL_ATTRIBUTE_SELECTORS: Vec<(Selector, &'static str)> = URL_ATTRIBUTES
+        .iter()
+        .map(|(selector, attribute)| (selector.parse().unwrap(), *attribute))
+        .collect();
+    static ref SRCSET_SELECTOR: Selector = "img[srcset], source[srcset]".parse().unwrap();
+    static ref PICTURE_SELECTOR: Selector = "picture".parse().unwrap();
+    static ref PICTURE_SOURCE_SELECTOR: Selector = "picture > source".parse().unwrap();
+    static ref IMG_SELECTOR: Selector = "img".parse().unwrap();
+}
+
+/// Base URL of the document being rewritten, updated by its `<base href>`.
+struct DocumentBase {
+    url: Url,
+    base: RefCell<Option<Url>>,
+}
+
+impl DocumentBase {
+    fn get(&self) -> Url {
+        self.base
+            .borrow()
+            .clone()
+            .unwrap_or_else(|| self.url.clone())
+    }
+}
+
+/// Resolves relative URLs against the document's base URL.
 #[inline]
-fn correct_relative_links(
-    handlers: &mut Vec<(Selector, ContentFunction)>,
-    errors: &mut Vec<SpeedReaderError>,
-    origin: String,
-) {
-    let href_origin = origin.clone();
-    add_element_function(
-        handlers,
-        errors,
-        "a[href]",
-        Box::new(move |el| {
-            let href = el.get_attribute("href").expect("href was required");
-
-            if !href.starts_with("http://")
-                && !href.starts_with("https://")
-                && !href.starts_with("//")
-            {
-                el.set_attribute("href", &format!("{}{}", href_origin, href))?;
+fn correct_relative_links<'h>(
+    document: &Rc<DocumentBase>,
+) -> Vec<(&'static Selector, ElementContentHandlers<'h>)> {
+    // Only the first `<base>` counts
+    let state = Rc::clone(document);
+    let mut handlers = vec![(
+        &*BASE_SELECTOR,
+        ElementContentHandlers::default().element(move |el: &mut Element| {
+            let mut base = state.base.borrow_mut();
+            if base.is_none() {
+                let href = el.get_attribute("href").unwrap_or_default();
+                *base = state.url.join(href.trim()).ok();
             }
-
             Ok(())
         }),
-    );
-    add_element_function(
-        handlers,
-        errors,
-        "img[src]",
-        Box::new(move |el| {
-            let src = el.get_attribute("src").expect("src was required");
-
-            if !src.starts_with("http://") && !src.starts_with("https://") && !src.starts_with("//")
-            {
-                el.set_attribute("src", &format!("{}{}", origin, src))?;
-            }
+    )];
 
+    for (selector, attribute) in URL_ATTRIBUTE_SELECTORS.iter() {
+        let state = Rc::clone(document);
+        handlers.push((
+            selector,
+            ElementContentHandlers::default().element(move |el: &mut Element| {
+                let value = el.get_attribute(attribute).unwrap_or_default();
+                if let Some(resolved) = urls::resolve(&state.get(), &value) {
+                    el.set_attribute(attribute, &resolved)?;
+                }
+                Ok(())
+            }),
+        ));
+    }
+    let state = Rc::clone(document);
+    handlers.push((
+        &*SRCSET_SELECTOR,
+        ElementContentHandlers::default().element(move |el: &mut Element| {
+            let srcset = el.get_attribute("srcset").unwrap_or_default();
+            el.set_attribute("srcset", &urls::resolve_srcset(&state.get(), &srcset))?;
             Ok(())
         }),
-    );
+    ));
+    handlers
+}
+
+/// Replaces `srcset` candidates, including those of the `<source>` elements
+/// of a `<picture>`, with the single `src` best matching the target. Runs
+/// after delazification, so candidates it copied are resolved here.
+#[inline]
+fn select_images<'h>(
+    document: &Rc<DocumentBase>,
+    target: ImageTarget,
+) -> Vec<(&'static Selector, ElementContentHandlers<'h>)> {
+    // Sources come before the `<img>` of their `<picture>`
+    let sources = Rc::new(RefCell::new(Vec::<String>::new()));
+
+    let picture = Rc::clone(&sources);
+    let picture_source = Rc::clone(&sources);
+    let state = Rc::clone(document);
+    vec![
+        (
+            &*PICTURE_SELECTOR,
+            ElementContentHandlers::default().element(move |_: &mut Element| {
+                picture.borrow_mut().clear();
+                Ok(())
+            }),
+        ),
+        (
+            &*PICTURE_SOURCE_SELECTOR,
+            ElementContentHandlers::default().element(move |el: &mut Element| {
+                if let Some(srcset) = el.get_attribute("srcset") {
+                    picture_source.borrow_mut().push(srcset);
+                }
+                el.remove();
+                Ok(())
+            }),
+        ),
+        (
+            &*IMG_SELECTOR,
+            ElementContentHandlers::default().element(move |el: &mut Element| {
+                let base = state.get();
+                let pending = sources.replace(Vec::new());
+                let srcset = el.get_attribute("srcset");
+                let srcsets = pending.iter().chain(srcset.iter()).map(String::as_str);
+                if let Some(src) = target.select(srcsets) {
+                    let src = urls::resolve(&base, src).unwrap_or_else(|| src.to_owned());
+                    el.set_attribute("src", &src)?;
+                } else if let Some(src) = el.get_attribute("src") {
+                    if let Some(resolved) = urls::resolve(&base, &src) {
+                        el.set_attribute("src", &resolved)?;
+                    }
+                }
+                el.remove_attribute("srcset");
+                el.remove_attribute("sizes");
+
+                let width = el.get_attribute("width").unwrap_or_default();
+                let height = el.get_attribute("height").unwrap_or_default();
+                el.remove_attribute("style");
+                if let Some(style) = images::aspect_ratio_style(&width, &height) {
+                    el.set_attribute("style", &style)?;
+                }
+                el.remove_attribute("width");
+                el.remove_attribute("height");
+                Ok(())
+            }),
+        ),
+    ]
 }
 
 const LAZY_MAPPINGS: [(&str, &str, &str); 7] = [
@@ -262,11 +383,10 @@ const LAZY_MAPPINGS: [(&str, &str, &str); 7] = [
 ];
 
 #[inline]
-fn delazify(handlers: &mut Vec<(Selector, ContentFunction)>, errors: &mut Vec<SpeedReaderError>) {
+fn delazify(handlers: &mut Vec<(Selector, ContentFunction)>) {
     for (selector, attribute, target) in LAZY_MAPPINGS.iter() {
         add_element_function(
             handlers,
-            errors,
             selector,
             Box::new(move |el| {
                 if let Some(src) = el.get_attribute(attribute) {
@@ -279,25 +399,18 @@ fn delazify(handlers: &mut Vec<(Selector, ContentFunction)>, errors: &mut Vec<Sp
 
     add_element_function(
         handlers,
-        errors,
         "img",
         Box::new(|el| {
-            el.remove_attribute("height");
-            el.remove_attribute("width");
-            el.set_attribute("load", "lazy").ok();
+            el.set_attribute("loading", "lazy").ok();
             Ok(())
         }),
     );
 }
 
 #[inline]
-fn fix_social_embeds(
-    handlers: &mut Vec<(Selector, ContentFunction)>,
-    errors: &mut Vec<SpeedReaderError>,
-) {
+fn fix_social_embeds(handlers: &mut Vec<(Selector, ContentFunction)>) {
     add_element_function(
         handlers,
-        errors,
         ".twitterContainer",
         Box::new(|el: &mut Element| {
             el.prepend(
@@ -311,6 +424,128 @@ fn fix_social_embeds(
     );
 }
 
+fn rewrite_operations(handlers: &mut Vec<(Selector, ContentFunction)>, operations: &[SelectorOps]) {
+    for selector_ops in operations {
+        for op in selector_ops.ops.iter() {
+            if check_rewrite_op(op).is_ok() {
+                add_rewrite_op(handlers, &selector_ops.selector, op.clone());
+            }
+        }
+    }
+}
+
+/// Checks the parts of an operation that deserialization doesn't.
+pub fn check_rewrite_op(op: &RewriteOp) -> Result<(), SpeedReaderError> {
+    let invalid = |what: &str, value: &str| {
+        Err(SpeedReaderError::ConfigurationError(format!(
+            "Invalid {} `{}`",
+            what, value
+        )))
+    };
+    match op {
+        RewriteOp::RemoveAttribute { name } | RewriteOp::SetAttribute { name, .. }
+            if name.is_empty()
+                || name
+                    .chars()
+                    .any(|c| c.is_whitespace() || "\"'>/=".contains(c)) =>
+        {
+            invalid("attribute name", name)
+        }
+        RewriteOp::Wrap { tag }
+            if !tag.starts_with(|c: char| c.is_ascii_alphabetic())
+                || !tag.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') =>
+        {
+            invalid("tag name", tag)
+        }
+        RewriteOp::ReplaceText { pattern, .. } if Regex::new(pattern).is_err() => {
+            invalid("pattern", pattern)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Operations adding content skip elements already dropped from the output.
+fn add_rewrite_op(handlers: &mut Vec<(Selector, ContentFunction)>, selector: &str, op: RewriteOp) {
+    let with_descendants = format!("{}, {} *", selector, selector);
+    match op {
+        RewriteOp::RemoveAttribute { name } => add_element_function(
+            handlers,
+            selector,
+            Box::new(move |el| {
+                el.remove_attribute(&name);
+                Ok(())
+            }),
+        ),
+        RewriteOp::SetAttribute { name, value } => add_element_function(
+            handlers,
+            selector,
+            Box::new(move |el| {
+                el.set_attribute(&name, &value)?;
+                Ok(())
+            }),
+        ),
+        RewriteOp::ReplaceWithText => add_element_function(
+            handlers,
+            &with_descendants,
+            Box::new(|el| {
+                el.remove_and_keep_content();
+                Ok(())
+            }),
+        ),
+        RewriteOp::Wrap { tag } => add_element_function(
+            handlers,
+            selector,
+            Box::new(move |el| {
+                if !el.removed() {
+                    el.before(&format!("<{}>", tag), ContentType::Html);
+                    el.after(&format!("</{}>", tag), ContentType::Html);
+                }
+                Ok(())
+            }),
+        ),
+        RewriteOp::InsertBefore { html } => add_element_function(
+            handlers,
+            selector,
+            Box::new(move |el| {
+                if !el.removed() {
+                    el.before(&html, ContentType::Html);
+                }
+                Ok(())
+            }),
+        ),
+        RewriteOp::InsertAfter { html } => add_element_function(
+            handlers,
+            selector,
+            Box::new(move |el| {
+                if !el.removed() {
+                    el.after(&html, ContentType::Html);
+                }
+                Ok(())
+            }),
+        ),
+        RewriteOp::ReplaceText {
+            pattern,
+            replacement,
+        } => {
+            let regex = match Regex::new(&pattern) {
+                Ok(regex) => regex,
+                Err(_) => unreachable!("patterns are checked by check_rewrite_op"),
+            };
+            let replace = move |text: &str| match regex.replace_all(text, replacement.as_str()) {
+                Cow::Owned(text) => Some(text),
+                Cow::Borrowed(_) => None,
+            };
+            add_text_node_function(handlers, selector, Box::new(replace))
+        }
+    }
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
 #[inline]
 fn mark_retained_element(el: &mut Element) -> HandlerResult {
     el.set_user_data(true);
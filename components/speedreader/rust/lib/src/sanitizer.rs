@@ -0,0 +1,377 @@
+use lazy_static::lazy_static;
+use lol_html::html_content::Element;
+use lol_html::{doc_comments, ElementContentHandlers, HtmlRewriter, OutputSink, Selector, Settings};
+use readability::urls;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::sync::Arc;
+use url::Url;
+
+use crate::speedreader::SpeedReaderError;
+
+lazy_static! {
+    static ref ANY_ELEMENT: Selector = "*".parse().unwrap();
+}
+
+/// Elements kept by the default policy.
+const DEFAULT_TAGS: [&str; 71] = [
+    "html", "head", "title", "body", "article", "section", "header", "footer", "main", "aside",
+    "nav", "div", "span", "p", "br", "hr", "h1", "h2", "h3", "h4", "h5", "h6", "a", "abbr", "b",
+    "bdi", "bdo", "cite", "code", "data", "dfn", "em", "i", "kbd", "mark", "q", "s", "samp",
+    "small", "strong", "sub", "sup", "time", "u", "var", "wbr", "blockquote", "pre", "del", "ins",
+    "ul", "ol", "li", "dl", "dt", "dd", "figure", "figcaption", "picture", "img", "video", "audio",
+    "source", "table", "caption", "thead", "tbody", "tfoot", "tr", "th", "td",
+];
+
+/// Elements the default policy drops together with their content. Anything
+/// else not allowed is unwrapped, keeping its content.
+const DEFAULT_DROPPED_TAGS: [&str; 27] = [
+    "script", "noscript", "style", "xmp", "noembed", "noframes", "plaintext", "link", "meta",
+    "base", "iframe", "frame", "frameset", "object", "embed", "applet", "param", "form", "input",
+    "button", "select", "textarea", "template", "svg", "math", "canvas", "dialog",
+];
+
+/// Elements whose content is parsed as raw text or RCDATA, so markup in it
+/// passes through unchanged. Unwrapping one would turn that text into live
+/// markup, so they are dropped unless allowed, whatever the policy says.
+const RAW_TEXT_TAGS: [&str; 10] = [
+    "script", "style", "xmp", "iframe", "noembed", "noframes", "noscript", "plaintext",
+    "textarea", "title",
+];
+
+/// Attributes the default policy allows on every element.
+const DEFAULT_ATTRIBUTES: [&str; 5] = ["id", "class", "dir", "lang", "title"];
+
+/// Attributes the default policy allows on specific elements.
+const DEFAULT_TAG_ATTRIBUTES: [(&str, &[&str]); 14] = [
+    ("a", &["href", "name"]),
+    ("img", &["src", "alt", "loading", "style"]),
+    ("video", &["src", "poster", "controls", "width", "height"]),
+    ("audio", &["src", "controls"]),
+    ("source", &["src", "srcset", "type", "media", "sizes"]),
+    ("blockquote", &["cite"]),
+    ("q", &["cite"]),
+    ("del", &["cite", "datetime"]),
+    ("ins", &["cite", "datetime"]),
+    ("time", &["datetime"]),
+    ("ol", &["start", "reversed", "type"]),
+    ("li", &["value"]),
+    ("td", &["colspan", "rowspan", "headers"]),
+    ("th", &["colspan", "rowspan", "headers", "scope"]),
+];
+
+/// URL schemes the default policy allows. Relative URLs are always allowed.
+const DEFAULT_URL_SCHEMES: [&str; 4] = ["http", "https", "mailto", "tel"];
+
+/// Attributes whose values browsers treat as URLs.
+const URL_ATTRIBUTES: [&str; 9] = [
+    "href",
+    "src",
+    "poster",
+    "cite",
+    "action",
+    "formaction",
+    "background",
+    "data",
+    "xlink:href",
+];
+
+/// Allowlist of what distilled output may contain. The default only keeps
+/// text formatting, links, media and tables, without any way to run code.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SanitizerPolicy {
+    /// Elements kept in the output.
+    pub tags: HashSet<String>,
+    /// Elements removed together with their content. Other elements that
+    /// aren't allowed are replaced with their content.
+    pub dropped_tags: HashSet<String>,
+    /// Attributes allowed on every kept element.
+    pub attributes: HashSet<String>,
+    /// Attributes allowed on the given elements only.
+    pub tag_attributes: HashMap<String, HashSet<String>>,
+    /// Schemes allowed in URL attributes.
+    pub url_schemes: HashSet<String>,
+}
+
+fn to_set(values: &[&str]) -> HashSet<String> {
+    values.iter().map(|v| (*v).to_owned()).collect()
+}
+
+impl Default for SanitizerPolicy {
+    fn default() -> Self {
+        SanitizerPolicy {
+            tags: to_set(&DEFAULT_TAGS),
+            dropped_tags: to_set(&DEFAULT_DROPPED_TAGS),
+            attributes: to_set(&DEFAULT_ATTRIBUTES),
+            tag_attributes: DEFAULT_TAG_ATTRIBUTES
+                .iter()
+                .map(|(tag, attributes)| ((*tag).to_owned(), to_set(attributes)))
+                .collect(),
+            url_schemes: to_set(&DEFAULT_URL_SCHEMES),
+        }
+    }
+}
+
+impl SanitizerPolicy {
+    pub fn allows_attribute(&self, tag: &str, name: &str) -> bool {
+        self.attributes.contains(name)
+            || self
+                .tag_attributes
+                .get(tag)
+                .map_or(false, |attributes| attributes.contains(name))
+    }
+
+    /// Whether a raw, not entity decoded, attribute value is a relative URL
+    /// or one with an allowed scheme. Character references ahead of the path
+    /// could hide the scheme, so values having them are rejected.
+    pub fn allows_url(&self, value: &str) -> bool {
+        let head = value.split(&['/', '?', '#'][..]).next().unwrap_or_default();
+        if head.contains('&') {
+            return false;
+        }
+        match Url::parse(value) {
+            Ok(url) => self.url_schemes.contains(url.scheme()),
+            Err(url::ParseError::RelativeUrlWithoutBase) => true,
+            Err(_) => false,
+        }
+    }
+
+    fn allows_attribute_value(&self, name: &str, value: &str) -> bool {
+        if name == "srcset" {
+            urls::parse_srcset(value)
+                .iter()
+                .all(|(url, _)| self.allows_url(url))
+        } else if URL_ATTRIBUTES.contains(&name) {
+            self.allows_url(value)
+        } else {
+            true
+        }
+    }
+
+    fn sanitize_element(&self, el: &mut Element) {
+        let tag = el.tag_name();
+        let allowed = self.tags.contains(&tag);
+        if self.dropped_tags.contains(&tag) || (!allowed && RAW_TEXT_TAGS.contains(&&*tag)) {
+            el.remove();
+            return;
+        }
+        if !allowed {
+            el.remove_and_keep_content();
+            return;
+        }
+        let disallowed: Vec<String> = el
+            .attributes()
+            .iter()
+            .filter(|attr| {
+                let name = attr.name();
+                !self.allows_attribute(&tag, &name)
+                    || !self.allows_attribute_value(&name, &attr.value())
+            })
+            .map(|attr| attr.name())
+            .collect();
+        for name in disallowed {
+            el.remove_attribute(&name);
+        }
+    }
+}
+
+struct SanitizerState<'h, O: OutputSink> {
+    rewriter: HtmlRewriter<'h, O>,
+    error: Option<SpeedReaderError>,
+}
+
+/// Streaming sanitizer stage applying a `SanitizerPolicy` to the output of a
+/// rewriter, which writes into it through the sink from `Sanitizer::sink`.
+pub struct Sanitizer<'h, O: OutputSink> {
+    state: Rc<RefCell<SanitizerState<'h, O>>>,
+}
+
+/// Output sink feeding a `Sanitizer`. Errors are held until the sanitizer
+/// is checked or ended.
+pub struct SanitizerSink<'h, O: OutputSink> {
+    state: Rc<RefCell<SanitizerState<'h, O>>>,
+}
+
+impl<'h, O: OutputSink> OutputSink for SanitizerSink<'h, O> {
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        let mut state = self.state.borrow_mut();
+        if state.error.is_none() {
+            if let Err(error) = state.rewriter.write(chunk) {
+                state.error = Some(error.into());
+            }
+        }
+    }
+}
+
+impl<'h, O: OutputSink> Sanitizer<'h, O> {
+    pub fn try_new(policy: Arc<SanitizerPolicy>, output_sink: O) -> Result<Self, SpeedReaderError> {
+        let rewriter = HtmlRewriter::try_new(
+            Settings {
+                element_content_handlers: vec![(
+                    &*ANY_ELEMENT,
+                    ElementContentHandlers::default().element(move |el: &mut Element| {
+                        policy.sanitize_element(el);
+                        Ok(())
+                    }),
+                )],
+                document_content_handlers: vec![doc_comments!(|c| {
+                    c.remove();
+                    Ok(())
+                })],
+                ..Settings::default()
+            },
+            output_sink,
+        )?;
+        Ok(Sanitizer {
+            state: Rc::new(RefCell::new(SanitizerState {
+                rewriter,
+                error: None,
+            })),
+        })
+    }
+
+    pub fn sink(&self) -> SanitizerSink<'h, O> {
+        SanitizerSink {
+            state: Rc::clone(&self.state),
+        }
+    }
+
+    /// Reports the first error sanitizing the output written so far.
+    pub fn check(&self) -> Result<(), SpeedReaderError> {
+        match self.state.borrow_mut().error.take() {
+            Some(error) => Err(error),
+            None => Ok(()),
+        }
+    }
+
+    /// Flushes the sanitized output, once the rewriter writing into the
+    /// sanitizer has ended.
+    pub fn end(&mut self) -> Result<(), SpeedReaderError> {
+        self.check()?;
+        self.state.borrow_mut().rewriter.end()?;
+        Ok(())
+    }
+}
+
+/// Sanitizes a complete document.
+pub fn sanitize(policy: Arc<SanitizerPolicy>, html: &[u8]) -> Result<Vec<u8>, SpeedReaderError> {
+    let mut output = vec![];
+    let mut sanitizer = Sanitizer::try_new(policy, |c: &[u8]| output.extend_from_slice(c))?;
+    sanitizer.sink().handle_chunk(html);
+    sanitizer.end()?;
+    drop(sanitizer);
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitize_str(html: &str) -> String {
+        let sanitized = sanitize(Arc::new(SanitizerPolicy::default()), html.as_bytes()).unwrap();
+        String::from_utf8(sanitized).unwrap()
+    }
+
+    #[test]
+    fn strips_active_content() {
+        assert_eq!(
+            sanitize_str(
+                "<div onclick=\"alert(1)\" class=\"c\"><p style=\"color: red\">Text</p>\
+                <script>alert(1)</script><iframe src=\"https://e.com\"></iframe>\
+                <form action=\"/x\"><input name=\"q\"><p>Form text</p></form>\
+                <object data=\"x.swf\"><embed src=\"x.swf\"></object><!-- comment -->\
+                <font color=\"red\">Unwrapped</font></div>"
+            ),
+            "<div class=\"c\"><p>Text</p>Unwrapped</div>"
+        );
+    }
+
+    fn assert_drops_raw_text(tag: &str) {
+        assert_eq!(
+            sanitize_str(&format!(
+                "<p>a</p><{0}><script>alert(1)</script><img src=x onerror=alert(1)></{0}><p>b</p>",
+                tag
+            )),
+            "<p>a</p><p>b</p>"
+        );
+    }
+
+    #[test]
+    fn drops_xmp() {
+        assert_drops_raw_text("xmp");
+    }
+
+    #[test]
+    fn drops_noembed() {
+        assert_drops_raw_text("noembed");
+    }
+
+    #[test]
+    fn drops_noframes() {
+        assert_drops_raw_text("noframes");
+    }
+
+    #[test]
+    fn drops_plaintext() {
+        // Has no end tag, everything after it is its text
+        assert_eq!(
+            sanitize_str("<p>a</p><plaintext><script>alert(1)</script></plaintext><p>b</p>"),
+            "<p>a</p>"
+        );
+    }
+
+    #[test]
+    fn never_unwraps_raw_text() {
+        let mut policy = SanitizerPolicy::default();
+        policy.dropped_tags.clear();
+        policy.tags.remove("title");
+        let sanitized = sanitize(
+            Arc::new(policy),
+            b"<title><script>alert(1)</script></title><xmp><script>alert(1)</script></xmp>\
+            <font>Unwrapped</font>",
+        )
+        .unwrap();
+        assert_eq!(String::from_utf8(sanitized).unwrap(), "Unwrapped");
+    }
+
+    #[test]
+    fn checks_url_schemes() {
+        assert_eq!(
+            sanitize_str(
+                "<a href=\"javascript:alert(1)\">a</a>\
+                <a href=\" JavaScript:alert(1)\">b</a>\
+                <a href=\"java&#x09;script:alert(1)\">c</a>\
+                <a href=\"javascript&colon;alert(1)\">d</a>\
+                <a href=\"https://e.com/?a=1&amp;b=2\">e</a>\
+                <a href=\"page.html\">f</a><a href=\"mailto:me@e.com\">g</a>\
+                <img src=\"data:image/png;base64,AAAA\" alt=\"h\">\
+                <video><source srcset=\"a.mp4, vbscript:x\"></video>"
+            ),
+            "<a>a</a><a>b</a><a>c</a><a>d</a>\
+            <a href=\"https://e.com/?a=1&amp;b=2\">e</a>\
+            <a href=\"page.html\">f</a><a href=\"mailto:me@e.com\">g</a>\
+            <img alt=\"h\"><video><source></video>"
+        );
+    }
+
+    #[test]
+    fn custom_policy() {
+        let mut policy = SanitizerPolicy::default();
+        policy.dropped_tags.remove("iframe");
+        policy.tags.insert("iframe".to_owned());
+        policy
+            .tag_attributes
+            .insert("iframe".to_owned(), to_set(&["src"]));
+        policy.url_schemes.insert("data".to_owned());
+        let sanitized = sanitize(
+            Arc::new(policy),
+            b"<iframe src=\"https://e.com\" srcdoc=\"x\"></iframe><img src=\"data:,\">",
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(sanitized).unwrap(),
+            "<iframe src=\"https://e.com\"></iframe><img src=\"data:,\">"
+        );
+    }
+}
@@ -1,15 +1,24 @@
 use lol_html::Selector;
 use serde::{Deserialize, Serialize};
-use core::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use thiserror::Error;
 use url::Url;
+use readability::diagnostics::Diagnostics;
+use readability::images::ImageTarget;
+use readability::scorer::ReadabilityOptions;
 
 pub use lol_html::OutputSink;
 
+use super::metadata::ArticleMetadata;
+use super::response::ResponseInfo;
 use super::rewriter_config_builder::*;
-use super::speedreader_heuristics::SpeedReaderHeuristics;
+use super::sanitizer::SanitizerPolicy;
+use super::template::{OutputTemplate, Stylesheet};
+use super::speedreader_heuristics::{SpeedReaderHeuristics, DEFAULT_READABILITY_THRESHOLD};
+use super::speedreader_hybrid::SpeedReaderHybrid;
 use super::speedreader_streaming::SpeedReaderStreaming;
-use super::whitelist::Whitelist;
+use super::whitelist::{LayeredWhitelist, Whitelist, WhitelistLayer, WhitelistPatch};
 use lol_html::errors::SelectorError;
 
 #[derive(Error, Debug, PartialEq)]
@@ -30,6 +39,76 @@ pub enum SpeedReaderError {
     BadURL(String),
     #[error("Selector Error: `{0}` - {1}")]
     SelectorError(String, SelectorError),
+    #[error("Unsupported URL scheme: `{0}`")]
+    InvalidScheme(String),
+    #[error("Not readable: {readable_votes} of {total_votes} classifier votes for readable")]
+    ClassifierRejected {
+        readable_votes: u32,
+        total_votes: u32,
+    },
+    #[error("Nothing extracted: {text_length} characters of text in extracted content")]
+    NothingExtracted { text_length: usize },
+    #[error("Unsupported response: {0}")]
+    UnsupportedResponse(String),
+    #[error("Malformed whitelist: `{0}`")]
+    MalformedWhitelist(String),
+    #[error("Unsupported whitelist format version: {0}")]
+    UnsupportedWhitelistVersion(u16),
+    #[error("Whitelist checksum mismatch")]
+    WhitelistChecksumMismatch,
+    #[error("Whitelist signature verification failed")]
+    WhitelistSignatureInvalid,
+}
+
+impl SpeedReaderError {
+    pub fn outcome(&self) -> DistillOutcome {
+        match self {
+            SpeedReaderError::ClassifierRejected { .. } => DistillOutcome::ClassifierRejected,
+            SpeedReaderError::NothingExtracted { .. } => DistillOutcome::NothingExtracted,
+            SpeedReaderError::InvalidScheme(_) => DistillOutcome::InvalidScheme,
+            SpeedReaderError::UnsupportedResponse(_) => DistillOutcome::UnsupportedResponse,
+            SpeedReaderError::InvalidUrl(_) | SpeedReaderError::BadURL(_) => {
+                DistillOutcome::InvalidUrl
+            }
+            SpeedReaderError::DocumentParseError(_) => DistillOutcome::ParseFailure,
+            SpeedReaderError::SelectorError(..) => DistillOutcome::SelectorFailure,
+            SpeedReaderError::RewritingError(_) => DistillOutcome::RewritingFailure,
+            SpeedReaderError::ConfigurationError(_)
+            | SpeedReaderError::SerializationError(_)
+            | SpeedReaderError::DeserializationError(_)
+            | SpeedReaderError::MalformedWhitelist(_)
+            | SpeedReaderError::UnsupportedWhitelistVersion(_)
+            | SpeedReaderError::WhitelistChecksumMismatch
+            | SpeedReaderError::WhitelistSignatureInvalid => DistillOutcome::ConfigurationFailure,
+        }
+    }
+}
+
+/// Outcome of distilling a document, with stable numeric values that are
+/// passed across the FFI boundary. Anything but `Distilled` is a failure
+/// case, see the matching `SpeedReaderError` variant for details.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum DistillOutcome {
+    Distilled = 0,
+    ClassifierRejected = 1,
+    NothingExtracted = 2,
+    InvalidScheme = 3,
+    InvalidUrl = 4,
+    ParseFailure = 5,
+    SelectorFailure = 6,
+    RewritingFailure = 7,
+    ConfigurationFailure = 8,
+    UnsupportedResponse = 9,
+}
+
+impl<T> From<&Result<T, SpeedReaderError>> for DistillOutcome {
+    fn from(result: &Result<T, SpeedReaderError>) -> Self {
+        match result {
+            Ok(_) => DistillOutcome::Distilled,
+            Err(err) => err.outcome(),
+        }
+    }
 }
 
 impl From<lol_html::errors::RewritingError> for SpeedReaderError {
@@ -86,10 +165,37 @@ impl From<u8> for RewriterType {
     }
 }
 
+/// Format distilled articles are written in.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum OutputFormat {
+    /// The sanitized article, placed in the `OutputTemplate` if one is set.
+    Html = 0,
+    /// CommonMark, starting with the article title and byline.
+    Markdown = 1,
+    /// Text without markup, starting with the article title and byline.
+    PlainText = 2,
+}
+
+impl Default for OutputFormat {
+    fn default() -> Self {
+        OutputFormat::Html
+    }
+}
+
 pub trait SpeedReaderProcessor {
     fn write(&mut self, input: &[u8]) -> Result<(), SpeedReaderError>;
     fn end(&mut self) -> Result<(), SpeedReaderError>;
     fn rewriter_type(&self) -> RewriterType;
+    /// Article metadata found in the document. Only available after `end`.
+    fn metadata(&self) -> Option<&ArticleMetadata>;
+    /// Share of classifier votes for the document being readable, if the
+    /// classifier ran. Only available after `end`.
+    fn confidence(&self) -> Option<f32>;
+    /// How heuristics scored the candidates and which nodes they removed,
+    /// if extraction ran with diagnostics enabled. Only available after
+    /// `end`.
+    fn diagnostics(&self) -> Option<&Diagnostics>;
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -97,6 +203,38 @@ pub struct SpeedReaderConfig {
     pub domain: String,
     pub url_rules: Vec<String>,
     pub declarative_rewrite: Option<RewriteRules>,
+    /// Adblock-style URL patterns, same syntax as `url_rules`, limiting the
+    /// pages of the domain this configuration applies to. Empty applies to
+    /// the whole domain.
+    #[serde(default)]
+    pub url_scope: Vec<String>,
+    #[serde(default)]
+    pub domain_match: DomainMatch,
+    /// Scoring model heuristics extract the site's articles with, replacing
+    /// the one set on `SpeedReader` for per-site or experimental tuning.
+    #[serde(default)]
+    pub readability: Option<ReadabilityOptions>,
+}
+
+/// How `SpeedReaderConfig::domain` is matched against page hosts. Matching
+/// never extends past the registrable domain, so entries for public
+/// suffixes only ever match exactly.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DomainMatch {
+    /// Only the host itself.
+    Exact,
+    /// The host and all its subdomains.
+    Subdomains,
+    /// Every host under the registrable domain of the entry, e.g.
+    /// `news.example.co.uk` also covers `www.example.co.uk`.
+    Registrable,
+}
+
+impl Default for DomainMatch {
+    fn default() -> Self {
+        DomainMatch::Subdomains
+    }
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -106,6 +244,31 @@ pub struct AttributeRewrite {
     pub element_name: String,
 }
 
+/// Operations applied in order to every element matching `selector` that is
+/// kept in the output.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SelectorOps {
+    pub selector: String,
+    pub ops: Vec<RewriteOp>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum RewriteOp {
+    RemoveAttribute { name: String },
+    SetAttribute { name: String, value: String },
+    /// Drops the element and all elements inside it, keeping their text.
+    ReplaceWithText,
+    /// Wraps the element in a new `tag` element.
+    Wrap { tag: String },
+    InsertBefore { html: String },
+    InsertAfter { html: String },
+    /// Regex replacement on the element's text, `$1` style references
+    /// allowed in `replacement`. Applied to each text node as a whole, with
+    /// character references decoded.
+    ReplaceText { pattern: String, replacement: String },
+}
+
 impl Default for RewriteRules {
     fn default() -> Self {
         RewriteRules {
@@ -114,7 +277,8 @@ impl Default for RewriteRules {
             delazify: true,
             fix_embeds: false,
             content_script: None,
-            preprocess: vec![]
+            preprocess: vec![],
+            operations: vec![],
         }
     }
 }
@@ -125,13 +289,18 @@ pub struct RewriteRules {
     pub main_content_cleanup: Vec<String>,
     pub delazify: bool,
     pub fix_embeds: bool,
+    /// Deprecated and ignored: the sanitizer drops scripts and the output
+    /// CSP blocks them. `Whitelist::validate` reports entries still setting it.
+    #[serde(default)]
     pub content_script: Option<String>,
     pub preprocess: Vec<AttributeRewrite>,
+    #[serde(default)]
+    pub operations: Vec<SelectorOps>,
 }
 
 impl RewriteRules {
-    pub fn get_content_handlers(&self, url: &Url) -> Vec<(Selector, ContentFunction)> {
-        rewrite_rules_to_content_handlers(self, &url.origin().ascii_serialization())
+    pub fn get_content_handlers(&self) -> Vec<(Selector, ContentFunction)> {
+        site_content_handlers(self)
     }
 }
 
@@ -147,39 +316,293 @@ impl RewriteRules {
     }
 }
 
+/// Rewriter configuration compiled for a specific article URL. Obtained
+/// from `SpeedReader::get_rewriter_config` and passed back to
+/// `SpeedReader::get_rewriter`, which borrows the compiled handlers for the
+/// lifetime of the rewriter. Site handlers are shared with every other
+/// configuration obtained for the same whitelist entry; the handlers
+/// tracking the state of a document are created by each rewriter.
+pub struct CompiledRewriteConfig {
+    rewriter_type: RewriterType,
+    origin: String,
+    site_handlers: Arc<Vec<(Selector, ContentFunction)>>,
+    readability_options: Arc<ReadabilityOptions>,
+}
+
+impl CompiledRewriteConfig {
+    fn empty(
+        rewriter_type: RewriterType,
+        origin: String,
+        readability_options: Arc<ReadabilityOptions>,
+    ) -> Self {
+        CompiledRewriteConfig {
+            rewriter_type,
+            origin,
+            site_handlers: Arc::new(vec![]),
+            readability_options,
+        }
+    }
+
+    pub fn rewriter_type(&self) -> RewriterType {
+        self.rewriter_type
+    }
+
+    pub fn origin(&self) -> &str {
+        &self.origin
+    }
+
+    pub fn handlers(&self) -> impl Iterator<Item = &(Selector, ContentFunction)> {
+        self.site_handlers.iter()
+    }
+
+    /// Scoring model heuristics use on the article, the site's own if its
+    /// whitelist entry has one.
+    pub fn readability_options(&self) -> Arc<ReadabilityOptions> {
+        Arc::clone(&self.readability_options)
+    }
+}
+
 pub struct SpeedReader {
-    whitelist: Whitelist,
-    url_engine: adblock::engine::Engine,
+    whitelist: LayeredWhitelist,
+    url_engines: BTreeMap<WhitelistLayer, adblock::engine::Engine>,
+    site_handlers: HashMap<SiteHandlersKey, Arc<Vec<(Selector, ContentFunction)>>>,
+    heuristics_fallback: bool,
+    extraction_diagnostics: bool,
+    readability_threshold: f32,
+    image_target: ImageTarget,
+    readability_options: Arc<ReadabilityOptions>,
+    sanitizer_policy: Arc<SanitizerPolicy>,
+    output_template: Option<Arc<OutputTemplate>>,
 }
 
 impl Default for SpeedReader {
     fn default() -> Self {
-        let whitelist = Whitelist::default();
-        let url_engine = adblock::engine::Engine::from_rules(&whitelist.get_url_rules());
         SpeedReader {
-            whitelist,
-            url_engine,
+            whitelist: LayeredWhitelist::default(),
+            url_engines: BTreeMap::new(),
+            site_handlers: HashMap::new(),
+            heuristics_fallback: false,
+            extraction_diagnostics: false,
+            readability_threshold: DEFAULT_READABILITY_THRESHOLD,
+            image_target: ImageTarget::default(),
+            readability_options: Arc::new(ReadabilityOptions::default()),
+            sanitizer_policy: Arc::new(SanitizerPolicy::default()),
+            output_template: Some(Arc::new(OutputTemplate::default())),
         }
     }
 }
 
 impl SpeedReader {
+    /// Uses the whitelist as the `Component` layer, which the methods that
+    /// don't take a layer operate on.
     pub fn with_whitelist(whitelist: Whitelist) -> Self {
-        let url_engine = adblock::engine::Engine::from_rules(&whitelist.get_url_rules());
-        SpeedReader {
-            whitelist,
-            url_engine,
+        let mut sr = SpeedReader::default();
+        sr.set_layer(WhitelistLayer::Component, whitelist);
+        sr
+    }
+
+    /// Replaces the configurations of a layer. Configurations of other layers
+    /// and domains disabled with `disable_domain` are kept.
+    pub fn set_layer(&mut self, layer: WhitelistLayer, whitelist: Whitelist) {
+        self.site_handlers.retain(|(l, _, _), _| *l != layer);
+        for config in whitelist.get_configurations() {
+            if let Some(rewrite) = config.declarative_rewrite.as_ref() {
+                self.site_handlers.insert(
+                    site_handlers_key(layer, config),
+                    Arc::new(site_content_handlers(rewrite)),
+                );
+            }
+        }
+        self.whitelist.set_layer(layer, whitelist);
+        self.rebuild_url_engine(layer);
+    }
+
+    /// Turns reader mode off for the domain and its subdomains in the layer,
+    /// whatever lower layers say about them.
+    pub fn disable_domain(&mut self, layer: WhitelistLayer, domain: &str) -> bool {
+        self.whitelist.disable_domain(layer, domain)
+    }
+
+    /// Lifts a previous `disable_domain`.
+    pub fn enable_domain(&mut self, layer: WhitelistLayer, domain: &str) -> bool {
+        self.whitelist.enable_domain(layer, domain)
+    }
+
+    /// Layer that decided how the URL is handled: the highest one with a
+    /// configuration for it or disabling its host.
+    pub fn get_layer(&self, article_url: &str) -> Option<WhitelistLayer> {
+        let url = Url::parse(article_url).ok()?;
+        self.whitelist
+            .get_configuration_for_url(&url)
+            .map(|found| found.layer)
+    }
+
+    /// Adds or replaces the configuration for its domain and URL scope.
+    pub fn add_config(&mut self, config: SpeedReaderConfig) {
+        self.add_layer_config(WhitelistLayer::Component, config);
+    }
+
+    /// Same as `add_config`, on the given layer.
+    pub fn add_layer_config(&mut self, layer: WhitelistLayer, config: SpeedReaderConfig) {
+        if self.insert_config(layer, config) {
+            self.rebuild_url_engine(layer);
         }
     }
 
+    /// Removes all configurations of the domain. Returns `false` if there
+    /// were none.
+    pub fn remove_domain(&mut self, domain: &str) -> bool {
+        self.remove_layer_domain(WhitelistLayer::Component, domain)
+    }
+
+    /// Same as `remove_domain`, on the given layer.
+    pub fn remove_layer_domain(&mut self, layer: WhitelistLayer, domain: &str) -> bool {
+        let removed = self.drop_domain(layer, domain);
+        if removed {
+            self.rebuild_url_engine(layer);
+        }
+        removed
+    }
+
+    /// Applies a whitelist delta to the `Component` layer, rebuilding the URL
+    /// rules at most once.
+    pub fn apply_patch(&mut self, patch: WhitelistPatch) {
+        let layer = WhitelistLayer::Component;
+        let mut rebuild = false;
+        for domain in patch.remove_domains.iter() {
+            rebuild |= self.drop_domain(layer, domain);
+        }
+        for config in patch.add.into_iter() {
+            rebuild |= self.insert_config(layer, config);
+        }
+        if rebuild {
+            self.rebuild_url_engine(layer);
+        }
+    }
+
+    /// Returns `true` if rules were replaced and the layer's URL engine needs
+    /// a rebuild, as adblock engines can only have rules added.
+    fn insert_config(&mut self, layer: WhitelistLayer, config: SpeedReaderConfig) -> bool {
+        let key = site_handlers_key(layer, &config);
+        match config.declarative_rewrite.as_ref() {
+            Some(rewrite) => self
+                .site_handlers
+                .insert(key, Arc::new(site_content_handlers(rewrite))),
+            None => self.site_handlers.remove(&key),
+        };
+
+        let url_rules = config.url_rules.clone();
+        match self.whitelist.layer_mut(layer).add_configuration(config) {
+            Some(replaced) => replaced.url_rules != url_rules,
+            None => {
+                let url_engine = self
+                    .url_engines
+                    .entry(layer)
+                    .or_insert_with(|| adblock::engine::Engine::from_rules(&[]));
+                for rule in url_rules.iter() {
+                    if !url_engine.filter_exists(rule) {
+                        url_engine.filter_add(rule);
+                    }
+                }
+                false
+            }
+        }
+    }
+
+    /// Returns `true` if the layer's URL engine needs a rebuild.
+    fn drop_domain(&mut self, layer: WhitelistLayer, domain: &str) -> bool {
+        let removed = self.whitelist.layer_mut(layer).remove_domain(domain);
+        for config in removed.iter() {
+            self.site_handlers.remove(&site_handlers_key(layer, config));
+        }
+        !removed.is_empty()
+    }
+
+    fn rebuild_url_engine(&mut self, layer: WhitelistLayer) {
+        let rules = self
+            .whitelist
+            .layer(layer)
+            .map(Whitelist::get_url_rules)
+            .unwrap_or_default();
+        self.url_engines
+            .insert(layer, adblock::engine::Engine::from_rules(&rules));
+    }
+
+    /// Opt into re-running documents through heuristics when a site's
+    /// streaming rules keep little or no text. Streaming rewriters then
+    /// buffer the whole document before producing any output.
+    pub fn set_heuristics_fallback(&mut self, enabled: bool) {
+        self.heuristics_fallback = enabled;
+    }
+
+    /// Opt into recording how heuristics score candidates and which nodes
+    /// they remove, available from `SpeedReaderProcessor::diagnostics`.
+    /// Meant for debugging extractions, it costs an extra pass over the
+    /// candidates.
+    pub fn set_extraction_diagnostics(&mut self, enabled: bool) {
+        self.extraction_diagnostics = enabled;
+    }
+
+    /// Share of classifier votes, in `[0, 1]`, a document needs to exceed to
+    /// be considered readable by heuristics. Raising it trades missed
+    /// articles for fewer broken pages.
+    pub fn set_readability_threshold(&mut self, threshold: f32) {
+        self.readability_threshold = threshold;
+    }
+
+    /// Display images are picked for from `srcset` and `<picture>`
+    /// candidates. Applies to configurations obtained afterwards.
+    pub fn set_image_target(&mut self, image_target: ImageTarget) {
+        self.image_target = image_target;
+    }
+
+    /// Scoring model heuristics extract articles with, unless the site's
+    /// whitelist entry has its own. Applies to configurations obtained
+    /// afterwards.
+    pub fn set_readability_options(&mut self, readability_options: ReadabilityOptions) {
+        self.readability_options = Arc::new(readability_options);
+    }
+
+    /// Allowlist every distilled document is sanitized with. The default
+    /// leaves nothing able to run code, including the social embeds of site
+    /// configurations.
+    pub fn set_sanitizer_policy(&mut self, sanitizer_policy: SanitizerPolicy) {
+        self.sanitizer_policy = Arc::new(sanitizer_policy);
+    }
+
+    /// Document distilled content is placed in. With `None` rewriters
+    /// produce only the sanitized content.
+    pub fn set_output_template(&mut self, output_template: Option<OutputTemplate>) {
+        self.output_template = output_template.map(Arc::new);
+    }
+
+    /// Replaces the stylesheet of the current output template, keeping the
+    /// rest of it. Does nothing while no template is set.
+    pub fn set_stylesheet(&mut self, stylesheet: Stylesheet) {
+        if let Some(output_template) = self.output_template.as_mut() {
+            Arc::make_mut(output_template).stylesheet = stylesheet;
+        }
+    }
+
+    /// Only the URL rules of the layer that decides for the URL's host are
+    /// consulted, so a layer overriding a site also replaces its rules. URLs
+    /// of hosts no layer knows are checked against all rules, highest layer
+    /// first.
     pub fn url_readable(&self, url: &str) -> Option<bool> {
-        let matched = self.url_engine.check_network_urls(url, url, "");
-        if matched.exception.is_some() {
-            Some(false)
-        } else if matched.matched {
-            Some(true)
-        } else {
-            None
+        let found = Url::parse(url)
+            .ok()
+            .and_then(|u| self.whitelist.get_configuration_for_url(&u));
+        match found {
+            Some(found) if found.config.is_none() => Some(false),
+            Some(found) => self
+                .url_engines
+                .get(&found.layer)
+                .and_then(|engine| engine_url_readable(engine, url)),
+            None => self
+                .url_engines
+                .values()
+                .rev()
+                .find_map(|engine| engine_url_readable(engine, url)),
         }
     }
 
@@ -187,7 +610,8 @@ impl SpeedReader {
         if let Ok(url) = Url::parse(article_url) {
             let config = self
                 .whitelist
-                .get_configuration(&url.domain().unwrap_or_default());
+                .get_configuration_for_url(&url)
+                .and_then(|found| found.config);
 
             match config {
                 Some(SpeedReaderConfig {
@@ -202,54 +626,118 @@ impl SpeedReader {
         }
     }
 
-    pub fn get_opaque_config(&self, article_url: &str) -> Box<dyn Any> {
+    pub fn get_rewriter_config(&self, article_url: &str) -> CompiledRewriteConfig {
         if let Ok(url) = Url::parse(article_url) {
-            let config = self
+            let origin = url.origin().ascii_serialization();
+            let found = self
                 .whitelist
-                .get_configuration(&url.domain().unwrap_or_default());
+                .get_configuration_for_url(&url)
+                .and_then(|found| found.config.map(|config| (found.layer, config)));
+            let readability_options = found
+                .and_then(|(_, config)| config.readability.clone())
+                .map(Arc::new)
+                .unwrap_or_else(|| Arc::clone(&self.readability_options));
 
-            match config {
-                Some(SpeedReaderConfig {
-                    declarative_rewrite: Some(rewrite),
-                    ..
-                }) => Box::new(rewrite.get_content_handlers(&url)),
-                _ => Box::new(Vec::<(Selector, ContentFunction)>::new()),
+            match found {
+                Some((
+                    layer,
+                    config @ SpeedReaderConfig {
+                        declarative_rewrite: Some(rewrite),
+                        ..
+                    },
+                )) => {
+                    let site_handlers = self
+                        .site_handlers
+                        .get(&site_handlers_key(layer, config))
+                        .cloned()
+                        .unwrap_or_else(|| Arc::new(site_content_handlers(rewrite)));
+                    CompiledRewriteConfig {
+                        rewriter_type: RewriterType::Streaming,
+                        site_handlers,
+                        origin,
+                        readability_options,
+                    }
+                }
+                Some(_) => CompiledRewriteConfig::empty(
+                    RewriterType::Heuristics,
+                    origin,
+                    readability_options,
+                ),
+                None => {
+                    CompiledRewriteConfig::empty(RewriterType::Unknown, origin, readability_options)
+                }
             }
         } else {
-            Box::new(Vec::<(Selector, ContentFunction)>::new())
+            CompiledRewriteConfig::empty(
+                RewriterType::Unknown,
+                String::new(),
+                Arc::clone(&self.readability_options),
+            )
         }
     }
 
+    /// Rewriter distilling the document at `article_url`. The `response` it
+    /// came with, if known, must be a successful HTML one. Its `Content-Type`
+    /// charset is used to decode the document unless it has a byte order
+    /// mark; otherwise a `<meta>` declaration or UTF-8 is. Its language and
+    /// canonical link fill in article metadata the document leaves out.
     pub fn get_rewriter<'h, O: OutputSink + 'h>(
-        &'h self,
+        &self,
         article_url: &str,
-        extra: &'h Box<dyn Any>,
+        config: &'h CompiledRewriteConfig,
         output_sink: O,
         rewriter_type: Option<RewriterType>,
+        output_format: OutputFormat,
+        response: Option<&ResponseInfo>,
     ) -> Result<Box<dyn SpeedReaderProcessor + 'h>, SpeedReaderError> {
+        if let Some(response) = response {
+            response.check_distillable()?;
+        }
         if let Ok(url) = Url::parse(article_url) {
             let rewriter_decided = match rewriter_type {
                 Some(r_type) => r_type,
-                None => self.get_rewriter_type(article_url),
+                None => config.rewriter_type(),
             };
 
-            if let Some(content_handlers) = extra.downcast_ref::<Vec<(Selector, ContentFunction)>>()
-            {
-                match rewriter_decided {
-                    RewriterType::Streaming => Ok(Box::new(SpeedReaderStreaming::try_new(
+            match rewriter_decided {
+                RewriterType::Streaming if self.heuristics_fallback => Ok(Box::new(
+                    SpeedReaderHybrid::new(
                         url,
                         output_sink,
-                        content_handlers,
-                    )?)),
-                    _ => Ok(Box::new(SpeedReaderHeuristics::try_new(
-                        url.as_str(),
-                        output_sink,
-                    )?)),
+                        config,
+                        self.readability_threshold,
+                        self.image_target,
+                        Arc::clone(&self.sanitizer_policy),
+                        self.output_template.clone(),
+                        output_format,
+                        response.cloned(),
+                        self.extraction_diagnostics,
+                    ),
+                )),
+                RewriterType::Streaming => Ok(Box::new(SpeedReaderStreaming::try_new(
+                    url,
+                    output_sink,
+                    config,
+                    self.image_target,
+                    Arc::clone(&self.sanitizer_policy),
+                    self.output_template.clone(),
+                    output_format,
+                    response,
+                )?)),
+                _ => {
+                    let mut heuristics = SpeedReaderHeuristics::try_new(url.as_str(), output_sink)?;
+                    heuristics.set_readability_threshold(self.readability_threshold);
+                    heuristics.set_image_target(self.image_target);
+                    heuristics.set_readability_options(config.readability_options());
+                    heuristics.set_diagnostics(self.extraction_diagnostics);
+                    heuristics.set_sanitizer_policy(Arc::clone(&self.sanitizer_policy));
+                    heuristics.set_output_template(self.output_template.clone());
+                    heuristics.set_output_format(output_format);
+                    if let Some(response) = response {
+                        heuristics.set_response_info(response.clone());
+                    }
+                    Ok(Box::new(heuristics))
                 }
-            } else {
-                Err(SpeedReaderError::ConfigurationError(
-                "The configuration `extra` parameter could not be unmarshalled to expected type"
-                    .to_owned()))
             }
         } else {
             Err(SpeedReaderError::BadURL(article_url.to_owned()))
@@ -257,6 +745,24 @@ impl SpeedReader {
     }
 }
 
+/// Configurations are unique per layer, domain and URL scope.
+type SiteHandlersKey = (WhitelistLayer, String, Vec<String>);
+
+fn site_handlers_key(layer: WhitelistLayer, config: &SpeedReaderConfig) -> SiteHandlersKey {
+    (layer, config.domain.clone(), config.url_scope.clone())
+}
+
+fn engine_url_readable(engine: &adblock::engine::Engine, url: &str) -> Option<bool> {
+    let matched = engine.check_network_urls(url, url, "");
+    if matched.exception.is_some() {
+        Some(false)
+    } else if matched.matched {
+        Some(true)
+    } else {
+        None
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -270,6 +776,9 @@ mod test {
                 r#"@@||example.com/article/video"#.to_owned(),
             ],
             declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
         });
         whitelist.add_configuration(SpeedReaderConfig {
             domain: "example.net".to_owned(),
@@ -281,7 +790,11 @@ mod test {
                 fix_embeds: true,
                 content_script: None,
                 preprocess: vec![],
+                operations: vec![],
             }),
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
         });
         whitelist
     }
@@ -314,6 +827,117 @@ mod test {
         assert_eq!(readable, None);
     }
 
+    #[test]
+    pub fn add_config_live() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        assert_eq!(sr.url_readable("http://smart-e.org/blog"), None);
+        sr.add_config(SpeedReaderConfig {
+            domain: "smart-e.org".to_owned(),
+            url_rules: vec![r#"||smart-e.org/blog"#.to_owned()],
+            declarative_rewrite: Some(RewriteRules::default()),
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        assert_eq!(sr.url_readable("http://smart-e.org/blog"), Some(true));
+        assert_eq!(
+            sr.get_rewriter_type("http://smart-e.org/blog"),
+            RewriterType::Streaming
+        );
+
+        // Replacing the rules drops the old ones
+        sr.add_config(SpeedReaderConfig {
+            domain: "smart-e.org".to_owned(),
+            url_rules: vec![r#"||smart-e.org/news"#.to_owned()],
+            declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        assert_eq!(sr.url_readable("http://smart-e.org/blog"), None);
+        assert_eq!(sr.url_readable("http://smart-e.org/news"), Some(true));
+        assert_eq!(
+            sr.get_rewriter_type("http://smart-e.org/news"),
+            RewriterType::Heuristics
+        );
+    }
+
+    #[test]
+    pub fn remove_domain_live() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        assert!(sr.remove_domain("example.net"));
+        assert!(!sr.remove_domain("example.net"));
+        assert_eq!(sr.url_readable("http://example.net/article/today"), None);
+        assert_eq!(
+            sr.get_rewriter_type("http://example.net/article/today"),
+            RewriterType::Unknown
+        );
+        assert!(sr.site_handlers.is_empty());
+        assert_eq!(
+            sr.url_readable("http://example.com/article/today"),
+            Some(true)
+        );
+    }
+
+    #[test]
+    pub fn apply_patch_live() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        let patch = WhitelistPatch::deserialize(
+            br#"{
+                "remove_domains": ["example.com"],
+                "add": [{
+                    "domain": "smart-e.org",
+                    "url_rules": ["||smart-e.org/blog"],
+                    "declarative_rewrite": null
+                }]
+            }"#,
+        )
+        .unwrap();
+        sr.apply_patch(patch);
+        assert_eq!(sr.url_readable("http://example.com/article/today"), None);
+        assert_eq!(sr.url_readable("http://smart-e.org/blog"), Some(true));
+        assert_eq!(
+            sr.url_readable("http://example.net/article/today"),
+            Some(true)
+        );
+    }
+
+    #[test]
+    pub fn layered_overrides() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        let mut user = Whitelist::default();
+        user.add_configuration(SpeedReaderConfig {
+            domain: "example.net".to_owned(),
+            url_rules: vec![r#"||example.net/blog"#.to_owned()],
+            declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        sr.set_layer(WhitelistLayer::User, user);
+
+        // The user layer replaces the component rules for example.net
+        let article = "http://example.net/article/today";
+        assert_eq!(sr.url_readable(article), None);
+        assert_eq!(sr.url_readable("http://example.net/blog"), Some(true));
+        assert_eq!(sr.get_rewriter_type(article), RewriterType::Heuristics);
+        assert_eq!(sr.get_layer(article), Some(WhitelistLayer::User));
+
+        let article = "http://example.com/article/today";
+        assert_eq!(sr.get_layer(article), Some(WhitelistLayer::Component));
+        assert!(sr.disable_domain(WhitelistLayer::Enterprise, "example.com"));
+        assert_eq!(sr.url_readable(article), Some(false));
+        assert_eq!(sr.get_rewriter_type(article), RewriterType::Unknown);
+        assert_eq!(sr.get_layer(article), Some(WhitelistLayer::Enterprise));
+
+        // Reloading a layer keeps the others
+        sr.set_layer(WhitelistLayer::Component, get_whitelist());
+        assert_eq!(sr.get_layer(article), Some(WhitelistLayer::Enterprise));
+        assert!(sr.enable_domain(WhitelistLayer::Enterprise, "example.com"));
+        assert_eq!(sr.url_readable(article), Some(true));
+        assert_eq!(sr.get_layer("http://bbc.com/news"), None);
+    }
+
     #[test]
     pub fn configuration_matching_some() {
         let sr = SpeedReader::with_whitelist(get_whitelist());
@@ -329,15 +953,41 @@ mod test {
     }
 
     #[test]
-    pub fn configuration_opaque_correctly_typed() {
+    pub fn configuration_compiled_heuristics() {
         let sr = SpeedReader::with_whitelist(get_whitelist());
         let article = "http://example.com/article/today";
-        let config = sr.get_rewriter_type(article);
-        assert_eq!(config, RewriterType::Heuristics);
-        let opaque = sr.get_opaque_config(article);
-        assert!(opaque
-            .downcast_ref::<Vec<(Selector, ContentFunction)>>()
-            .is_some());
+        let config = sr.get_rewriter_config(article);
+        assert_eq!(config.rewriter_type(), RewriterType::Heuristics);
+        assert_eq!(config.origin(), "http://example.com");
+        assert_eq!(config.handlers().count(), 0);
+    }
+
+    #[test]
+    pub fn configuration_compiled_streaming() {
+        let sr = SpeedReader::with_whitelist(get_whitelist());
+        let article = "http://example.net/article/today";
+        let config = sr.get_rewriter_config(article);
+        assert_eq!(config.rewriter_type(), RewriterType::Streaming);
+        assert_eq!(config.origin(), "http://example.net");
+        assert!(config.handlers().count() > 0);
+    }
+
+    #[test]
+    pub fn configuration_compiled_handlers_shared() {
+        let sr = SpeedReader::with_whitelist(get_whitelist());
+        let first = sr.get_rewriter_config("http://example.net/article/today");
+        let second = sr.get_rewriter_config("https://www.example.net/article/tomorrow");
+        assert!(Arc::ptr_eq(&first.site_handlers, &second.site_handlers));
+        assert_eq!(second.origin(), "https://www.example.net");
+        assert_eq!(first.handlers().count(), second.handlers().count());
+    }
+
+    #[test]
+    pub fn configuration_compiled_nomatch() {
+        let sr = SpeedReader::with_whitelist(get_whitelist());
+        let config = sr.get_rewriter_config("http://bbc.com/article/today");
+        assert_eq!(config.rewriter_type(), RewriterType::Unknown);
+        assert_eq!(config.handlers().count(), 0);
     }
 
     #[test]
@@ -346,8 +996,8 @@ mod test {
         let article = "http://example.com/article/today";
         let config = sr.get_rewriter_type(article);
         assert_eq!(config, RewriterType::Heuristics);
-        let opaque = sr.get_opaque_config(article);
-        let maybe_rewriter = sr.get_rewriter(article, &opaque, |_: &[u8]| {}, Some(config));
+        let compiled = sr.get_rewriter_config(article);
+        let maybe_rewriter = sr.get_rewriter(article, &compiled, |_: &[u8]| {}, Some(config), OutputFormat::Html, None);
         assert!(maybe_rewriter.is_ok());
         let rewriter = maybe_rewriter.unwrap();
         assert_eq!(rewriter.rewriter_type(), RewriterType::Heuristics);
@@ -359,10 +1009,675 @@ mod test {
         let article = "http://example.net/article/today";
         let config = sr.get_rewriter_type(article);
         assert_eq!(config, RewriterType::Streaming);
-        let opaque = sr.get_opaque_config(article);
-        let maybe_rewriter = sr.get_rewriter(article, &opaque, |_: &[u8]| {}, Some(config));
+        let compiled = sr.get_rewriter_config(article);
+        let maybe_rewriter = sr.get_rewriter(article, &compiled, |_: &[u8]| {}, Some(config), OutputFormat::Html, None);
         assert!(maybe_rewriter.is_ok());
         let rewriter = maybe_rewriter.unwrap();
         assert_eq!(rewriter.rewriter_type(), RewriterType::Streaming);
     }
+
+    #[test]
+    pub fn rewriter_streaming_metadata() {
+        let sr = SpeedReader::with_whitelist(get_whitelist());
+        let article = "http://example.net/article/today";
+        let compiled = sr.get_rewriter_config(article);
+        let mut rewriter = sr
+            .get_rewriter(article, &compiled, |_: &[u8]| {}, None, OutputFormat::Html, None)
+            .unwrap();
+        rewriter
+            .write(b"<html><head><title>Today</title></head><body><article>Hi</article>")
+            .unwrap();
+        assert!(rewriter.metadata().is_none());
+        rewriter.end().unwrap();
+        let metadata = rewriter.metadata().unwrap();
+        assert_eq!(metadata.title, Some("Today".to_owned()));
+    }
+
+    #[test]
+    fn distill_outcome_from_result() {
+        let ok: Result<(), SpeedReaderError> = Ok(());
+        assert_eq!(DistillOutcome::from(&ok), DistillOutcome::Distilled);
+
+        let rejected: Result<(), SpeedReaderError> = Err(SpeedReaderError::ClassifierRejected {
+            readable_votes: 10,
+            total_votes: 100,
+        });
+        assert_eq!(
+            DistillOutcome::from(&rejected),
+            DistillOutcome::ClassifierRejected
+        );
+        assert_eq!(
+            SpeedReaderError::NothingExtracted { text_length: 0 }.outcome() as u8,
+            2
+        );
+        assert_eq!(
+            SpeedReaderError::BadURL("about:blank".to_owned()).outcome(),
+            DistillOutcome::InvalidUrl
+        );
+    }
+
+    #[test]
+    fn rewriter_heuristics_invalid_scheme() {
+        let sr = SpeedReader::default();
+        let article = "ftp://example.com/article.html";
+        let compiled = sr.get_rewriter_config(article);
+        let rewriter = sr.get_rewriter(
+            article,
+            &compiled,
+            |_: &[u8]| {},
+            Some(RewriterType::Heuristics),
+            OutputFormat::Html,
+            None,
+        );
+        assert_eq!(
+            rewriter.err().map(|e| e.outcome()),
+            Some(DistillOutcome::InvalidScheme)
+        );
+    }
+
+    #[test]
+    fn rewriter_fallback_keeps_streaming() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.set_heuristics_fallback(true);
+        let article = "http://example.net/article/today";
+        let compiled = sr.get_rewriter_config(article);
+        let mut output = vec![];
+        let mut rewriter = sr
+            .get_rewriter(
+                article,
+                &compiled,
+                |c: &[u8]| output.extend_from_slice(c),
+                None,
+                OutputFormat::Html,
+                None,
+            )
+            .unwrap();
+        rewriter
+            .write(b"<html><body><article><p>Plenty of article text in here.</p></article>")
+            .unwrap();
+        rewriter.end().unwrap();
+        assert_eq!(rewriter.rewriter_type(), RewriterType::Streaming);
+        drop(rewriter);
+        assert!(String::from_utf8(output)
+            .unwrap()
+            .contains("Plenty of article text in here."));
+    }
+
+    #[test]
+    fn rewriter_fallback_to_heuristics() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.set_heuristics_fallback(true);
+        let article = "http://example.net/article/today";
+        let compiled = sr.get_rewriter_config(article);
+        let mut output = vec![];
+        let mut rewriter = sr
+            .get_rewriter(
+                article,
+                &compiled,
+                |c: &[u8]| output.extend_from_slice(c),
+                None,
+                OutputFormat::Html,
+                None,
+            )
+            .unwrap();
+        rewriter
+            .write(b"<html><body><div class=\"redesigned\"><p>Not matched.</p></div>")
+            .unwrap();
+        let result = rewriter.end();
+        assert_eq!(rewriter.rewriter_type(), RewriterType::Heuristics);
+        assert!(result.is_err());
+        drop(rewriter);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn rewriter_rewrite_operations() {
+        let rules: RewriteRules = serde_json::from_str(
+            r#"{
+                "main_content": ["article"],
+                "main_content_cleanup": [],
+                "delazify": false,
+                "fix_embeds": false,
+                "content_script": null,
+                "preprocess": [],
+                "operations": [
+                    {"selector": "p.lead", "ops": [
+                        {"op": "remove_attribute", "name": "class"},
+                        {"op": "set_attribute", "name": "lang", "value": "en"},
+                        {"op": "wrap", "tag": "section"}
+                    ]},
+                    {"selector": ".byline", "ops": [{"op": "replace_with_text"}]},
+                    {"selector": "h1", "ops": [
+                        {"op": "insert_before", "html": "<hr>"},
+                        {"op": "replace_text", "pattern": "(\\w+) news", "replacement": "$1 <news>"}
+                    ]},
+                    {"selector": "p.note", "ops": [
+                        {"op": "replace_text", "pattern": " & ", "replacement": " and "}
+                    ]},
+                    {"selector": "nav", "ops": [{"op": "insert_after", "html": "<p>leak</p>"}]}
+                ]
+            }"#,
+        )
+        .unwrap();
+        let mut sr = SpeedReader::default();
+        sr.add_config(SpeedReaderConfig {
+            domain: "example.org".to_owned(),
+            url_rules: vec![],
+            declarative_rewrite: Some(rules),
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        let article = "http://example.org/article";
+        let compiled = sr.get_rewriter_config(article);
+        let mut output = vec![];
+        let mut rewriter = sr
+            .get_rewriter(
+                article,
+                &compiled,
+                |c: &[u8]| output.extend_from_slice(c),
+                None,
+                OutputFormat::Html,
+                None,
+            )
+            .unwrap();
+        let document = b"<html><body><nav>Menu</nav><article><h1>Good news</h1>\
+            <p class=\"lead\">Lead</p><div class=\"byline\">By <a href=\"/me\">me</a></div>\
+            <p class=\"note\">Salt &amp; pepper, fish &amp; chips</p></article>";
+        // Text is split between writes
+        for chunk in document.chunks(3) {
+            rewriter.write(chunk).unwrap();
+        }
+        rewriter.end().unwrap();
+        drop(rewriter);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains("<hr><h1>Good &lt;news&gt;</h1>"), "{}", output);
+        assert!(output.contains("Salt and pepper, fish and chips"), "{}", output);
+        assert!(output.contains("<section><p lang=\"en\">Lead</p></section>"), "{}", output);
+        assert!(output.contains("By me"), "{}", output);
+        assert!(!output.contains("leak"), "{}", output);
+    }
+
+    #[test]
+    fn rewriter_resolves_urls_against_base() {
+        let sr = SpeedReader::with_whitelist(get_whitelist());
+        let article = "http://example.net/article/today/index.html";
+        let compiled = sr.get_rewriter_config(article);
+        let mut output = vec![];
+        let mut rewriter = sr
+            .get_rewriter(
+                article,
+                &compiled,
+                |c: &[u8]| output.extend_from_slice(c),
+                None,
+                OutputFormat::Html,
+                None,
+            )
+            .unwrap();
+        rewriter
+            .write(
+                b"<html><head><base href=\"/static/\"><base href=\"/ignored/\"></head><body><article>\
+                <a href=\"../page2\">next</a><a href=\"#notes\">notes</a>\
+                <a href=\"mailto:me@example.net\">mail</a>\
+                <img src=\"img.png\">\
+                <video poster=\"p.jpg\"></video><blockquote cite=\"src.html\">q</blockquote>\
+                </article>",
+            )
+            .unwrap();
+        rewriter.end().unwrap();
+        drop(rewriter);
+        let output = String::from_utf8(output).unwrap();
+        for expected in [
+            r#"href="http://example.net/page2""#,
+            r##"href="#notes""##,
+            r#"href="mailto:me@example.net""#,
+            r#"src="http://example.net/static/img.png""#,
+            r#"poster="http://example.net/static/p.jpg""#,
+            r#"cite="http://example.net/static/src.html""#,
+        ]
+        .iter()
+        {
+            assert!(output.contains(expected), "{} not in {}", expected, output);
+        }
+    }
+
+    #[test]
+    fn rewriters_keep_their_own_base() {
+        let sr = SpeedReader::with_whitelist(get_whitelist());
+        let article = "http://example.net/article/today/index.html";
+        let compiled = sr.get_rewriter_config(article);
+        let mut first_output = vec![];
+        let mut first = sr
+            .get_rewriter(
+                article,
+                &compiled,
+                |c: &[u8]| first_output.extend_from_slice(c),
+                None,
+                OutputFormat::Html,
+                None,
+            )
+            .unwrap();
+        let mut second_output = vec![];
+        let mut second = sr
+            .get_rewriter(
+                article,
+                &compiled,
+                |c: &[u8]| second_output.extend_from_slice(c),
+                None,
+                OutputFormat::Html,
+                None,
+            )
+            .unwrap();
+        first
+            .write(b"<html><head><base href=\"/static/\"></head><body><article>")
+            .unwrap();
+        second
+            .write(b"<html><head></head><body><article><img src=\"img.png\"></article>")
+            .unwrap();
+        second.end().unwrap();
+        first.write(b"<img src=\"img.png\"></article>").unwrap();
+        first.end().unwrap();
+        drop(first);
+        drop(second);
+        let first_output = String::from_utf8(first_output).unwrap();
+        let second_output = String::from_utf8(second_output).unwrap();
+        assert!(
+            first_output.contains(r#"src="http://example.net/static/img.png""#),
+            "{}",
+            first_output
+        );
+        assert!(
+            second_output.contains(r#"src="http://example.net/article/today/img.png""#),
+            "{}",
+            second_output
+        );
+    }
+
+    #[test]
+    fn rewriter_selects_images() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.set_image_target(ImageTarget {
+            width: 400,
+            pixel_ratio: 2.0,
+        });
+        let article = "http://example.net/article/today/index.html";
+        let compiled = sr.get_rewriter_config(article);
+        let mut output = vec![];
+        let mut rewriter = sr
+            .get_rewriter(
+                article,
+                &compiled,
+                |c: &[u8]| output.extend_from_slice(c),
+                None,
+                OutputFormat::Html,
+                None,
+            )
+            .unwrap();
+        rewriter
+            .write(
+                b"<html><body><article>\
+                <img data-srcset=\"s.jpg 400w, m.jpg 800w, l.jpg 1600w\" width=\"1600\" height=\"900\">\
+                <picture><source srcset=\"p.webp 1200w\" type=\"image/webp\">\
+                <img src=\"fallback.jpg\" srcset=\"p.jpg 600w\" sizes=\"50vw\"></picture>\
+                <img src=\"plain.jpg\" width=\"100%\" style=\"position: fixed\">\
+                </article>",
+            )
+            .unwrap();
+        rewriter.end().unwrap();
+        drop(rewriter);
+        let output = String::from_utf8(output).unwrap();
+        assert!(output.contains(
+            r#"loading="lazy" src="http://example.net/article/today/m.jpg" style="aspect-ratio: 1600 / 900">"#
+        ), "{}", output);
+        assert!(output.contains(
+            r#"<picture><img src="http://example.net/article/today/p.webp" loading="lazy"></picture>"#
+        ), "{}", output);
+        assert!(output.contains(
+            r#"<img src="http://example.net/article/today/plain.jpg" loading="lazy">"#
+        ), "{}", output);
+    }
+
+    #[test]
+    fn site_readability_options() {
+        let config: SpeedReaderConfig = serde_json::from_str(
+            r#"{
+                "domain": "example.org",
+                "url_rules": [],
+                "declarative_rewrite": null,
+                "readability": {"negative_candidates": "promo", "decay_factor": 2.0}
+            }"#,
+        )
+        .unwrap();
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.add_config(config);
+        sr.set_readability_options(ReadabilityOptions {
+            decay_factor: 4.0,
+            ..ReadabilityOptions::default()
+        });
+
+        let site = sr.get_rewriter_config("http://example.org/article");
+        assert_eq!(site.rewriter_type(), RewriterType::Heuristics);
+        assert_eq!(site.readability_options().negative_candidates.as_str(), "promo");
+        assert_eq!(site.readability_options().decay_factor, 2.0);
+        assert_eq!(
+            site.readability_options().positive_candidates,
+            ReadabilityOptions::default().positive_candidates
+        );
+
+        let other = sr.get_rewriter_config("http://example.com/article");
+        assert_eq!(other.readability_options().decay_factor, 4.0);
+    }
+
+    #[test]
+    fn rewriter_sanitizes_output() {
+        let rules = RewriteRules {
+            main_content: vec!["article".to_owned()],
+            fix_embeds: true,
+            ..RewriteRules::default()
+        };
+        let mut sr = SpeedReader::default();
+        sr.add_config(SpeedReaderConfig {
+            domain: "example.org".to_owned(),
+            url_rules: vec![],
+            declarative_rewrite: Some(rules),
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        let article = "http://example.org/article";
+        let document = b"<html><body><article><p onclick=\"steal()\">Text</p>\
+            <a href=\"javascript:steal()\">link</a><iframe src=\"/ad\"></iframe>\
+            <div class=\"twitterContainer\">Tweet</div><script>track()</script></article>\
+            </body></html>";
+
+        let distill = |sr: &SpeedReader| {
+            let compiled = sr.get_rewriter_config(article);
+            let mut output = vec![];
+            let mut rewriter = sr
+                .get_rewriter(
+                    article,
+                    &compiled,
+                    |c: &[u8]| output.extend_from_slice(c),
+                    None,
+                    OutputFormat::Html,
+                    None,
+                )
+                .unwrap();
+            rewriter.write(document).unwrap();
+            rewriter.end().unwrap();
+            drop(rewriter);
+            String::from_utf8(output).unwrap()
+        };
+
+        let output = distill(&sr);
+        assert!(
+            output.contains("<article><p>Text</p><a>link</a><div class=\"twitterContainer\">"),
+            "{}",
+            output
+        );
+        assert!(!output.contains("script"), "{}", output);
+
+        let mut policy = SanitizerPolicy::default();
+        policy.dropped_tags.remove("script");
+        policy.tags.insert("script".to_owned());
+        sr.set_sanitizer_policy(policy);
+        let output = distill(&sr);
+        assert!(output.contains("<script>track()</script>"), "{}", output);
+        assert!(!output.contains("onclick"), "{}", output);
+    }
+
+    #[test]
+    fn rewriter_output_template() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.set_readability_threshold(0.0);
+        sr.set_output_template(Some(OutputTemplate {
+            stylesheet: Stylesheet::Link("chrome://speedreader/theme.css".to_owned()),
+            ..OutputTemplate::default()
+        }));
+        let article = "http://example.net/article/today/index.html";
+        let document = b"<!DOCTYPE html>\n<html lang=\"fr\"><head><title>Fish &amp; chips</title>\
+            <meta property=\"og:site_name\" content=\"Example\"></head><body><nav>Menu</nav>\
+            <article><p>A long enough paragraph of text to be extracted as content.</p>\
+            <p>And another paragraph, so that there is plenty of text.</p></article></body></html>";
+
+        let distill = |rewriter_type| {
+            let compiled = sr.get_rewriter_config(article);
+            let mut output = vec![];
+            let mut rewriter = sr
+                .get_rewriter(
+                    article,
+                    &compiled,
+                    |c: &[u8]| output.extend_from_slice(c),
+                    Some(rewriter_type),
+                    OutputFormat::Html,
+                    None,
+                )
+                .unwrap();
+            for chunk in document.chunks(10) {
+                rewriter.write(chunk).unwrap();
+            }
+            rewriter.end().unwrap();
+            drop(rewriter);
+            String::from_utf8(output).unwrap()
+        };
+
+        let streaming = distill(RewriterType::Streaming);
+        let heuristics = distill(RewriterType::Heuristics);
+        let split = |output: &str| {
+            let start = output.find("<div id=\"article-content\">").unwrap();
+            let end = output.rfind("</div>").unwrap();
+            (output[..start].to_owned(), output[end..].to_owned())
+        };
+        assert_eq!(split(&streaming), split(&heuristics));
+        let (head, tail) = split(&streaming);
+        assert!(head.starts_with("<!DOCTYPE html>\n<html lang=\"fr\" dir=\"auto\">"), "{}", head);
+        assert!(head.contains("<title>Fish &amp; chips</title>"), "{}", head);
+        assert!(head.contains("href=\"chrome://speedreader/theme.css\""), "{}", head);
+        assert!(head.contains("<span class=\"site-name\">Example</span>"), "{}", head);
+        assert_eq!(tail, "</div>\n</article>\n</body>\n</html>\n");
+        assert!(!streaming.contains("Menu"));
+        assert!(heuristics.contains("another paragraph"));
+    }
+
+    #[test]
+    fn set_stylesheet_keeps_template() {
+        let mut sr = SpeedReader::default();
+        sr.set_output_template(Some(OutputTemplate {
+            content_security_policy: "default-src 'self'".to_owned(),
+            ..OutputTemplate::default()
+        }));
+        sr.set_stylesheet(Stylesheet::Inline("body { margin: 0 }".to_owned()));
+        assert_eq!(
+            sr.output_template.as_ref().map(|t| (**t).clone()),
+            Some(OutputTemplate {
+                stylesheet: Stylesheet::Inline("body { margin: 0 }".to_owned()),
+                content_security_policy: "default-src 'self'".to_owned(),
+            })
+        );
+
+        sr.set_output_template(None);
+        sr.set_stylesheet(Stylesheet::Link("chrome://speedreader/theme.css".to_owned()));
+        assert!(sr.output_template.is_none());
+    }
+
+    #[test]
+    fn rewriter_extraction_diagnostics() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.set_readability_threshold(0.0);
+        let article = "http://example.net/article/today/index.html";
+        let document = b"<html><body><div class=\"comments\"><p>A comment</p></div>\
+            <article><p>A long enough paragraph of text to be extracted as content.</p>\
+            <p>And another paragraph, so that there is plenty of text.</p></article></body></html>";
+
+        let diagnostics = |sr: &SpeedReader, rewriter_type| {
+            let compiled = sr.get_rewriter_config(article);
+            let mut rewriter = sr
+                .get_rewriter(
+                    article,
+                    &compiled,
+                    |_: &[u8]| {},
+                    Some(rewriter_type),
+                    OutputFormat::Html,
+                    None,
+                )
+                .unwrap();
+            rewriter.write(document).unwrap();
+            rewriter.end().unwrap();
+            rewriter.diagnostics().cloned()
+        };
+
+        assert_eq!(diagnostics(&sr, RewriterType::Heuristics), None);
+        sr.set_extraction_diagnostics(true);
+        assert_eq!(diagnostics(&sr, RewriterType::Streaming), None);
+        let found = diagnostics(&sr, RewriterType::Heuristics).unwrap();
+        let top = found.top_candidate.as_ref().unwrap();
+        assert!(found.candidates.iter().any(|c| &c.path == top && c.tag == "article"));
+        assert_eq!(found.removed[0].class, Some("comments".to_owned()));
+    }
+
+    #[test]
+    fn rewriter_output_formats() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.set_readability_threshold(0.0);
+        let article = "http://example.net/article/today/index.html";
+        let document = b"<html><head><title>Fish &amp; chips</title></head><body>\
+            <nav>Menu</nav><article><p>A long enough paragraph of <em>text</em> to be \
+            extracted.</p>\
+            <p>See <a href=\"/more\">more</a>.</p></article></body></html>";
+
+        let distill = |rewriter_type, output_format| {
+            let compiled = sr.get_rewriter_config(article);
+            let mut output = vec![];
+            let mut rewriter = sr
+                .get_rewriter(
+                    article,
+                    &compiled,
+                    |c: &[u8]| output.extend_from_slice(c),
+                    Some(rewriter_type),
+                    output_format,
+                    None,
+                )
+                .unwrap();
+            for chunk in document.chunks(10) {
+                rewriter.write(chunk).unwrap();
+            }
+            rewriter.end().unwrap();
+            drop(rewriter);
+            String::from_utf8(output).unwrap()
+        };
+
+        let markdown = "# Fish & chips\n\nA long enough paragraph of _text_ to be extracted.\n\n\
+            See [more](http://example.net/more).\n";
+        assert_eq!(distill(RewriterType::Streaming, OutputFormat::Markdown), markdown);
+        assert_eq!(distill(RewriterType::Heuristics, OutputFormat::Markdown), markdown);
+
+        let text = "Fish & chips\n\nA long enough paragraph of text to be extracted.\n\nSee more.\n";
+        assert_eq!(distill(RewriterType::Streaming, OutputFormat::PlainText), text);
+        assert_eq!(distill(RewriterType::Heuristics, OutputFormat::PlainText), text);
+    }
+
+    #[test]
+    fn rewriter_decodes_legacy_encodings() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.set_readability_threshold(0.0);
+        sr.set_output_template(None);
+        let article = "http://example.net/article/today/index.html";
+        let text = "日本語の記事の本文です。十分な長さのテキストがあります。";
+        let document = format!("<html><body><article><p>{}</p></article></body></html>", text);
+        let (body, _, _) = encoding_rs::SHIFT_JIS.encode(&document);
+        let with_meta = [&b"<meta charset=\"shift_jis\">"[..], &body].concat();
+
+        let distill = |rewriter_type, document: &[u8], content_type: Option<&str>| {
+            let response = content_type.map(|content_type| ResponseInfo {
+                content_type: Some(content_type.to_owned()),
+                ..ResponseInfo::default()
+            });
+            let compiled = sr.get_rewriter_config(article);
+            let mut output = vec![];
+            let mut rewriter = sr
+                .get_rewriter(
+                    article,
+                    &compiled,
+                    |c: &[u8]| output.extend_from_slice(c),
+                    Some(rewriter_type),
+                    OutputFormat::Html,
+                    response.as_ref(),
+                )
+                .unwrap();
+            // Split characters between writes
+            for chunk in document.chunks(5) {
+                rewriter.write(chunk).unwrap();
+            }
+            rewriter.end().unwrap();
+            drop(rewriter);
+            String::from_utf8(output).unwrap()
+        };
+
+        for &rewriter_type in [RewriterType::Streaming, RewriterType::Heuristics].iter() {
+            let declared = distill(rewriter_type, &body, Some("text/html; charset=Shift_JIS"));
+            assert!(declared.contains(text), "{:?}: {}", rewriter_type, declared);
+            let sniffed = distill(rewriter_type, &with_meta, None);
+            assert!(sniffed.contains(text), "{:?}: {}", rewriter_type, sniffed);
+        }
+    }
+
+    #[test]
+    fn rewriter_uses_response_info() {
+        let mut sr = SpeedReader::with_whitelist(get_whitelist());
+        sr.set_readability_threshold(0.0);
+        let article = "http://example.net/article/today/index.html";
+        let document = "<html><head><title>Today</title></head><body><article>\
+            <p>A long enough paragraph of text to be extracted.</p></article></body></html>";
+        let response = ResponseInfo {
+            status: Some(200),
+            content_type: Some("text/html".to_owned()),
+            content_language: Some("nl".to_owned()),
+            link: Some("</article/today/>; rel=\"canonical\"".to_owned()),
+        };
+        let compiled = sr.get_rewriter_config(article);
+
+        for &rewriter_type in [RewriterType::Streaming, RewriterType::Heuristics].iter() {
+            let mut output = vec![];
+            let mut rewriter = sr
+                .get_rewriter(
+                    article,
+                    &compiled,
+                    |c: &[u8]| output.extend_from_slice(c),
+                    Some(rewriter_type),
+                    OutputFormat::Html,
+                    Some(&response),
+                )
+                .unwrap();
+            rewriter.write(document.as_bytes()).unwrap();
+            rewriter.end().unwrap();
+            let metadata = rewriter.metadata().cloned().unwrap();
+            drop(rewriter);
+            assert_eq!(metadata.lang, Some("nl".to_owned()));
+            assert_eq!(
+                metadata.canonical_url,
+                Some("http://example.net/article/today/".to_owned())
+            );
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.starts_with("<!DOCTYPE html>\n<html lang=\"nl\""), "{}", output);
+            assert!(output.contains("<link rel=\"canonical\" href=\"http://example.net/article/today/\">"));
+        }
+
+        let get_rewriter = |response: &ResponseInfo| {
+            sr.get_rewriter(article, &compiled, |_: &[u8]| {}, None, OutputFormat::Html, Some(response))
+                .err()
+        };
+        let pdf = ResponseInfo {
+            content_type: Some("application/pdf".to_owned()),
+            ..response.clone()
+        };
+        assert_eq!(get_rewriter(&pdf).map(|e| e.outcome()), Some(DistillOutcome::UnsupportedResponse));
+        let not_found = ResponseInfo {
+            status: Some(404),
+            ..response.clone()
+        };
+        assert_eq!(
+            get_rewriter(&not_found),
+            Some(SpeedReaderError::UnsupportedResponse("status 404".to_owned()))
+        );
+    }
 }
@@ -2,13 +2,30 @@ use lol_html::OutputSink;
 
 use core::borrow::Borrow;
 use core::cell::RefCell;
+use std::sync::Arc;
 use url::Url;
 
 use super::classifier::feature_extractor::{FeatureExtractorStreamer, FeaturisingTreeSink};
-use super::classifier::Classifier;
+use super::classifier::{self, Classifier, READABLE};
+use super::formats;
+use super::metadata::{ArticleMetadata, MetadataCollector};
+use super::response::ResponseInfo;
+use super::sanitizer::{sanitize, SanitizerPolicy};
+use super::template::OutputTemplate;
 use super::speedreader::*;
 
+use readability::diagnostics::Diagnostics;
 use readability::extractor;
+use readability::images::ImageTarget;
+use readability::scorer::ReadabilityOptions;
+
+/// Extracted content with less text than this is not worth showing; matches
+/// the smallest text readability considers when scoring candidates.
+pub(crate) const MIN_EXTRACTED_TEXT_LENGTH: usize = 20;
+
+/// Documents are readable when more than this share of the classifier votes
+/// says so, same as taking the majority vote.
+pub const DEFAULT_READABILITY_THRESHOLD: f32 = 0.5;
 
 pub struct SpeedReaderHeuristics<O>
 where
@@ -18,6 +35,17 @@ where
     readable: RefCell<Option<bool>>,
     streamer: FeatureExtractorStreamer,
     output_sink: O,
+    metadata: Option<ArticleMetadata>,
+    readability_threshold: f32,
+    image_target: ImageTarget,
+    readability_options: Arc<ReadabilityOptions>,
+    sanitizer_policy: Arc<SanitizerPolicy>,
+    output_template: Option<Arc<OutputTemplate>>,
+    output_format: OutputFormat,
+    response: Option<ResponseInfo>,
+    confidence: Option<f32>,
+    collect_diagnostics: bool,
+    diagnostics: Option<Diagnostics>,
 }
 
 impl<O: OutputSink> SpeedReaderProcessor for SpeedReaderHeuristics<O> {
@@ -35,27 +63,57 @@ impl<O: OutputSink> SpeedReaderProcessor for SpeedReaderHeuristics<O> {
         if let Some(url) = self.url.as_ref() {
             // Already decided the document is not readable
             if self.document_readable() == Some(false) {
-                return Err(SpeedReaderError::RewritingError(
-                    "Not readable with heuristics".to_owned(),
+                return Err(SpeedReaderError::DocumentParseError(
+                    "Failed to parse document input".to_owned(),
                 ));
             }
-            let (readable, maybe_doc) = process(self.streamer.end(), &url);
+            let sink = self.streamer.end();
+            // Collect metadata before extraction strips the document head
+            let mut collector = MetadataCollector::default();
+            if let Some(response) = &self.response {
+                collector.response(response, url);
+            }
+            collector.collect_dom(&sink.rcdom.document);
+            self.metadata = Some(collector.finish(url));
 
-            *self.readable.borrow_mut() = Some(readable);
-            if readable {
-                if let Some(doc) = maybe_doc {
-                    self.output_sink.handle_chunk(doc.as_bytes());
-                    Ok(())
+            let votes = Classifier::from_feature_map(&sink.features).votes();
+            let confidence = classifier::confidence(&votes);
+            self.confidence = Some(confidence);
+
+            let processed = if confidence > self.readability_threshold {
+                let mut diagnostics = if self.collect_diagnostics {
+                    Some(Diagnostics::default())
                 } else {
-                    Err(SpeedReaderError::RewritingError(
-                        "Failed to extract content with heuristics".to_owned(),
-                    ))
-                }
+                    None
+                };
+                let extracted = extract(
+                    sink,
+                    &url,
+                    &self.image_target,
+                    &self.readability_options,
+                    diagnostics.as_mut(),
+                );
+                self.diagnostics = diagnostics;
+                extracted
             } else {
-                Err(SpeedReaderError::RewritingError(
-                    "Not readable with heuristics".to_owned(),
-                ))
+                Err(SpeedReaderError::ClassifierRejected {
+                    readable_votes: votes[READABLE],
+                    total_votes: votes.iter().sum(),
+                })
+            };
+
+            *self.readable.borrow_mut() = Some(processed.is_ok());
+            let mut doc = sanitize(Arc::clone(&self.sanitizer_policy), processed?.as_bytes())?;
+            if let Some(metadata) = &self.metadata {
+                doc = formats::render(
+                    self.output_format,
+                    self.output_template.as_ref().map(Arc::as_ref),
+                    metadata,
+                    &doc,
+                );
             }
+            self.output_sink.handle_chunk(&doc);
+            Ok(())
         } else {
             // No valid URL - no document
             Err(SpeedReaderError::InvalidUrl("".to_owned()))
@@ -65,6 +123,18 @@ impl<O: OutputSink> SpeedReaderProcessor for SpeedReaderHeuristics<O> {
     fn rewriter_type(&self) -> RewriterType {
         RewriterType::Heuristics
     }
+
+    fn metadata(&self) -> Option<&ArticleMetadata> {
+        self.metadata.as_ref()
+    }
+
+    fn confidence(&self) -> Option<f32> {
+        self.confidence
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        self.diagnostics.as_ref()
+    }
 }
 
 impl<O: OutputSink> SpeedReaderHeuristics<O> {
@@ -79,26 +149,99 @@ impl<O: OutputSink> SpeedReaderHeuristics<O> {
                     readable: RefCell::new(None),
                     streamer,
                     output_sink,
+                    metadata: None,
+                    readability_threshold: DEFAULT_READABILITY_THRESHOLD,
+                    image_target: ImageTarget::default(),
+                    readability_options: Arc::new(ReadabilityOptions::default()),
+                    sanitizer_policy: Arc::new(SanitizerPolicy::default()),
+                    output_template: Some(Arc::new(OutputTemplate::default())),
+                    output_format: OutputFormat::Html,
+                    response: None,
+                    confidence: None,
+                    collect_diagnostics: false,
+                    diagnostics: None,
                 })
             } else {
-                Err(SpeedReaderError::InvalidUrl(url.to_owned()))
+                Err(SpeedReaderError::InvalidScheme(
+                    url_parsed.scheme().to_owned(),
+                ))
             }
         })?
     }
 
+    /// Share of classifier votes, in `[0, 1]`, a document needs to exceed to
+    /// be considered readable. Higher values reject more borderline pages.
+    pub fn set_readability_threshold(&mut self, threshold: f32) {
+        self.readability_threshold = threshold;
+    }
+
+    /// Display images are picked for from `srcset` and `<picture>`
+    /// candidates.
+    pub fn set_image_target(&mut self, image_target: ImageTarget) {
+        self.image_target = image_target;
+    }
+
+    /// Scoring model content is extracted with.
+    pub fn set_readability_options(&mut self, readability_options: Arc<ReadabilityOptions>) {
+        self.readability_options = readability_options;
+    }
+
+    /// Allowlist the extracted content is sanitized with.
+    pub fn set_sanitizer_policy(&mut self, sanitizer_policy: Arc<SanitizerPolicy>) {
+        self.sanitizer_policy = sanitizer_policy;
+    }
+
+    /// Document the extracted content is placed in, `None` for the bare
+    /// content.
+    pub fn set_output_template(&mut self, output_template: Option<Arc<OutputTemplate>>) {
+        self.output_template = output_template;
+    }
+
+    /// HTTP response the document came with. Its `Content-Type` charset is
+    /// used to decode the document, though byte order marks still take
+    /// precedence, so it must be set before the first `write`.
+    pub fn set_response_info(&mut self, response: ResponseInfo) {
+        self.streamer.set_encoding(response.encoding());
+        self.response = Some(response);
+    }
+
+    /// Record how candidates are scored and which nodes are removed during
+    /// extraction.
+    pub fn set_diagnostics(&mut self, enabled: bool) {
+        self.collect_diagnostics = enabled;
+    }
+
+    /// Format the extracted content is written in.
+    pub fn set_output_format(&mut self, output_format: OutputFormat) {
+        self.output_format = output_format;
+    }
+
     pub fn document_readable(&self) -> Option<bool> {
         *self.readable.borrow()
     }
 }
 
-fn process(sink: &mut FeaturisingTreeSink, url: &Url) -> (bool, Option<String>) {
-    let class = Classifier::from_feature_map(&sink.features).classify();
-    if class == 0 {
-        (false, None)
-    } else if let Ok(extracted) = extractor::extract_dom(&mut sink.rcdom, url, &sink.features) {
-        (true, Some(extracted.content))
+fn extract(
+    sink: &mut FeaturisingTreeSink,
+    url: &Url,
+    image_target: &ImageTarget,
+    readability_options: &ReadabilityOptions,
+    diagnostics: Option<&mut Diagnostics>,
+) -> Result<String, SpeedReaderError> {
+    let extracted = extractor::extract_dom(
+        &mut sink.rcdom,
+        url,
+        &sink.features,
+        image_target,
+        readability_options,
+        diagnostics,
+    )?;
+    if extracted.text_length < MIN_EXTRACTED_TEXT_LENGTH {
+        Err(SpeedReaderError::NothingExtracted {
+            text_length: extracted.text_length,
+        })
     } else {
-        (false, None)
+        Ok(extracted.content)
     }
 }
 
@@ -132,4 +275,51 @@ mod tests {
         assert_eq!(result_sink.features["p"], 1);
         assert_eq!(result_sink.features["a"], 1);
     }
+
+    #[test]
+    fn test_invalid_scheme() {
+        let sreader = SpeedReaderHeuristics::try_new("ftp://test.xyz", |_: &[u8]| {});
+        assert_eq!(
+            sreader.err(),
+            Some(SpeedReaderError::InvalidScheme("ftp".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_classifier_rejected() {
+        let mut sreader =
+            SpeedReaderHeuristics::try_new("https://test.xyz", |_: &[u8]| {}).unwrap();
+        sreader.write(b"<html><p>hello world</p></html>").unwrap();
+        let err = sreader.end().unwrap_err();
+
+        match err {
+            SpeedReaderError::ClassifierRejected {
+                readable_votes,
+                total_votes,
+            } => assert!(readable_votes * 2 <= total_votes),
+            _ => panic!("Unexpected error {:?}", err),
+        }
+        assert_eq!(sreader.document_readable(), Some(false));
+        assert_eq!(err.outcome(), DistillOutcome::ClassifierRejected);
+        assert!(sreader.confidence().unwrap() <= DEFAULT_READABILITY_THRESHOLD);
+    }
+
+    #[test]
+    fn test_readability_threshold() {
+        let mut sreader =
+            SpeedReaderHeuristics::try_new("https://test.xyz/news/article", |_: &[u8]| {})
+                .unwrap();
+        assert_eq!(sreader.confidence(), None);
+        sreader.set_readability_threshold(1.0);
+        sreader
+            .write(b"<html><article><p>Lots of text</p><p>And more of it</p></article></html>")
+            .unwrap();
+
+        assert_eq!(
+            sreader.end().err().map(|e| e.outcome()),
+            Some(DistillOutcome::ClassifierRejected)
+        );
+        let confidence = sreader.confidence().unwrap();
+        assert!((0.0..=1.0).contains(&confidence));
+    }
 }
@@ -0,0 +1,187 @@
+use lol_html::html_content::TextType;
+use lol_html::OutputSink;
+use lol_html::{doc_text, HtmlRewriter, Settings};
+use readability::diagnostics::Diagnostics;
+use readability::images::ImageTarget;
+use std::sync::Arc;
+use url::Url;
+
+use super::formats;
+use super::metadata::ArticleMetadata;
+use super::response::ResponseInfo;
+use super::sanitizer::SanitizerPolicy;
+use super::template::OutputTemplate;
+use super::speedreader::*;
+use super::speedreader_heuristics::{SpeedReaderHeuristics, MIN_EXTRACTED_TEXT_LENGTH};
+use super::speedreader_streaming::SpeedReaderStreaming;
+
+/// Buffers the whole document, runs the configured streaming rewrite on it and
+/// falls back to heuristics if the site rules kept too little text, e.g.
+/// after a site redesign broke the `main_content` selectors. Nothing is sent
+/// to the output sink before the path is decided.
+pub struct SpeedReaderHybrid<'h, O>
+where
+    O: OutputSink,
+{
+    url: Url,
+    config: &'h CompiledRewriteConfig,
+    input: Vec<u8>,
+    output_sink: O,
+    used: RewriterType,
+    metadata: Option<ArticleMetadata>,
+    readability_threshold: f32,
+    image_target: ImageTarget,
+    sanitizer_policy: Arc<SanitizerPolicy>,
+    output_template: Option<Arc<OutputTemplate>>,
+    output_format: OutputFormat,
+    response: Option<ResponseInfo>,
+    confidence: Option<f32>,
+    collect_diagnostics: bool,
+    diagnostics: Option<Diagnostics>,
+}
+
+impl<'h, O: OutputSink> SpeedReaderProcessor for SpeedReaderHybrid<'h, O> {
+    fn write(&mut self, chunk: &[u8]) -> Result<(), SpeedReaderError> {
+        self.input.extend_from_slice(chunk);
+        Ok(())
+    }
+
+    fn end(&mut self) -> Result<(), SpeedReaderError> {
+        let mut output = vec![];
+        let mut streaming = SpeedReaderStreaming::try_new(
+            self.url.clone(),
+            |c: &[u8]| output.extend_from_slice(c),
+            self.config,
+            self.image_target,
+            Arc::clone(&self.sanitizer_policy),
+            None,
+            OutputFormat::Html,
+            self.response.as_ref(),
+        )?;
+        streaming.write(&self.input)?;
+        streaming.end()?;
+        let metadata = streaming.metadata().cloned();
+        drop(streaming);
+
+        if visible_text_len(&output)? >= MIN_EXTRACTED_TEXT_LENGTH {
+            if let Some(metadata) = &metadata {
+                output = formats::render(
+                    self.output_format,
+                    self.output_template.as_ref().map(Arc::as_ref),
+                    metadata,
+                    &output,
+                );
+            }
+            self.metadata = metadata;
+            self.output_sink.handle_chunk(&output);
+            return Ok(());
+        }
+
+        self.used = RewriterType::Heuristics;
+        let output_sink = &mut self.output_sink;
+        let mut heuristics = SpeedReaderHeuristics::try_new(self.url.as_str(), |c: &[u8]| {
+            output_sink.handle_chunk(c)
+        })?;
+        heuristics.set_readability_threshold(self.readability_threshold);
+        heuristics.set_image_target(self.image_target);
+        heuristics.set_readability_options(self.config.readability_options());
+        heuristics.set_diagnostics(self.collect_diagnostics);
+        heuristics.set_sanitizer_policy(Arc::clone(&self.sanitizer_policy));
+        heuristics.set_output_template(self.output_template.clone());
+        heuristics.set_output_format(self.output_format);
+        if let Some(response) = &self.response {
+            heuristics.set_response_info(response.clone());
+        }
+        heuristics.write(&self.input)?;
+        let result = heuristics.end();
+        self.metadata = heuristics.metadata().cloned();
+        self.confidence = heuristics.confidence();
+        self.diagnostics = heuristics.diagnostics().cloned();
+        result
+    }
+
+    /// `Streaming` until `end` decides to fall back to heuristics.
+    fn rewriter_type(&self) -> RewriterType {
+        self.used
+    }
+
+    fn metadata(&self) -> Option<&ArticleMetadata> {
+        self.metadata.as_ref()
+    }
+
+    fn confidence(&self) -> Option<f32> {
+        self.confidence
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        self.diagnostics.as_ref()
+    }
+}
+
+impl<'h, O: OutputSink> SpeedReaderHybrid<'h, O> {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        url: Url,
+        output_sink: O,
+        config: &'h CompiledRewriteConfig,
+        readability_threshold: f32,
+        image_target: ImageTarget,
+        sanitizer_policy: Arc<SanitizerPolicy>,
+        output_template: Option<Arc<OutputTemplate>>,
+        output_format: OutputFormat,
+        response: Option<ResponseInfo>,
+        collect_diagnostics: bool,
+    ) -> Self {
+        SpeedReaderHybrid {
+            url,
+            config,
+            input: vec![],
+            output_sink,
+            used: RewriterType::Streaming,
+            metadata: None,
+            readability_threshold,
+            image_target,
+            sanitizer_policy,
+            output_template,
+            output_format,
+            response,
+            confidence: None,
+            collect_diagnostics,
+            diagnostics: None,
+        }
+    }
+}
+
+/// Characters of readable text in the document, ignoring whitespace and the
+/// contents of scripts, styles and the title.
+fn visible_text_len(html: &[u8]) -> Result<usize, SpeedReaderError> {
+    let mut len = 0;
+    let mut rewriter = HtmlRewriter::try_new(
+        Settings {
+            document_content_handlers: vec![doc_text!(|t| {
+                if t.text_type() == TextType::Data {
+                    len += t.as_str().chars().filter(|c| !c.is_whitespace()).count();
+                }
+                Ok(())
+            })],
+            ..Settings::default()
+        },
+        |_: &[u8]| {},
+    )?;
+    rewriter.write(html)?;
+    rewriter.end()?;
+    drop(rewriter);
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn visible_text_skips_markup() {
+        let html = b"<html><head><title>Title</title><style>p {}</style></head>
+            <body><article id=\"article\"><script>var x;</script>\n  <p>a b</p></article>";
+        assert_eq!(visible_text_len(html), Ok(2));
+    }
+}
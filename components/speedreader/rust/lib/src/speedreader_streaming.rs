@@ -1,10 +1,19 @@
 use lol_html::doc_comments;
 use lol_html::OutputSink;
-use lol_html::Selector;
 use lol_html::{HtmlRewriter, Settings};
+use readability::diagnostics::Diagnostics;
+use readability::images::ImageTarget;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
 use url::Url;
 
-use super::rewriter_config_builder::{content_handlers, ContentFunction};
+use super::charset::StreamDecoder;
+use super::metadata::{metadata_content_handlers, ArticleMetadata, MetadataCollector};
+use super::response::ResponseInfo;
+use super::rewriter_config_builder::{content_handlers, url_content_handlers};
+use super::sanitizer::{Sanitizer, SanitizerPolicy, SanitizerSink};
+use super::template::{OutputTemplate, TemplateSink};
 use super::speedreader::*;
 
 pub struct SpeedReaderStreaming<'h, O>
@@ -12,44 +21,99 @@ where
     O: OutputSink,
 {
     url: Url,
-    rewriter: HtmlRewriter<'h, O>,
+    decoder: StreamDecoder,
+    rewriter: HtmlRewriter<'h, SanitizerSink<'h, TemplateSink<O>>>,
+    sanitizer: Sanitizer<'h, TemplateSink<O>>,
+    metadata_collector: Rc<RefCell<MetadataCollector>>,
+    metadata: Option<ArticleMetadata>,
 }
 
 impl<'h, O: OutputSink> SpeedReaderProcessor for SpeedReaderStreaming<'h, O> {
     fn write(&mut self, chunk: &[u8]) -> Result<(), SpeedReaderError> {
-        self.rewriter.write(chunk)?;
-        Ok(())
+        let decoded = self.decoder.decode(chunk);
+        self.rewriter.write(decoded.as_bytes())?;
+        self.sanitizer.check()
     }
 
     fn end(&mut self) -> Result<(), SpeedReaderError> {
+        let decoded = self.decoder.finish();
+        self.rewriter.write(decoded.as_bytes())?;
         self.rewriter.end()?;
+        self.sanitizer.end()?;
+        let collector = self.metadata_collector.replace(MetadataCollector::default());
+        self.metadata = Some(collector.finish(&self.url));
         Ok(())
     }
 
     fn rewriter_type(&self) -> RewriterType {
         RewriterType::Streaming
     }
+
+    fn metadata(&self) -> Option<&ArticleMetadata> {
+        self.metadata.as_ref()
+    }
+
+    fn confidence(&self) -> Option<f32> {
+        None
+    }
+
+    fn diagnostics(&self) -> Option<&Diagnostics> {
+        None
+    }
 }
 
 impl<'h, O: OutputSink> SpeedReaderStreaming<'h, O> {
     pub fn try_new(
         url: Url,
         output_sink: O,
-        config: &'h [(Selector, ContentFunction)],
+        config: &'h CompiledRewriteConfig,
+        image_target: ImageTarget,
+        sanitizer_policy: Arc<SanitizerPolicy>,
+        output_template: Option<Arc<OutputTemplate>>,
+        output_format: OutputFormat,
+        response: Option<&ResponseInfo>,
     ) -> Result<Self, SpeedReaderError> {
+        let mut collector = MetadataCollector::default();
+        if let Some(response) = response {
+            collector.response(response, &url);
+        }
+        let metadata_collector = Rc::new(RefCell::new(collector));
+        let template_sink = TemplateSink::new(
+            output_template,
+            output_format,
+            url.clone(),
+            Rc::clone(&metadata_collector),
+            output_sink,
+        );
+        let sanitizer = Sanitizer::try_new(sanitizer_policy, template_sink)?;
+        // Metadata handlers go first, so they observe content before the
+        // configured handlers get to remove it.
+        let mut element_content_handlers = metadata_content_handlers(&metadata_collector);
+        element_content_handlers.extend(content_handlers(config.handlers()));
+        element_content_handlers.extend(url_content_handlers(&url, image_target));
         let rewriter = HtmlRewriter::try_new(
             Settings {
-                element_content_handlers: content_handlers(config),
+                element_content_handlers,
                 document_content_handlers: vec![doc_comments!(|el| {
                     el.remove();
                     Ok(())
                 })],
+                // Input is decoded before it gets to the rewriter, so the
+                // output is always UTF-8 like the template declares
+                encoding: "utf-8",
                 ..Settings::default()
             },
-            output_sink,
+            sanitizer.sink(),
         )?;
 
-        let sr = SpeedReaderStreaming { url, rewriter };
+        let sr = SpeedReaderStreaming {
+            url,
+            decoder: StreamDecoder::new(response.and_then(ResponseInfo::encoding)),
+            rewriter,
+            sanitizer,
+            metadata_collector,
+            metadata: None,
+        };
 
         Ok(sr)
     }
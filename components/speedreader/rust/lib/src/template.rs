@@ -0,0 +1,315 @@
+use lol_html::OutputSink;
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use url::Url;
+
+use crate::formats;
+use crate::metadata::{ArticleMetadata, MetadataCollector};
+use crate::speedreader::OutputFormat;
+
+/// Lets output documents show images, media and styles from anywhere, and
+/// nothing else: no scripts, plugins, frames, form submissions or `<base>`.
+pub const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; img-src * data:; \
+     media-src *; style-src * 'unsafe-inline'; font-src *; form-action 'none'; base-uri 'none'";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Stylesheet {
+    None,
+    /// URL of a stylesheet to link.
+    Link(String),
+    /// CSS to include in a `<style>` element.
+    Inline(String),
+}
+
+/// HTML document distilled content is placed in. Both rewriter types
+/// produce the same structure:
+///
+/// ```html
+/// <!DOCTYPE html>
+/// <html lang="…" dir="…">
+/// <head>…</head>
+/// <body>
+/// <article id="article">
+/// <header id="article-header">
+/// <h1 class="title">…</h1>
+/// <p class="byline">…</p>
+/// <p class="metadata"><span class="site-name">…</span><time datetime="…">…</time></p>
+/// </header>
+/// <div id="article-content">…</div>
+/// </article>
+/// </body>
+/// </html>
+/// ```
+///
+/// Header elements without metadata are left out.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputTemplate {
+    pub stylesheet: Stylesheet,
+    /// Set through a `<meta http-equiv>` tag.
+    pub content_security_policy: String,
+}
+
+impl Default for OutputTemplate {
+    fn default() -> Self {
+        OutputTemplate {
+            stylesheet: Stylesheet::None,
+            content_security_policy: DEFAULT_CONTENT_SECURITY_POLICY.to_owned(),
+        }
+    }
+}
+
+const TAIL: &str = "</div>\n</article>\n</body>\n</html>\n";
+
+impl OutputTemplate {
+    /// The document up to the article content.
+    pub fn head(&self, metadata: &ArticleMetadata) -> String {
+        let mut head = String::from("<!DOCTYPE html>\n<html");
+        if let Some(lang) = &metadata.lang {
+            head.push_str(&format!(" lang=\"{}\"", escape(lang)));
+        }
+        head.push_str(&format!(
+            " dir=\"{}\">\n<head>\n<meta charset=\"utf-8\">\n",
+            escape(metadata.dir.as_ref().map(String::as_str).unwrap_or("auto"))
+        ));
+        head.push_str(&format!(
+            "<meta http-equiv=\"Content-Security-Policy\" content=\"{}\">\n",
+            escape(&self.content_security_policy)
+        ));
+        let title = metadata.title.as_ref().map(|t| escape(t)).unwrap_or_default();
+        head.push_str(&format!("<title>{}</title>\n", title));
+        if let Some(canonical_url) = &metadata.canonical_url {
+            head.push_str(&format!("<link rel=\"canonical\" href=\"{}\">\n", escape(canonical_url)));
+        }
+        match &self.stylesheet {
+            Stylesheet::None => (),
+            Stylesheet::Link(url) => {
+                head.push_str(&format!("<link rel=\"stylesheet\" href=\"{}\">\n", escape(url)))
+            }
+            // Keep the CSS from closing the element
+            Stylesheet::Inline(css) => {
+                head.push_str(&format!("<style>{}</style>\n", css.replace("</", "<\\/")))
+            }
+        }
+        head.push_str("</head>\n<body>\n<article id=\"article\">\n<header id=\"article-header\">\n");
+        if !title.is_empty() {
+            head.push_str(&format!("<h1 class=\"title\">{}</h1>\n", title));
+        }
+        if let Some(byline) = &metadata.byline {
+            head.push_str(&format!("<p class=\"byline\">{}</p>\n", escape(byline)));
+        }
+        if metadata.site_name.is_some() || metadata.published.is_some() {
+            head.push_str("<p class=\"metadata\">");
+            if let Some(site_name) = &metadata.site_name {
+                head.push_str(&format!("<span class=\"site-name\">{}</span>", escape(site_name)));
+            }
+            if let Some(published) = &metadata.published {
+                let published = escape(published);
+                head.push_str(&format!("<time datetime=\"{}\">{}</time>", published, published));
+            }
+            head.push_str("</p>\n");
+        }
+        head.push_str("</header>\n<div id=\"article-content\">");
+        head
+    }
+
+    /// The document after the article content.
+    pub fn tail(&self) -> &'static str {
+        TAIL
+    }
+
+    /// Places complete content, optionally starting with a doctype, in the
+    /// template.
+    pub fn render(&self, metadata: &ArticleMetadata, content: &[u8]) -> Vec<u8> {
+        let content = &content[content_start(content).unwrap_or(content.len())..];
+        let mut output = self.head(metadata).into_bytes();
+        output.extend_from_slice(content);
+        output.extend_from_slice(self.tail().as_bytes());
+        output
+    }
+}
+
+/// Offset of the content following leading whitespace and doctypes. `None`
+/// if there is none yet, or a doctype isn't complete.
+fn content_start(output: &[u8]) -> Option<usize> {
+    let mut start = 0;
+    loop {
+        start += output[start..]
+            .iter()
+            .take_while(|b| b.is_ascii_whitespace())
+            .count();
+        let rest = &output[start..];
+        if rest.is_empty() {
+            return None;
+        }
+        if !rest.starts_with(b"<!") {
+            return Some(start);
+        }
+        start += rest.iter().position(|b| *b == b'>')? + 1;
+    }
+}
+
+/// Output sink writing streamed content into an `OutputTemplate`. The
+/// document starts when the first content arrives, with the metadata
+/// collected up to then, which typically covers everything in `<head>`. The
+/// last, empty, chunk closes it. Without a template, output is passed on as
+/// it is. Text formats need the whole content, which is converted with the
+/// final metadata once the last chunk arrives.
+pub struct TemplateSink<O: OutputSink> {
+    template: Option<Arc<OutputTemplate>>,
+    format: OutputFormat,
+    url: Url,
+    metadata: Rc<RefCell<MetadataCollector>>,
+    output_sink: O,
+    started: bool,
+    pending: Vec<u8>,
+}
+
+impl<O: OutputSink> TemplateSink<O> {
+    pub fn new(
+        template: Option<Arc<OutputTemplate>>,
+        format: OutputFormat,
+        url: Url,
+        metadata: Rc<RefCell<MetadataCollector>>,
+        output_sink: O,
+    ) -> Self {
+        TemplateSink {
+            template,
+            format,
+            url,
+            metadata,
+            output_sink,
+            started: false,
+            pending: vec![],
+        }
+    }
+
+    fn start(&mut self, template: &OutputTemplate) {
+        let metadata = self.metadata.borrow().snapshot(&self.url);
+        self.output_sink
+            .handle_chunk(template.head(&metadata).as_bytes());
+        self.started = true;
+    }
+}
+
+impl<O: OutputSink> OutputSink for TemplateSink<O> {
+    fn handle_chunk(&mut self, chunk: &[u8]) {
+        if self.format != OutputFormat::Html {
+            if chunk.is_empty() {
+                let metadata = self.metadata.borrow().snapshot(&self.url);
+                let content = std::mem::replace(&mut self.pending, Vec::new());
+                let converted = formats::convert(self.format, &metadata, &content);
+                self.output_sink.handle_chunk(converted.as_bytes());
+                self.output_sink.handle_chunk(&[]);
+            } else {
+                self.pending.extend_from_slice(chunk);
+            }
+            return;
+        }
+        let template = match self.template.clone() {
+            Some(template) => template,
+            None => return self.output_sink.handle_chunk(chunk),
+        };
+        if chunk.is_empty() {
+            if !self.started {
+                self.start(&template);
+            }
+            self.output_sink.handle_chunk(template.tail().as_bytes());
+            self.output_sink.handle_chunk(&[]);
+        } else if self.started {
+            self.output_sink.handle_chunk(chunk);
+        } else {
+            self.pending.extend_from_slice(chunk);
+            if let Some(start) = content_start(&self.pending) {
+                self.start(&template);
+                let pending = std::mem::replace(&mut self.pending, Vec::new());
+                self.output_sink.handle_chunk(&pending[start..]);
+            }
+        }
+    }
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metadata() -> ArticleMetadata {
+        ArticleMetadata {
+            title: Some("Fish & \"chips\"".to_owned()),
+            byline: Some("Jane Doe".to_owned()),
+            site_name: Some("Example".to_owned()),
+            published: Some("2020-04-01".to_owned()),
+            lang: Some("en".to_owned()),
+            canonical_url: Some("https://example.com/article?a=1&b=2".to_owned()),
+            ..ArticleMetadata::default()
+        }
+    }
+
+    #[test]
+    fn renders_document() {
+        let template = OutputTemplate {
+            stylesheet: Stylesheet::Link("https://example.com/theme.css".to_owned()),
+            content_security_policy: "default-src 'none'".to_owned(),
+        };
+        let output = template.render(&metadata(), b"<!DOCTYPE html>\n<p>Text</p>");
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "<!DOCTYPE html>\n<html lang=\"en\" dir=\"auto\">\n<head>\n<meta charset=\"utf-8\">\n\
+             <meta http-equiv=\"Content-Security-Policy\" content=\"default-src 'none'\">\n\
+             <title>Fish &amp; &quot;chips&quot;</title>\n\
+             <link rel=\"canonical\" href=\"https://example.com/article?a=1&amp;b=2\">\n\
+             <link rel=\"stylesheet\" href=\"https://example.com/theme.css\">\n</head>\n<body>\n\
+             <article id=\"article\">\n<header id=\"article-header\">\n\
+             <h1 class=\"title\">Fish &amp; &quot;chips&quot;</h1>\n\
+             <p class=\"byline\">Jane Doe</p>\n<p class=\"metadata\">\
+             <span class=\"site-name\">Example</span>\
+             <time datetime=\"2020-04-01\">2020-04-01</time></p>\n</header>\n\
+             <div id=\"article-content\"><p>Text</p></div>\n</article>\n</body>\n</html>\n"
+        );
+    }
+
+    #[test]
+    fn inline_stylesheet_stays_in_style() {
+        let template = OutputTemplate {
+            stylesheet: Stylesheet::Inline("p { color: red }</style><script>".to_owned()),
+            ..OutputTemplate::default()
+        };
+        let head = template.head(&ArticleMetadata::default());
+        assert!(head.contains("<style>p { color: red }<\\/style><script></style>"));
+        assert!(head.contains("<title></title>\n"));
+        assert!(head.contains("<header id=\"article-header\">\n</header>"));
+    }
+
+    #[test]
+    fn streamed_content_starts_document() {
+        let mut output = vec![];
+        let collector = Rc::new(RefCell::new(MetadataCollector::default()));
+        let url = Url::parse("https://example.com/article").unwrap();
+        let mut sink = TemplateSink::new(
+            Some(Arc::new(OutputTemplate::default())),
+            OutputFormat::Html,
+            url,
+            Rc::clone(&collector),
+            |c: &[u8]| output.push(c.to_vec()),
+        );
+        sink.handle_chunk(b"<!DOC");
+        sink.handle_chunk(b"TYPE html>\n  ");
+        collector.borrow_mut().title_text("Title");
+        sink.handle_chunk(b"<p>Text");
+        sink.handle_chunk(b"</p>");
+        sink.handle_chunk(b"");
+        drop(sink);
+        let document = String::from_utf8(output.concat()).unwrap();
+        assert!(document.starts_with("<!DOCTYPE html>\n<html dir=\"auto\">"));
+        assert!(document.contains("<h1 class=\"title\">Title</h1>"));
+        assert!(document.ends_with("<div id=\"article-content\"><p>Text</p></div>\n</article>\n</body>\n</html>\n"));
+        assert_eq!(output.last().map(Vec::len), Some(0));
+    }
+}
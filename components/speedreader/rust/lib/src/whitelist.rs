@@ -1,15 +1,40 @@
+use adblock::filters::network::{NetworkFilter, NetworkFilterMask, NetworkMatchable};
+use adblock::request::Request;
+use psl::Psl;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::io::prelude::*;
+use std::net::Ipv4Addr;
+use std::fmt;
 use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use ed25519_dalek::{ExpandedSecretKey, PublicKey, SecretKey, Signature};
+use lol_html::Selector;
+use thiserror::Error;
+use url::{Host, Url};
 
-use crate::speedreader::{SpeedReaderConfig, SpeedReaderError};
+use crate::rewriter_config_builder::check_rewrite_op;
+use crate::speedreader::{DomainMatch, SpeedReaderConfig, SpeedReaderError};
 
-const IMAGE_TARGET_WIDTH: u32 = 600;
+/// Signed whitelist layout, integers little-endian:
+/// magic (4) | version (2) | payload length (4) | gzipped JSON payload |
+/// CRC32 of payload (4) | Ed25519 signature of everything before it (64)
+const SIGNED_MAGIC: &[u8; 4] = b"SRWL";
+const SIGNED_VERSION: u16 = 1;
+const SIGNED_HEADER_LEN: usize = 10;
+const SIGNATURE_LEN: usize = 64;
+
+/// Public half of the key whitelist releases are signed with, see
+/// `speedreader whitelist pack`.
+const WHITELIST_PUBLIC_KEY: [u8; 32] = [
+    0x79, 0xeb, 0x2d, 0xc0, 0xe1, 0x98, 0x03, 0x83, 0xe0, 0x29, 0x53, 0x2f, 0x74, 0xe6, 0xe9, 0x6e,
+    0x39, 0x1c, 0x06, 0x91, 0x64, 0xdf, 0xf9, 0x07, 0x45, 0x81, 0x0f, 0xaa, 0x57, 0xe7, 0xd9, 0xf9,
+];
 
 #[derive(Serialize, Deserialize)]
 pub struct Whitelist {
-    map: HashMap<String, SpeedReaderConfig>,
+    map: HashMap<String, Vec<SpeedReaderConfig>>,
 }
 
 impl Default for Whitelist {
@@ -21,58 +46,673 @@ impl Default for Whitelist {
 }
 
 impl Whitelist {
-    pub fn add_configuration(&mut self, config: SpeedReaderConfig) {
-        self.map.insert(config.domain.clone(), config);
+    /// Adds a configuration, replacing and returning one for the same domain
+    /// and URL scope.
+    pub fn add_configuration(&mut self, config: SpeedReaderConfig) -> Option<SpeedReaderConfig> {
+        let host = host_key(&config.domain);
+        let key = match config.domain_match {
+            DomainMatch::Registrable => registrable_domain(&host).unwrap_or(host.clone()),
+            _ => host.clone(),
+        };
+        let configs = self.map.entry(key).or_default();
+        let existing = configs
+            .iter_mut()
+            .find(|c| c.url_scope == config.url_scope && host_key(&c.domain) == host);
+        if let Some(existing) = existing {
+            Some(std::mem::replace(existing, config))
+        } else {
+            configs.push(config);
+            None
+        }
     }
 
-    pub fn get_configuration(&self, domain: &str) -> Option<&SpeedReaderConfig> {
-        if let Some(config) = self.map.get(domain) {
-            return Some(config);
+    /// Removes and returns all configurations of the domain.
+    pub fn remove_domain(&mut self, domain: &str) -> Vec<SpeedReaderConfig> {
+        let host = host_key(domain);
+        let mut removed = vec![];
+        let mut keys = vec![host.clone()];
+        // Registrable domain entries are stored under their registrable domain
+        keys.extend(registrable_domain(&host).filter(|r| *r != host));
+        for key in keys.iter() {
+            if let Some(configs) = self.map.get_mut(key) {
+                let (matching, kept) = configs
+                    .drain(..)
+                    .partition(|c: &SpeedReaderConfig| host_key(&c.domain) == host);
+                removed.extend(matching);
+                *configs = kept;
+                if configs.is_empty() {
+                    self.map.remove(key);
+                }
+            }
         }
+        removed
+    }
+
+    /// Domain-wide configuration for the domain or its closest parent domain,
+    /// ignoring configurations with a URL scope.
+    pub fn get_configuration(&self, domain: &str) -> Option<&SpeedReaderConfig> {
+        self.find_for_domain(domain, |configs| {
+            configs.iter().find(|c| c.url_scope.is_empty()).copied()
+        })
+    }
+
+    /// Most specific configuration for the full URL: a scoped configuration
+    /// with a matching pattern wins over the domain-wide one, the longest
+    /// matching pattern wins between scoped ones.
+    pub fn get_configuration_for_url(&self, url: &Url) -> Option<&SpeedReaderConfig> {
+        let request = Request::from_urls(url.as_str(), url.as_str(), "").ok();
+        self.find_for_domain(url.host_str().unwrap_or_default(), |configs| {
+            let mut best: Option<(usize, &SpeedReaderConfig)> = None;
+            for &config in configs {
+                let specificity = if config.url_scope.is_empty() {
+                    Some(0)
+                } else {
+                    request
+                        .as_ref()
+                        .and_then(|r| scope_specificity(&config.url_scope, r))
+                };
+                match (specificity, best) {
+                    (Some(s), Some((best_s, _))) if s <= best_s => (),
+                    (Some(s), _) => best = Some((s, config)),
+                    (None, _) => (),
+                }
+            }
+            best.map(|(_, config)| config)
+        })
+    }
+
+    /// Every URL rule matching the URL, across all configurations, whether
+    /// or not the configuration would be picked for it. Sorted by domain.
+    pub fn matching_rules(&self, url: &Url) -> Vec<RuleMatch<'_>> {
+        let request = match Request::from_urls(url.as_str(), url.as_str(), "") {
+            Ok(request) => request,
+            Err(_) => return vec![],
+        };
+        let mut matches: Vec<RuleMatch> = self
+            .get_configurations()
+            .flat_map(|config| config.url_rules.iter().map(move |rule| (config, rule)))
+            .filter_map(|(config, rule)| {
+                let filter = NetworkFilter::parse(rule, false).ok()?;
+                if filter.matches(&request) {
+                    Some(RuleMatch {
+                        config,
+                        rule,
+                        exception: filter.is_exception(),
+                    })
+                } else {
+                    None
+                }
+            })
+            .collect();
+        matches.sort_by(|a, b| (&a.config.domain, a.rule).cmp(&(&b.config.domain, b.rule)));
+        matches
+    }
+
+    /// Walks from the host up to its registrable domain, never further, so
+    /// entries for public suffixes like `co.uk` can't match whole registries.
+    /// IP hosts only match exactly.
+    fn find_for_domain<'a, F>(&'a self, domain: &str, select: F) -> Option<&'a SpeedReaderConfig>
+    where
+        F: Fn(&[&'a SpeedReaderConfig]) -> Option<&'a SpeedReaderConfig>,
+    {
+        let host = match Host::parse(domain.trim_end_matches('.')) {
+            Ok(Host::Domain(name)) => name,
+            Ok(ip) => {
+                let configs = self.map.get(&ip.to_string())?;
+                return select(&configs.iter().collect::<Vec<_>>());
+            }
+            Err(_) => return None,
+        };
+        let registrable_len = registrable_domain(&host).map_or(host.len(), |r| r.len());
 
-        for (i, c) in domain[..domain.len() - 2].char_indices() {
-            if c == '.' {
-                let subdomain = &domain[i + 1..];
-                let maybe_config = self.map.get(subdomain);
+        let mut candidate = host.as_str();
+        loop {
+            let exact = candidate.len() == host.len();
+            // Names with more than four numeric labels aren't IPs, but their
+            // parents may look like ones
+            let ip_like = !exact && candidate.parse::<Ipv4Addr>().is_ok();
+            if let Some(configs) = self.map.get(candidate).filter(|_| !ip_like) {
+                let eligible: Vec<&SpeedReaderConfig> = configs
+                    .iter()
+                    .filter(|c| exact || c.domain_match != DomainMatch::Exact)
+                    .collect();
+                let maybe_config = select(&eligible);
                 if maybe_config.is_some() {
                     return maybe_config;
                 }
             }
+            if candidate.len() <= registrable_len {
+                return None;
+            }
+            candidate = &candidate[candidate.find('.')? + 1..];
         }
+    }
 
-        None
+    pub fn get_configurations(&self) -> impl Iterator<Item = &SpeedReaderConfig> {
+        self.map.values().flatten()
     }
 
     pub fn get_url_rules(&self) -> Vec<String> {
-        self.map
-            .values()
+        self.get_configurations()
             .flat_map(|c| c.url_rules.iter().cloned())
             .collect()
     }
 
+    /// Sorted by domain and URL scope, so equal whitelists serialize equally.
     pub fn serialize(&self) -> Result<Vec<u8>, SpeedReaderError> {
         let mut out = Vec::new();
-        let j = serde_json::to_string(&self.map.values().collect::<Vec<&SpeedReaderConfig>>())?;
+        let mut configs: Vec<&SpeedReaderConfig> = self.get_configurations().collect();
+        configs.sort_by(|a, b| (&a.domain, &a.url_scope).cmp(&(&b.domain, &b.url_scope)));
+        let j = serde_json::to_string(&configs)?;
         out.extend_from_slice(j.as_bytes());
         Ok(out)
     }
 
     pub fn deserialize(serialized: &[u8]) -> Result<Self, SpeedReaderError> {
-        let mut gz = GzDecoder::new(serialized);
-        let mut s = String::new();
-        let read = gz.read_to_string(&mut s);
-        if read.is_err() {
-            let decoded = std::str::from_utf8(serialized)?;
-            s.clear();
-            s.push_str(decoded);
-        }
+        let s = decode(serialized)?;
         let configurations: Vec<SpeedReaderConfig> = serde_json::from_str(&s)?;
         let mut whitelist = Whitelist::default();
         for config in configurations.into_iter() {
-            whitelist.add_configuration(config)
+            whitelist.add_configuration(config);
         }
         Ok(whitelist)
     }
+
+    /// Serializes into the framed, signed format read by
+    /// `deserialize_verified`. `private_key` is a raw 32 byte Ed25519 key.
+    pub fn serialize_signed(&self, private_key: &[u8]) -> Result<Vec<u8>, SpeedReaderError> {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(&self.serialize()?)?;
+        let payload = gz.finish()?;
+
+        let mut out = Vec::with_capacity(SIGNED_HEADER_LEN + payload.len() + 4 + SIGNATURE_LEN);
+        out.extend_from_slice(SIGNED_MAGIC);
+        out.extend_from_slice(&SIGNED_VERSION.to_le_bytes());
+        out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        out.extend_from_slice(&payload);
+        out.extend_from_slice(&crc32(&payload).to_le_bytes());
+
+        let secret = SecretKey::from_bytes(private_key).map_err(|_| {
+            SpeedReaderError::ConfigurationError("Invalid signing key".to_owned())
+        })?;
+        let signature = ExpandedSecretKey::from(&secret).sign(&out, &PublicKey::from(&secret));
+        out.extend_from_slice(&signature.to_bytes());
+        Ok(out)
+    }
+
+    /// Reads the signed format, verified against the embedded release key.
+    /// Use `deserialize` for plain JSON during development.
+    pub fn deserialize_verified(serialized: &[u8]) -> Result<Self, SpeedReaderError> {
+        Whitelist::deserialize_verified_with_key(serialized, &WHITELIST_PUBLIC_KEY)
+    }
+
+    /// Reads the signed format, verified against a raw 32 byte Ed25519
+    /// public key. Only tests sign with other keys than the release one.
+    fn deserialize_verified_with_key(
+        serialized: &[u8],
+        public_key: &[u8],
+    ) -> Result<Self, SpeedReaderError> {
+        if serialized.len() < SIGNED_HEADER_LEN || &serialized[..4] != SIGNED_MAGIC {
+            return Err(SpeedReaderError::MalformedWhitelist(
+                "Missing header".to_owned(),
+            ));
+        }
+        let version = u16::from_le_bytes([serialized[4], serialized[5]]);
+        if version != SIGNED_VERSION {
+            return Err(SpeedReaderError::UnsupportedWhitelistVersion(version));
+        }
+        let mut payload_len = [0; 4];
+        payload_len.copy_from_slice(&serialized[6..SIGNED_HEADER_LEN]);
+        // The length is untrusted, it may overflow on 32-bit targets
+        let payload_end = SIGNED_HEADER_LEN.checked_add(u32::from_le_bytes(payload_len) as usize);
+        let expected_len = payload_end.and_then(|end| end.checked_add(4 + SIGNATURE_LEN));
+        let payload_end = match (payload_end, expected_len) {
+            (Some(payload_end), Some(expected_len)) if serialized.len() == expected_len => {
+                payload_end
+            }
+            _ => {
+                return Err(SpeedReaderError::MalformedWhitelist(
+                    "Unexpected length".to_owned(),
+                ))
+            }
+        };
+
+        let payload = &serialized[SIGNED_HEADER_LEN..payload_end];
+        let mut checksum = [0; 4];
+        checksum.copy_from_slice(&serialized[payload_end..payload_end + 4]);
+        if crc32(payload) != u32::from_le_bytes(checksum) {
+            return Err(SpeedReaderError::WhitelistChecksumMismatch);
+        }
+
+        let (signed, signature) = serialized.split_at(payload_end + 4);
+        let key = PublicKey::from_bytes(public_key).map_err(|_| {
+            SpeedReaderError::ConfigurationError("Invalid whitelist key".to_owned())
+        })?;
+        Signature::from_bytes(signature)
+            .and_then(|signature| key.verify_strict(signed, &signature))
+            .map_err(|_| SpeedReaderError::WhitelistSignatureInvalid)?;
+
+        let mut json = String::new();
+        GzDecoder::new(payload).read_to_string(&mut json)?;
+        Whitelist::deserialize(json.as_bytes())
+    }
+
+    /// Checks every configuration for problems that would otherwise only
+    /// show at runtime, if at all. Sorted by domain.
+    pub fn validate(&self) -> Vec<WhitelistDiagnostic> {
+        let mut configs: Vec<&SpeedReaderConfig> = self.get_configurations().collect();
+        configs.sort_by(|a, b| (&a.domain, &a.url_scope).cmp(&(&b.domain, &b.url_scope)));
+        configs.into_iter().flat_map(validate_config).collect()
+    }
+
+    /// Like `validate` on the deserialized whitelist, also flagging entries
+    /// that appear more than once and would silently replace each other.
+    pub fn validate_serialized(serialized: &[u8]) -> Result<Vec<WhitelistDiagnostic>, SpeedReaderError> {
+        let s = decode(serialized)?;
+        let configurations: Vec<SpeedReaderConfig> = serde_json::from_str(&s)?;
+        let mut diagnostics = vec![];
+        let mut seen = vec![];
+        for config in configurations.iter() {
+            let key = (host_key(&config.domain), &config.url_scope);
+            if seen.contains(&key) {
+                diagnostics.push(WhitelistDiagnostic::new(
+                    config,
+                    WhitelistProblem::DuplicateDomain,
+                ));
+            } else {
+                seen.push(key);
+            }
+        }
+
+        let mut whitelist = Whitelist::default();
+        for config in configurations.into_iter() {
+            whitelist.add_configuration(config);
+        }
+        diagnostics.extend(whitelist.validate());
+        Ok(diagnostics)
+    }
+
+    /// Applies removals first, then additions, same as
+    /// `SpeedReader::apply_patch` without the compiled state.
+    pub fn apply_patch(&mut self, patch: WhitelistPatch) {
+        for domain in patch.remove_domains.iter() {
+            self.remove_domain(domain);
+        }
+        for config in patch.add.into_iter() {
+            self.add_configuration(config);
+        }
+    }
+}
+
+/// Delta between two whitelists, for shipping updates without the whole list.
+/// Domains in `remove_domains` lose all their configurations before the ones
+/// in `add` are added, so a domain can be replaced wholesale by listing it in
+/// both.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct WhitelistPatch {
+    #[serde(default)]
+    pub remove_domains: Vec<String>,
+    #[serde(default)]
+    pub add: Vec<SpeedReaderConfig>,
+}
+
+impl WhitelistPatch {
+    pub fn serialize(&self) -> Result<Vec<u8>, SpeedReaderError> {
+        let j = serde_json::to_string(self)?;
+        Ok(j.into_bytes())
+    }
+
+    /// Accepts gzip-compressed or plain JSON, like `Whitelist::deserialize`.
+    pub fn deserialize(serialized: &[u8]) -> Result<Self, SpeedReaderError> {
+        let s = decode(serialized)?;
+        Ok(serde_json::from_str(&s)?)
+    }
+}
+
+/// URL rule matching a URL, see `Whitelist::matching_rules`.
+#[derive(Clone, Copy, Debug)]
+pub struct RuleMatch<'a> {
+    pub config: &'a SpeedReaderConfig,
+    pub rule: &'a str,
+    /// `@@` rules make matching URLs unreadable.
+    pub exception: bool,
+}
+
+/// Problem found in a whitelist entry by `Whitelist::validate`.
+#[derive(Clone, Debug, PartialEq, Error)]
+pub enum WhitelistProblem {
+    #[error("domain is not a valid host name")]
+    InvalidDomain,
+    #[error("domain appears more than once with the same URL scope")]
+    DuplicateDomain,
+    #[error("invalid selector `{selector}`: {error}")]
+    InvalidSelector { selector: String, error: String },
+    #[error("invalid URL rule `{rule}`: {error}")]
+    InvalidUrlRule { rule: String, error: String },
+    #[error("invalid operation on `{selector}`: {error}")]
+    InvalidRewriteOp { selector: String, error: String },
+    #[error("URL rule `{0}` can never match the domain")]
+    UnreachableUrlRule(String),
+    #[error("declarative rewrite without `main_content` selectors")]
+    EmptyMainContent,
+    #[error("`content_script` is no longer supported, scripts never reach the output")]
+    DeprecatedContentScript,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WhitelistDiagnostic {
+    pub domain: String,
+    pub url_scope: Vec<String>,
+    pub problem: WhitelistProblem,
+}
+
+impl WhitelistDiagnostic {
+    fn new(config: &SpeedReaderConfig, problem: WhitelistProblem) -> Self {
+        WhitelistDiagnostic {
+            domain: config.domain.clone(),
+            url_scope: config.url_scope.clone(),
+            problem,
+        }
+    }
+}
+
+impl fmt::Display for WhitelistDiagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.url_scope.is_empty() {
+            write!(f, "{}: {}", self.domain, self.problem)
+        } else {
+            write!(
+                f,
+                "{} [{}]: {}",
+                self.domain,
+                self.url_scope.join(", "),
+                self.problem
+            )
+        }
+    }
+}
+
+fn validate_config(config: &SpeedReaderConfig) -> Vec<WhitelistDiagnostic> {
+    let mut problems = vec![];
+    let host = match Host::parse(config.domain.trim_end_matches('.')) {
+        Ok(host) => Some(host.to_string()),
+        Err(_) => {
+            problems.push(WhitelistProblem::InvalidDomain);
+            None
+        }
+    };
+
+    for rule in config.url_rules.iter().chain(config.url_scope.iter()) {
+        match NetworkFilter::parse(rule, false) {
+            Ok(filter) => {
+                let reachable = match (host.as_ref(), filter.hostname.as_ref()) {
+                    (Some(host), Some(rule_host))
+                        if filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_ANCHOR)
+                            && !filter.mask.contains(NetworkFilterMask::IS_HOSTNAME_REGEX) =>
+                    {
+                        rule_reaches_domain(&rule_host.to_lowercase(), host, config.domain_match)
+                    }
+                    _ => true,
+                };
+                if !reachable {
+                    problems.push(WhitelistProblem::UnreachableUrlRule(rule.clone()));
+                }
+            }
+            Err(error) => problems.push(WhitelistProblem::InvalidUrlRule {
+                rule: rule.clone(),
+                error: format!("{:?}", error),
+            }),
+        }
+    }
+
+    if let Some(rewrite) = config.declarative_rewrite.as_ref() {
+        if rewrite.main_content.is_empty() {
+            problems.push(WhitelistProblem::EmptyMainContent);
+        }
+        if rewrite.content_script.is_some() {
+            problems.push(WhitelistProblem::DeprecatedContentScript);
+        }
+        let selectors = rewrite
+            .main_content
+            .iter()
+            .chain(rewrite.main_content_cleanup.iter())
+            .chain(rewrite.preprocess.iter().map(|p| &p.selector))
+            .chain(rewrite.operations.iter().map(|o| &o.selector));
+        for selector in selectors {
+            if let Err(error) = selector.parse::<Selector>() {
+                problems.push(WhitelistProblem::InvalidSelector {
+                    selector: selector.clone(),
+                    error: error.to_string(),
+                });
+            }
+        }
+        for selector_ops in rewrite.operations.iter() {
+            for op in selector_ops.ops.iter() {
+                if let Err(error) = check_rewrite_op(op) {
+                    problems.push(WhitelistProblem::InvalidRewriteOp {
+                        selector: selector_ops.selector.clone(),
+                        error: error.to_string(),
+                    });
+                }
+            }
+        }
+    }
+
+    problems
+        .into_iter()
+        .map(|problem| WhitelistDiagnostic::new(config, problem))
+        .collect()
+}
+
+/// Whether a `||rule_host` anchored rule can match pages the configuration
+/// applies to: the rule host is one of them or a parent of the domain.
+fn rule_reaches_domain(rule_host: &str, host: &str, domain_match: DomainMatch) -> bool {
+    let is_subdomain = |name: &str, parent: &str| {
+        name.len() > parent.len()
+            && name.ends_with(parent)
+            && name[..name.len() - parent.len()].ends_with('.')
+    };
+    let covered = match domain_match {
+        DomainMatch::Registrable => registrable_domain(host).unwrap_or_else(|| host.to_owned()),
+        _ => host.to_owned(),
+    };
+    rule_host == covered
+        || is_subdomain(&covered, rule_host)
+        || (domain_match != DomainMatch::Exact && is_subdomain(rule_host, &covered))
+}
+
+/// Sources a `LayeredWhitelist` is assembled from, in increasing order of
+/// precedence.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum WhitelistLayer {
+    /// Shipped with the browser.
+    BuiltIn,
+    /// Delivered and updated by the component updater.
+    Component,
+    /// Set by enterprise policy.
+    Enterprise,
+    /// Sites the user forced reader mode on or off for.
+    User,
+}
+
+impl WhitelistLayer {
+    pub const ALL: [WhitelistLayer; 4] = [
+        WhitelistLayer::BuiltIn,
+        WhitelistLayer::Component,
+        WhitelistLayer::Enterprise,
+        WhitelistLayer::User,
+    ];
+}
+
+/// Result of a layered lookup: the layer that decided and the configuration
+/// it provides, `None` if that layer disabled the site.
+#[derive(Clone, Copy, Debug)]
+pub struct LayerMatch<'a> {
+    pub layer: WhitelistLayer,
+    pub config: Option<&'a SpeedReaderConfig>,
+}
+
+#[derive(Default)]
+struct Layer {
+    whitelist: Whitelist,
+    disabled: Vec<String>,
+}
+
+impl Layer {
+    fn disables(&self, host: &str) -> bool {
+        let host = host_key(host);
+        self.disabled.iter().any(|domain| {
+            host == *domain
+                || (host.ends_with(domain.as_str())
+                    && host[..host.len() - domain.len()].ends_with('.'))
+        })
+    }
+}
+
+/// Whitelists from several sources stacked on top of each other. Lookups go
+/// from the highest layer down, and the first layer that either has a
+/// configuration for the URL or disables its host decides, so higher layers
+/// can add sites, override the configuration of lower ones or switch sites
+/// off without touching the lists underneath.
+#[derive(Default)]
+pub struct LayeredWhitelist {
+    layers: BTreeMap<WhitelistLayer, Layer>,
+}
+
+impl LayeredWhitelist {
+    /// Replaces the configurations of a layer, keeping its disabled domains.
+    pub fn set_layer(&mut self, layer: WhitelistLayer, whitelist: Whitelist) {
+        self.layers.entry(layer).or_default().whitelist = whitelist;
+    }
+
+    pub fn layer(&self, layer: WhitelistLayer) -> Option<&Whitelist> {
+        self.layers.get(&layer).map(|l| &l.whitelist)
+    }
+
+    pub fn layer_mut(&mut self, layer: WhitelistLayer) -> &mut Whitelist {
+        &mut self.layers.entry(layer).or_default().whitelist
+    }
+
+    /// Disables the domain and its subdomains in the layer, hiding any
+    /// configuration lower layers have for them. Returns `false` if it was
+    /// already disabled.
+    pub fn disable_domain(&mut self, layer: WhitelistLayer, domain: &str) -> bool {
+        let domain = host_key(domain);
+        let disabled = &mut self.layers.entry(layer).or_default().disabled;
+        if disabled.contains(&domain) {
+            false
+        } else {
+            disabled.push(domain);
+            true
+        }
+    }
+
+    /// Lifts a previous `disable_domain`. Returns `false` if the domain
+    /// wasn't disabled in the layer.
+    pub fn enable_domain(&mut self, layer: WhitelistLayer, domain: &str) -> bool {
+        let domain = host_key(domain);
+        match self.layers.get_mut(&layer) {
+            Some(l) => {
+                let len = l.disabled.len();
+                l.disabled.retain(|d| *d != domain);
+                l.disabled.len() != len
+            }
+            None => false,
+        }
+    }
+
+    /// Layered equivalent of `Whitelist::get_configuration`.
+    pub fn get_configuration(&self, domain: &str) -> Option<LayerMatch<'_>> {
+        self.find(domain, |whitelist| whitelist.get_configuration(domain))
+    }
+
+    /// Layered equivalent of `Whitelist::get_configuration_for_url`.
+    pub fn get_configuration_for_url(&self, url: &Url) -> Option<LayerMatch<'_>> {
+        self.find(url.host_str().unwrap_or_default(), |whitelist| {
+            whitelist.get_configuration_for_url(url)
+        })
+    }
+
+    fn find<'a, F>(&'a self, host: &str, lookup: F) -> Option<LayerMatch<'a>>
+    where
+        F: Fn(&'a Whitelist) -> Option<&'a SpeedReaderConfig>,
+    {
+        self.layers.iter().rev().find_map(|(&layer, l)| {
+            if l.disables(host) {
+                Some(LayerMatch {
+                    layer,
+                    config: None,
+                })
+            } else {
+                lookup(&l.whitelist).map(|config| LayerMatch {
+                    layer,
+                    config: Some(config),
+                })
+            }
+        })
+    }
+
+    /// All configurations of all layers, including ones shadowed by higher
+    /// layers.
+    pub fn get_configurations(&self) -> impl Iterator<Item = (WhitelistLayer, &SpeedReaderConfig)> {
+        self.layers
+            .iter()
+            .flat_map(|(&layer, l)| l.whitelist.get_configurations().map(move |c| (layer, c)))
+    }
+}
+
+/// Canonical form of a whitelist domain: lowercase punycode for names,
+/// bracketed for IPv6.
+fn host_key(domain: &str) -> String {
+    match Host::parse(domain.trim_end_matches('.')) {
+        Ok(host) => host.to_string(),
+        Err(_) => domain.to_lowercase(),
+    }
+}
+
+/// Public suffix plus one label, `None` for public suffixes themselves.
+fn registrable_domain(host: &str) -> Option<String> {
+    psl::List::new()
+        .domain(host)
+        .map(|domain| domain.to_str().to_owned())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn decode(serialized: &[u8]) -> Result<String, SpeedReaderError> {
+    let mut gz = GzDecoder::new(serialized);
+    let mut s = String::new();
+    let read = gz.read_to_string(&mut s);
+    if read.is_err() {
+        let decoded = std::str::from_utf8(serialized)?;
+        s.clear();
+        s.push_str(decoded);
+    }
+    Ok(s)
+}
+
+/// Length of the longest pattern in `scope` matching the request, `None` if
+/// none matches or an exception (`@@`) pattern does.
+fn scope_specificity(scope: &[String], request: &Request) -> Option<usize> {
+    let mut specificity = None;
+    for rule in scope {
+        let filter = match NetworkFilter::parse(rule, false) {
+            Ok(filter) => filter,
+            Err(_) => continue,
+        };
+        if !filter.matches(request) {
+            continue;
+        }
+        if filter.is_exception() {
+            return None;
+        }
+        specificity = specificity.max(Some(rule.len()));
+    }
+    specificity
 }
 
 #[cfg(test)]
@@ -97,6 +737,9 @@ mod test {
                 r#"@@||example.com/article/video"#.to_owned(),
             ],
             declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
         });
         let config = whitelist.get_configuration("example.com");
         assert!(config.is_some());
@@ -112,6 +755,9 @@ mod test {
                 r#"@@||example.com/article/video"#.to_owned(),
             ],
             declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
         });
         let config = whitelist.get_configuration("www.example.com");
         assert!(config.is_some());
@@ -127,11 +773,17 @@ mod test {
                 r#"@@||example.com/article/video"#.to_owned(),
             ],
             declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
         });
         whitelist.add_configuration(SpeedReaderConfig {
             domain: "example.net".to_owned(),
             url_rules: vec![r#"||example.net/article"#.to_owned()],
             declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
         });
         let rules = whitelist.get_url_rules();
         assert_eq!(rules.len(), 3);
@@ -147,11 +799,17 @@ mod test {
                 r#"@@||example.com/article/video"#.to_owned(),
             ],
             declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
         });
         whitelist.add_configuration(SpeedReaderConfig {
             domain: "example.com".to_owned(),
             url_rules: vec![r#"||example.com/news"#.to_owned()],
             declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
         });
         assert_eq!(whitelist.map.len(), 1);
         let config = whitelist.get_configuration("example.com");
@@ -161,4 +819,405 @@ mod test {
             vec!["||example.com/news".to_owned()]
         );
     }
+
+    #[test]
+    pub fn scoped_configuration_for_url() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(SpeedReaderConfig {
+            domain: "example.com".to_owned(),
+            url_rules: vec![r#"||example.com/"#.to_owned()],
+            declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        whitelist.add_configuration(SpeedReaderConfig {
+            domain: "example.com".to_owned(),
+            url_rules: vec![r#"||example.com/news"#.to_owned()],
+            declarative_rewrite: None,
+            url_scope: vec![r#"||example.com/news"#.to_owned()],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        whitelist.add_configuration(SpeedReaderConfig {
+            domain: "example.com".to_owned(),
+            url_rules: vec![r#"||example.com/news/live"#.to_owned()],
+            declarative_rewrite: None,
+            url_scope: vec![
+                r#"||example.com/news/live"#.to_owned(),
+                r#"@@||example.com/news/live/archive"#.to_owned(),
+            ],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        assert_eq!(whitelist.map["example.com"].len(), 3);
+
+        let scope_for = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            whitelist
+                .get_configuration_for_url(&url)
+                .map(|c| c.url_scope.first().cloned().unwrap_or_default())
+        };
+        assert_eq!(
+            scope_for("https://www.example.com/news/live/today"),
+            Some("||example.com/news/live".to_owned())
+        );
+        assert_eq!(
+            scope_for("https://example.com/news/live/archive/2020"),
+            Some("||example.com/news".to_owned())
+        );
+        assert_eq!(
+            scope_for("https://example.com/opinion/today"),
+            Some("".to_owned())
+        );
+        assert_eq!(scope_for("https://example.net/news/today"), None);
+
+        let config = whitelist.get_configuration("example.com").unwrap();
+        assert!(config.url_scope.is_empty());
+    }
+
+    #[test]
+    pub fn patch_roundtrip_applies() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(SpeedReaderConfig {
+            domain: "example.com".to_owned(),
+            url_rules: vec![r#"||example.com/article"#.to_owned()],
+            declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+        whitelist.add_configuration(SpeedReaderConfig {
+            domain: "example.net".to_owned(),
+            url_rules: vec![r#"||example.net/article"#.to_owned()],
+            declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match: DomainMatch::default(),
+            readability: None,
+        });
+
+        let patch = WhitelistPatch {
+            remove_domains: vec!["example.com".to_owned()],
+            add: vec![SpeedReaderConfig {
+                domain: "example.org".to_owned(),
+                url_rules: vec![r#"||example.org/article"#.to_owned()],
+                declarative_rewrite: None,
+                url_scope: vec![],
+                domain_match: DomainMatch::default(),
+                readability: None,
+            }],
+        };
+        let patch = WhitelistPatch::deserialize(&patch.serialize().unwrap()).unwrap();
+        whitelist.apply_patch(patch);
+
+        assert!(whitelist.get_configuration("example.com").is_none());
+        assert!(whitelist.get_configuration("example.net").is_some());
+        assert!(whitelist.get_configuration("example.org").is_some());
+    }
+
+    fn config(domain: &str, domain_match: DomainMatch) -> SpeedReaderConfig {
+        SpeedReaderConfig {
+            domain: domain.to_owned(),
+            url_rules: vec![],
+            declarative_rewrite: None,
+            url_scope: vec![],
+            domain_match,
+            readability: None,
+        }
+    }
+
+    #[test]
+    pub fn domain_match_semantics() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(config("exact.example.com", DomainMatch::Exact));
+        whitelist.add_configuration(config("example.org", DomainMatch::Subdomains));
+        whitelist.add_configuration(config("news.example.co.uk", DomainMatch::Registrable));
+
+        assert!(whitelist.get_configuration("exact.example.com").is_some());
+        assert!(whitelist.get_configuration("www.exact.example.com").is_none());
+        assert!(whitelist.get_configuration("example.org").is_some());
+        assert!(whitelist.get_configuration("a.b.example.org").is_some());
+        assert!(whitelist.get_configuration("notexample.org").is_none());
+        assert!(whitelist.get_configuration("news.example.co.uk").is_some());
+        assert!(whitelist.get_configuration("www.example.co.uk").is_some());
+        assert!(whitelist.get_configuration("example.co.uk").is_some());
+        assert!(whitelist.get_configuration("other.co.uk").is_none());
+
+        assert_eq!(whitelist.remove_domain("news.example.co.uk").len(), 1);
+        assert!(whitelist.get_configuration("www.example.co.uk").is_none());
+    }
+
+    #[test]
+    pub fn public_suffix_entry_matches_exactly() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(config("co.uk", DomainMatch::Subdomains));
+        assert!(whitelist.get_configuration("co.uk").is_some());
+        assert!(whitelist.get_configuration("bbc.co.uk").is_none());
+        assert!(whitelist.get_configuration("www.bbc.co.uk").is_none());
+    }
+
+    #[test]
+    pub fn short_and_invalid_domains() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(config("example.com", DomainMatch::Subdomains));
+        for domain in ["", ".", "a", "ab", "com", "localhost", "exa mple.com"].iter() {
+            assert!(whitelist.get_configuration(domain).is_none(), "{}", domain);
+        }
+        assert!(whitelist.get_configuration("www.example.com.").is_some());
+        assert!(whitelist.get_configuration("WWW.Example.COM").is_some());
+    }
+
+    #[test]
+    pub fn idn_domains() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(config("bücher.example", DomainMatch::Subdomains));
+        whitelist.add_configuration(config("xn--fiqs8s", DomainMatch::Subdomains));
+
+        assert!(whitelist.get_configuration("xn--bcher-kva.example").is_some());
+        assert!(whitelist.get_configuration("www.bücher.example").is_some());
+        let url = Url::parse("https://www.bücher.example/artikel").unwrap();
+        assert!(whitelist.get_configuration_for_url(&url).is_some());
+
+        // `中国` is a public suffix
+        assert!(whitelist.get_configuration("中国").is_some());
+        assert!(whitelist.get_configuration("example.中国").is_none());
+    }
+
+    #[test]
+    pub fn ip_hosts() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(config("192.168.0.1", DomainMatch::Subdomains));
+        whitelist.add_configuration(config("[::1]", DomainMatch::Subdomains));
+
+        assert!(whitelist.get_configuration("192.168.0.1").is_some());
+        assert!(whitelist.get_configuration("10.192.168.0.1").is_none());
+        assert!(whitelist.get_configuration("[::1]").is_some());
+        assert!(whitelist.get_configuration("[0:0:0:0:0:0:0:1]").is_some());
+
+        let url = Url::parse("http://192.168.0.1/article").unwrap();
+        assert!(whitelist.get_configuration_for_url(&url).is_some());
+        let url = Url::parse("http://[::1]:8080/article").unwrap();
+        assert!(whitelist.get_configuration_for_url(&url).is_some());
+        let url = Url::parse("http://10.0.0.1/article").unwrap();
+        assert!(whitelist.get_configuration_for_url(&url).is_none());
+    }
+
+    fn signed_whitelist() -> (Vec<u8>, Vec<u8>) {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(config("example.com", DomainMatch::Subdomains));
+        let secret = SecretKey::from_bytes(&[7; 32]).unwrap();
+        let signed = whitelist.serialize_signed(secret.as_bytes()).unwrap();
+        (signed, PublicKey::from(&secret).to_bytes().to_vec())
+    }
+
+    #[test]
+    pub fn signed_roundtrip() {
+        let (signed, public_key) = signed_whitelist();
+        let whitelist = Whitelist::deserialize_verified_with_key(&signed, &public_key).unwrap();
+        assert!(whitelist.get_configuration("example.com").is_some());
+        // Signed with another key
+        let other_key = PublicKey::from(&SecretKey::from_bytes(&[8; 32]).unwrap()).to_bytes();
+        assert_eq!(
+            Whitelist::deserialize_verified_with_key(&signed, &other_key).err(),
+            Some(SpeedReaderError::WhitelistSignatureInvalid)
+        );
+        assert!(matches!(
+            Whitelist::deserialize_verified_with_key(&signed, b"short"),
+            Err(SpeedReaderError::ConfigurationError(_))
+        ));
+        // Not signed with the release key
+        assert_eq!(
+            Whitelist::deserialize_verified(&signed).err(),
+            Some(SpeedReaderError::WhitelistSignatureInvalid)
+        );
+        // Signed blobs are not plain JSON
+        assert!(Whitelist::deserialize(&signed).is_err());
+    }
+
+    #[test]
+    pub fn signed_rejects_tampering() {
+        let (signed, public_key) = signed_whitelist();
+        let verify = |data: &[u8]| {
+            Whitelist::deserialize_verified_with_key(data, &public_key).err()
+        };
+
+        let mut corrupted = signed.clone();
+        corrupted[SIGNED_HEADER_LEN + 2] ^= 0xff;
+        assert_eq!(
+            verify(&corrupted),
+            Some(SpeedReaderError::WhitelistChecksumMismatch)
+        );
+
+        // Forged payload with a valid checksum
+        let payload_end = signed.len() - 4 - SIGNATURE_LEN;
+        let checksum = crc32(&corrupted[SIGNED_HEADER_LEN..payload_end]);
+        corrupted[payload_end..payload_end + 4].copy_from_slice(&checksum.to_le_bytes());
+        assert_eq!(
+            verify(&corrupted),
+            Some(SpeedReaderError::WhitelistSignatureInvalid)
+        );
+
+        let mut future = signed.clone();
+        future[4..6].copy_from_slice(&(SIGNED_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            verify(&future),
+            Some(SpeedReaderError::UnsupportedWhitelistVersion(SIGNED_VERSION + 1))
+        );
+
+        assert!(matches!(
+            verify(&signed[..signed.len() - 1]),
+            Some(SpeedReaderError::MalformedWhitelist(_))
+        ));
+        let mut oversized = signed.clone();
+        oversized[6..SIGNED_HEADER_LEN].copy_from_slice(&u32::max_value().to_le_bytes());
+        assert!(matches!(
+            verify(&oversized),
+            Some(SpeedReaderError::MalformedWhitelist(_))
+        ));
+        assert!(matches!(
+            verify(b"[]"),
+            Some(SpeedReaderError::MalformedWhitelist(_))
+        ));
+    }
+
+    #[test]
+    pub fn layered_lookup() {
+        let mut builtin = Whitelist::default();
+        builtin.add_configuration(config("example.com", DomainMatch::Subdomains));
+        builtin.add_configuration(config("example.net", DomainMatch::Subdomains));
+        builtin.add_configuration(config("myexample.com", DomainMatch::Subdomains));
+        let mut user = Whitelist::default();
+        let mut forced = config("example.net", DomainMatch::Subdomains);
+        forced.url_rules = vec!["||example.net^".to_owned()];
+        user.add_configuration(forced);
+
+        let mut layered = LayeredWhitelist::default();
+        layered.set_layer(WhitelistLayer::BuiltIn, builtin);
+        layered.set_layer(WhitelistLayer::User, user);
+
+        let found = layered.get_configuration("www.example.com").unwrap();
+        assert_eq!(found.layer, WhitelistLayer::BuiltIn);
+        let found = layered.get_configuration("example.net").unwrap();
+        assert_eq!(found.layer, WhitelistLayer::User);
+        assert_eq!(found.config.unwrap().url_rules, vec!["||example.net^"]);
+        assert!(layered.get_configuration("example.org").is_none());
+
+        assert!(layered.disable_domain(WhitelistLayer::Enterprise, "Example.com"));
+        assert!(!layered.disable_domain(WhitelistLayer::Enterprise, "example.com"));
+        let url = Url::parse("https://news.example.com/today").unwrap();
+        let found = layered.get_configuration_for_url(&url).unwrap();
+        assert_eq!(found.layer, WhitelistLayer::Enterprise);
+        assert!(found.config.is_none());
+        // Only the disabled domain and its subdomains are affected
+        assert_eq!(
+            layered.get_configuration("myexample.com").unwrap().layer,
+            WhitelistLayer::BuiltIn
+        );
+
+        assert!(layered.enable_domain(WhitelistLayer::Enterprise, "example.com"));
+        assert!(!layered.enable_domain(WhitelistLayer::User, "example.com"));
+        assert_eq!(
+            layered.get_configuration_for_url(&url).unwrap().layer,
+            WhitelistLayer::BuiltIn
+        );
+        assert_eq!(layered.get_configurations().count(), 4);
+    }
+
+    #[test]
+    pub fn validate_reports_problems() {
+        let serialized = br#"[
+            {
+                "domain": "example.com",
+                "url_rules": ["||example.com/article", "||example.org/article", "||www.example.com^"],
+                "declarative_rewrite": {
+                    "main_content": [],
+                    "main_content_cleanup": ["div[", ".ad"],
+                    "delazify": true,
+                    "fix_embeds": false,
+                    "content_script": "<script>load()</script>",
+                    "preprocess": [{"selector": "p::", "element_name": "p", "attribute": null}],
+                    "operations": [{"selector": "h1", "ops": [{"op": "wrap", "tag": "<b>"}]}]
+                }
+            },
+            {"domain": "news.example.net", "url_rules": [], "url_scope": ["||other.net/"]},
+            {
+                "domain": "news.example.net",
+                "url_rules": ["||example.net/news", "/article/$bogusoption"],
+                "url_scope": ["||other.net/"],
+                "domain_match": "exact"
+            },
+            {"domain": "exa mple.org", "url_rules": []}
+        ]"#;
+        let diagnostics = Whitelist::validate_serialized(serialized).unwrap();
+        let problems: Vec<(&str, &WhitelistProblem)> = diagnostics
+            .iter()
+            .map(|d| (d.domain.as_str(), &d.problem))
+            .collect();
+        assert_eq!(problems[0], ("news.example.net", &WhitelistProblem::DuplicateDomain));
+        assert_eq!(problems[1], ("exa mple.org", &WhitelistProblem::InvalidDomain));
+        assert_eq!(
+            problems[2],
+            (
+                "example.com",
+                &WhitelistProblem::UnreachableUrlRule("||example.org/article".to_owned())
+            )
+        );
+        assert_eq!(problems[3], ("example.com", &WhitelistProblem::EmptyMainContent));
+        assert_eq!(problems[4], ("example.com", &WhitelistProblem::DeprecatedContentScript));
+        assert!(matches!(
+            problems[5],
+            ("example.com", WhitelistProblem::InvalidSelector { selector, .. }) if selector == "div["
+        ));
+        assert!(matches!(
+            problems[6],
+            ("example.com", WhitelistProblem::InvalidSelector { selector, .. }) if selector == "p::"
+        ));
+        assert!(matches!(
+            problems[7],
+            ("example.com", WhitelistProblem::InvalidRewriteOp { selector, .. }) if selector == "h1"
+        ));
+        assert!(matches!(
+            problems[8],
+            ("news.example.net", WhitelistProblem::InvalidUrlRule { rule, .. })
+                if rule == "/article/$bogusoption"
+        ));
+        assert_eq!(
+            problems[9],
+            (
+                "news.example.net",
+                &WhitelistProblem::UnreachableUrlRule("||other.net/".to_owned())
+            )
+        );
+        assert_eq!(problems.len(), 10);
+        assert_eq!(
+            diagnostics[8].to_string(),
+            "news.example.net [||other.net/]: URL rule `||other.net/` can never match the domain"
+        );
+    }
+
+    #[test]
+    pub fn matching_rules_include_exceptions() {
+        let mut whitelist = Whitelist::default();
+        whitelist.add_configuration(SpeedReaderConfig {
+            url_rules: vec![
+                "||example.com/article".to_owned(),
+                "@@||example.com/article/video".to_owned(),
+                "||example.com/news".to_owned(),
+            ],
+            ..config("example.com", DomainMatch::Subdomains)
+        });
+        let url = Url::parse("https://example.com/article/video/1").unwrap();
+        let matches = whitelist.matching_rules(&url);
+        let rules: Vec<(&str, bool)> = matches.iter().map(|m| (m.rule, m.exception)).collect();
+        assert_eq!(
+            rules,
+            vec![
+                ("@@||example.com/article/video", true),
+                ("||example.com/article", false)
+            ]
+        );
+        assert!(whitelist
+            .matching_rules(&Url::parse("https://example.org/news").unwrap())
+            .is_empty());
+    }
 }
@@ -11,6 +11,9 @@ fn serialization_works() {
             "||example.com/news/*".to_owned(),
         ],
         declarative_rewrite: None,
+        url_scope: vec![],
+        domain_match: speedreader::DomainMatch::default(),
+        readability: None,
     });
     let serialized = whitelist.serialize();
     assert!(serialized.is_ok());